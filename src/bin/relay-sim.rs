@@ -0,0 +1,78 @@
+//! A command-line application that simulates one or more R413D08 8-channel
+//! relay modules as a Modbus TCP server.
+//!
+//! The simulator serves the same register map as the real board, so it can be
+//! used to try out the `relay` tool or to test applications built on
+//! `r413d08_lib` without any hardware, e.g.:
+//!
+//! ```sh
+//! relay-sim --listen 127.0.0.1:5020 &
+//! relay tcp 127.0.0.1:5020 on 3
+//! ```
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use clap_verbosity_flag::{InfoLevel, Verbosity};
+use flexi_logger::Logger;
+use log::*;
+use r413d08_lib::{coils::CoilAdapter, protocol as proto, simulator::Simulator};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+fn parse_address(s: &str) -> Result<proto::Address, String> {
+    proto::Address::try_from(clap_num::maybe_hex::<u8>(s)?).map_err(|e| format!("{e}"))
+}
+
+const fn about_text() -> &'static str {
+    "Simulates R413D08 8-channel relay modules as a Modbus TCP server."
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about=about_text(), long_about = None)]
+struct SimArgs {
+    /// Verbosity level (-v, -vv, -vvv).
+    #[command(flatten)]
+    verbose: Verbosity<InfoLevel>,
+
+    /// Socket address to listen on for Modbus TCP connections.
+    #[arg(short, long, default_value = "127.0.0.1:5020")]
+    listen: SocketAddr,
+
+    /// RS485 address (1-247 or 0x01-0xF7) of a simulated device. Repeat to simulate several devices.
+    #[arg(short, long = "address", value_parser = parse_address, default_values_t = [proto::Address::default()])]
+    addresses: Vec<proto::Address>,
+
+    /// Also present the relays as coils 0-7 (FC 0x01, 0x05, 0x0F).
+    #[arg(long)]
+    coils: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = SimArgs::parse();
+
+    let _log_handle = Logger::try_with_env_or_str(args.verbose.log_level_filter().as_str())
+        .context("Cannot initialize logging")?
+        .start()
+        .context("Cannot start logging")?;
+
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Cannot listen on {}", args.listen))?;
+    let simulator = Simulator::new(args.addresses);
+    info!(
+        "Simulating devices {:?} on {}",
+        simulator
+            .addresses()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        args.listen
+    );
+    if args.coils {
+        CoilAdapter::new(simulator).serve(listener).await
+    } else {
+        simulator.serve(listener).await
+    }
+    .context("Simulator stopped unexpectedly")
+}
@@ -0,0 +1,284 @@
+//! Provides a server-side adapter that presents the relays as standard Modbus coils.
+//!
+//! Generic HMI and SCADA software drives relays with coils, while the R413D08
+//! expects command words written to holding registers (see [`proto::Command`]).
+//! The [`CoilAdapter`] wraps another Modbus server service, such as the
+//! [`crate::simulator::Simulator`] or the [`crate::gateway::Gateway`], and
+//! translates the coil functions into the register protocol of the board:
+//!
+//! | Function                   | Coils     | Translated to                                          |
+//! |----------------------------|-----------|--------------------------------------------------------|
+//! | 0x01 Read Coils            | `0`-`7`   | Reading the port states                                |
+//! | 0x05 Write Single Coil     | `0`-`7`   | [`proto::Command::Open`] or [`proto::Command::Close`]  |
+//! | 0x0F Write Multiple Coils  | `0`-`7`   | The commands of [`proto::Command::plan_transition`]    |
+//!
+//! Coil `n` is the relay of [`proto::Port`] `n` (coils `00001`-`00008` in the
+//! one-based notation of many tools). All other requests are passed through
+//! unchanged, so the native register map stays available.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use r413d08_lib::{coils::CoilAdapter, simulator::Simulator};
+//! use tokio::net::TcpListener;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     // Serve a simulated board whose relays can be switched with coils
+//!     let listener = TcpListener::bind("127.0.0.1:5020").await?;
+//!     CoilAdapter::new(Simulator::default()).serve(listener).await?;
+//!     Ok(())
+//! }
+//! ```
+
+use crate::protocol as proto;
+use std::{future::Future, ops::Range, pin::Pin};
+use tokio::net::TcpListener;
+use tokio_modbus::{
+    server::{
+        tcp::{accept_tcp_connection, Server},
+        Service,
+    },
+    ExceptionCode, Request, Response, SlaveId, SlaveRequest,
+};
+
+/// Wraps a Modbus server service and translates coil requests into R413D08 commands.
+#[derive(Debug, Clone)]
+pub struct CoilAdapter<S> {
+    inner: S,
+}
+
+/// Returns the port indices addressed by a coil request.
+///
+/// Fails with [`ExceptionCode::IllegalDataAddress`] if a coil outside of the
+/// relays is addressed.
+fn coil_range(address: u16, quantity: usize) -> Result<Range<usize>, ExceptionCode> {
+    let start = address as usize;
+    let end = start + quantity;
+    if quantity == 0 || end > proto::NUMBER_OF_PORTS {
+        return Err(ExceptionCode::IllegalDataAddress);
+    }
+    Ok(start..end)
+}
+
+impl<S> CoilAdapter<S>
+where
+    S: Service<Request = SlaveRequest<'static>, Exception = ExceptionCode>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send,
+{
+    /// Creates a new `CoilAdapter` forwarding the translated requests to the given service.
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    /// Returns the wrapped service.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Forwards a request to the wrapped service.
+    ///
+    /// Returns `Ok(None)` if the wrapped service does not answer, e.g. because
+    /// no device uses the slave address.
+    async fn forward(
+        &self,
+        slave: SlaveId,
+        request: Request<'static>,
+    ) -> Result<Option<Response>, ExceptionCode> {
+        self.inner
+            .call(SlaveRequest { slave, request })
+            .await
+            .map(Into::into)
+    }
+
+    /// Reads the port states from the wrapped service.
+    async fn read_ports(&self, slave: SlaveId) -> Result<Option<proto::PortStates>, ExceptionCode> {
+        let request =
+            Request::ReadHoldingRegisters(proto::PortStates::ADDRESS, proto::PortStates::QUANTITY);
+        match self.forward(slave, request).await? {
+            Some(Response::ReadHoldingRegisters(words))
+                if words.len() == proto::PortStates::QUANTITY as usize =>
+            {
+                Ok(Some(proto::PortStates::decode_from_holding_registers(
+                    &words,
+                )))
+            }
+            Some(_) => Err(ExceptionCode::ServerDeviceFailure),
+            None => Ok(None),
+        }
+    }
+
+    /// Executes a command with the wrapped service, `false` if it did not answer.
+    async fn execute(
+        &self,
+        slave: SlaveId,
+        command: proto::Command,
+    ) -> Result<bool, ExceptionCode> {
+        let (register, value) = command.encode();
+        let response = self
+            .forward(slave, Request::WriteSingleRegister(register, value))
+            .await?;
+        log::debug!("Slave {slave}: coil write translated to {command}");
+        Ok(response.is_some())
+    }
+
+    /// Handles a request, translating the coil functions.
+    async fn process(&self, req: SlaveRequest<'static>) -> Result<Option<Response>, ExceptionCode> {
+        let slave = req.slave;
+        match req.request {
+            Request::ReadCoils(address, quantity) => {
+                let range = coil_range(address, quantity as usize)?;
+                let Some(states) = self.read_ports(slave).await? else {
+                    return Ok(None);
+                };
+                let coils = states.as_slice()[range]
+                    .iter()
+                    .map(|state| *state == proto::PortState::Open)
+                    .collect();
+                Ok(Some(Response::ReadCoils(coils)))
+            }
+            Request::WriteSingleCoil(address, coil) => {
+                let range = coil_range(address, 1)?;
+                let port = proto::Port::try_from(range.start as u8)
+                    .map_err(|_| ExceptionCode::IllegalDataAddress)?;
+                let command = if coil {
+                    proto::Command::Open(port)
+                } else {
+                    proto::Command::Close(port)
+                };
+                let answered = self.execute(slave, command).await?;
+                Ok(answered.then_some(Response::WriteSingleCoil(address, coil)))
+            }
+            Request::WriteMultipleCoils(address, coils) => {
+                let range = coil_range(address, coils.len())?;
+                let Some(states) = self.read_ports(slave).await? else {
+                    return Ok(None);
+                };
+                let current = proto::PortMask::from(states);
+                let mut target = current;
+                for (index, coil) in range.zip(coils.iter()) {
+                    let port = proto::Port::try_from(index as u8)
+                        .map_err(|_| ExceptionCode::IllegalDataAddress)?;
+                    if *coil {
+                        target.insert(port);
+                    } else {
+                        target.remove(port);
+                    }
+                }
+                for command in proto::Command::plan_transition(current, target) {
+                    if !self.execute(slave, command).await? {
+                        return Ok(None);
+                    }
+                }
+                Ok(Some(Response::WriteMultipleCoils(
+                    address,
+                    coils.len() as u16,
+                )))
+            }
+            request => self.forward(slave, request).await,
+        }
+    }
+
+    /// Serves the adapter as a Modbus TCP server on the given listener.
+    ///
+    /// Each accepted connection is served by its own task, all of them sharing
+    /// the wrapped service. This function only returns if accepting a new
+    /// connection fails.
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        let server = Server::new(listener);
+        let on_connected = |stream, socket_addr| {
+            let adapter = self.clone();
+            async move { accept_tcp_connection(stream, socket_addr, move |_| Ok(Some(adapter.clone()))) }
+        };
+        let on_process_error = |err| log::warn!("Coil adapter connection failed: {err}");
+        server.serve(&on_connected, on_process_error).await
+    }
+}
+
+impl<S> Service for CoilAdapter<S>
+where
+    S: Service<Request = SlaveRequest<'static>, Exception = ExceptionCode>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send,
+{
+    type Request = SlaveRequest<'static>;
+    type Response = Option<Response>;
+    type Exception = ExceptionCode;
+    type Future = Pin<Box<dyn Future<Output = Result<Option<Response>, ExceptionCode>> + Send>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let adapter = self.clone();
+        Box::pin(async move { adapter.process(req).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coil_ranges() {
+        assert_eq!(coil_range(0, 8), Ok(0..8));
+        assert_eq!(coil_range(3, 2), Ok(3..5));
+        assert_eq!(coil_range(7, 1), Ok(7..8));
+        assert_eq!(coil_range(0, 0), Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(coil_range(7, 2), Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(coil_range(8, 1), Err(ExceptionCode::IllegalDataAddress));
+    }
+
+    #[cfg(feature = "simulator")]
+    #[tokio::test]
+    async fn coil_adapter_translates_to_commands() {
+        use crate::simulator::testing::Fixture;
+        use tokio_modbus::prelude::{Reader, SlaveContext, Writer};
+
+        let fixture = Fixture::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        tokio::spawn(CoilAdapter::new(fixture.simulator.clone()).serve(listener));
+        let mut ctx = tokio_modbus::client::tcp::connect(socket_addr)
+            .await
+            .unwrap();
+        ctx.set_slave(tokio_modbus::Slave(*proto::Address::default()));
+
+        ctx.write_single_coil(2, true).await.unwrap().unwrap();
+        ctx.write_single_coil(6, true).await.unwrap().unwrap();
+        assert_eq!(fixture.open_ports().bits(), 0b0100_0100);
+        assert_eq!(
+            ctx.read_coils(0, 8).await.unwrap().unwrap(),
+            [false, false, true, false, false, false, true, false]
+        );
+        assert_eq!(ctx.read_coils(5, 2).await.unwrap().unwrap(), [false, true]);
+
+        ctx.write_single_coil(2, false).await.unwrap().unwrap();
+        ctx.write_multiple_coils(0, &[true, true, false, true])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fixture.open_ports().bits(), 0b0100_1011);
+
+        // Coils beyond the relays are rejected, registers are passed through.
+        assert_eq!(
+            ctx.read_coils(4, 5).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            ctx.write_single_coil(8, true).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            ctx.read_holding_registers(proto::PortStates::ADDRESS, 2)
+                .await
+                .unwrap()
+                .unwrap(),
+            [1, 1]
+        );
+    }
+}
@@ -1,10 +1,14 @@
+use crate::config::RelayNames;
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
-use r413d08_lib::protocol as proto;
-use std::time::Duration;
+use r413d08_lib::{protocol as proto, tokio_common::MIN_POLL_INTERVAL};
+use std::{path::PathBuf, time::Duration};
 
-fn default_device_name() -> String {
+/// The Modbus I/O timeout if neither `--timeout` nor the profile sets one.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(200);
+
+pub fn default_device_name() -> String {
     if cfg!(target_os = "windows") {
         String::from("COM1")
     } else {
@@ -12,14 +16,125 @@ fn default_device_name() -> String {
     }
 }
 
-fn parse_relay(s: &str) -> Result<proto::Port, String> {
-    proto::Port::try_from(clap_num::maybe_hex::<u8>(s)?).map_err(|e| format!("{e}"))
-}
-
 fn parse_address(s: &str) -> Result<proto::Address, String> {
     proto::Address::try_from(clap_num::maybe_hex::<u8>(s)?).map_err(|e| format!("{e}"))
 }
 
+/// Parses a polling interval, which must not be shorter than [`MIN_POLL_INTERVAL`].
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    let interval = humantime::parse_duration(s).map_err(|e| e.to_string())?;
+    if interval < MIN_POLL_INTERVAL {
+        return Err(format!(
+            "The interval must be at least {}",
+            humantime::format_duration(MIN_POLL_INTERVAL)
+        ));
+    }
+    Ok(interval)
+}
+
+/// Parses a gateway unit mapping such as "255=3" into the unit id and the slave address.
+#[cfg(feature = "gateway")]
+fn parse_unit_map(s: &str) -> Result<(u8, proto::Address), String> {
+    let (unit, slave) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected UNIT=SLAVE, got '{s}'"))?;
+    Ok((
+        clap_num::maybe_hex::<u8>(unit.trim())?,
+        parse_address(slave.trim())?,
+    ))
+}
+
+/// Parses relay numbers, ranges or a mask, returns `None` if the item is none of them.
+///
+/// Hexadecimal masks below `0x08` are rejected: they read like a relay number
+/// but select other relays, e.g. `0x03` would switch relays 0 and 1.
+fn parse_relays(item: &str) -> Result<Option<proto::PortMask>> {
+    let Ok(mask) = item.parse::<proto::PortMask>() else {
+        return Ok(None);
+    };
+    let is_hex = item
+        .get(..2)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("0x"));
+    if is_hex && usize::from(mask.bits()) < proto::NUMBER_OF_PORTS {
+        anyhow::bail!(
+            "The mask '{item}' is ambiguous: use {} for relay {} or {:#b} for the mask",
+            mask.bits(),
+            mask.bits(),
+            mask
+        );
+    }
+    Ok(Some(mask))
+}
+
+/// A selection of relays as given on the command line.
+///
+/// Besides relay numbers, lists and ranges (`0,2,5`, `1-4`), binary or hexadecimal
+/// masks (`0b101`, `0x25`) and `all`, it may contain the relay names of the
+/// selected profile (`pump`, `pump,lights`). It is therefore only resolved once
+/// the configuration is loaded. Hexadecimal masks below `0x08` are rejected as
+/// ambiguous, binary masks select any relays.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelaySelector(String);
+
+impl std::str::FromStr for RelaySelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.trim().to_string()))
+    }
+}
+
+impl std::fmt::Display for RelaySelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl RelaySelector {
+    /// Resolves the selector to the selected relays.
+    pub fn resolve(&self, names: &RelayNames) -> Result<proto::PortMask> {
+        let mask = if self.0.eq_ignore_ascii_case("all") {
+            proto::PortMask::ALL
+        } else if let Some(mask) = names.get(&self.0) {
+            *mask
+        } else if let Some(mask) = parse_relays(&self.0)? {
+            mask
+        } else {
+            let mut mask = proto::PortMask::NONE;
+            for item in self.0.split(',').map(str::trim) {
+                mask |= match names.get(item) {
+                    Some(named) => *named,
+                    None => parse_relays(item)?.ok_or_else(|| {
+                        let known = if names.is_empty() {
+                            String::from("none")
+                        } else {
+                            names.keys().cloned().collect::<Vec<_>>().join(", ")
+                        };
+                        anyhow::anyhow!(
+                            "Unknown relay '{item}' in '{self}': expected a relay number 0 to 7, \
+                             a range, a mask or a relay name (known names: {known})"
+                        )
+                    })?,
+                };
+            }
+            mask
+        };
+        if mask.is_empty() {
+            anyhow::bail!("The selector '{self}' does not contain any relay");
+        }
+        Ok(mask)
+    }
+
+    /// Resolves the selector to exactly one relay.
+    pub fn resolve_single(&self, names: &RelayNames) -> Result<proto::Port> {
+        let mask = self.resolve(names)?;
+        match (mask.iter().next(), mask.len()) {
+            (Some(port), 1) => Ok(port),
+            _ => anyhow::bail!("The selector '{self}' must select exactly one relay, not {mask:#}"),
+        }
+    }
+}
+
 /// Defines the connection type and parameters (Modbus TCP or RTU).
 #[derive(Subcommand, Debug, Clone, PartialEq)]
 pub enum CliConnection {
@@ -44,6 +159,9 @@ pub enum CliConnection {
         #[command(subcommand)]
         command: CliCommands,
     },
+    /// Use the connection of the selected (or default) profile.
+    #[command(flatten)]
+    Profile(CliCommands),
 }
 
 #[derive(Subcommand, Debug, Clone, PartialEq)]
@@ -51,25 +169,22 @@ pub enum CliCommands {
     /// Read and display the current state (ON/OFF) of all 8 relays.
     Status,
 
-    /// Turn a specific relay ON (Close circuit).
+    /// Turn the selected relays ON (Close circuit).
     On {
-        /// Relay number 0 to 7
-        #[arg(value_parser = parse_relay)]
-        relay: proto::Port,
+        /// Relays to switch: 0 to 7, a list or range (0,2,5 or 1-4), a mask (0x25 or 0b101), "all" or relay names
+        relays: RelaySelector,
     },
 
-    /// Turn a specific relay OFF (Open circuit).
+    /// Turn the selected relays OFF (Open circuit).
     Off {
-        /// Relay number 0 to 7
-        #[arg(value_parser = parse_relay)]
-        relay: proto::Port,
+        /// Relays to switch: 0 to 7, a list or range (0,2,5 or 1-4), a mask (0x25 or 0b101), "all" or relay names
+        relays: RelaySelector,
     },
 
-    /// Toggle the state of a specific relay (ON->OFF, OFF->ON).
+    /// Toggle the state of the selected relays (ON->OFF, OFF->ON).
     Toggle {
-        /// Relay number 0 to 7
-        #[arg(value_parser = parse_relay)]
-        relay: proto::Port,
+        /// Relays to switch: 0 to 7, a list or range (0,2,5 or 1-4), a mask (0x25 or 0b101), "all" or relay names
+        relays: RelaySelector,
     },
 
     /// Turn all 8 relays ON simultaneously.
@@ -80,31 +195,95 @@ pub enum CliCommands {
 
     /// Latch a relay ON and turn all other relays OFF (Inter-locking).
     Latch {
-        /// Relay number 0 to 7
-        #[arg(value_parser = parse_relay)]
-        relay: proto::Port,
+        /// Relay number 0 to 7 or relay name
+        relay: RelaySelector,
     },
 
-    /// Turn a relay ON momentarily (~1 second), then automatically OFF (Non-locking).
+    /// Turn the selected relays ON momentarily (~1 second), then automatically OFF (Non-locking).
     Momentary {
-        /// Relay number 0 to 7
-        #[arg(value_parser = parse_relay)]
-        relay: proto::Port,
+        /// Relays to switch: 0 to 7, a list or range (0,2,5 or 1-4), a mask (0x25 or 0b101), "all" or relay names
+        relays: RelaySelector,
     },
 
-    /// Turn a relay ON, then automatically OFF after a specified delay.
+    /// Turn the selected relays ON, then automatically OFF after a specified delay.
     Delay {
-        /// Relay number 0 to 7
-        #[arg(value_parser = parse_relay)]
-        relay: proto::Port,
+        /// Relays to switch: 0 to 7, a list or range (0,2,5 or 1-4), a mask (0x25 or 0b101), "all" or relay names
+        relays: RelaySelector,
         /// Delay duration in seconds (0-255).
         delay: u8,
     },
 
+    /// Continuously poll and display the relay states, highlighting changes.
+    Watch {
+        /// Time between two polls (e.g., "500ms", "2s").
+        #[arg(value_parser = parse_interval, long, default_value = "500ms")]
+        interval: Duration,
+        /// Print one line per relay transition instead of redrawing a table.
+        #[arg(long)]
+        changes_only: bool,
+    },
+
+    /// Run a sequence of relay steps from a TOML or YAML file. Ctrl-C aborts it.
+    #[cfg(feature = "sequence")]
+    Run {
+        /// The sequence file (.toml, .yaml or .yml).
+        file: PathBuf,
+    },
+
+    /// Start an interactive shell that keeps the connection open and accepts these commands.
+    Shell,
+
+    /// Serve an HTTP/REST API that controls the relays, e.g. for other services.
+    #[cfg(feature = "http-server")]
+    Serve {
+        /// The address and port to listen on.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+    },
+
+    /// Share the RTU line with Modbus TCP clients, e.g. SCADA tools or 'relay tcp'.
+    #[cfg(feature = "gateway")]
+    Gateway {
+        /// The address and port to listen on for Modbus TCP connections.
+        #[arg(long, default_value = "0.0.0.0:502")]
+        listen: std::net::SocketAddr,
+        /// Forward a TCP unit id to another slave address, e.g. "255=3". Repeatable.
+        #[arg(long = "map", value_name = "UNIT=SLAVE", value_parser = parse_unit_map)]
+        units: Vec<(u8, proto::Address)>,
+        /// Also present the relays as coils 0-7 (FC 0x01, 0x05, 0x0F) for SCADA tools.
+        #[arg(long)]
+        coils: bool,
+    },
+
+    /// Bridge the relays to an MQTT broker, with Home Assistant discovery.
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttArgs),
+
+    /// Fire relay actions on cron expressions or times of day from a TOML schedule file.
+    #[cfg(feature = "schedule")]
+    Schedule {
+        /// The schedule file.
+        file: PathBuf,
+    },
+
     /// Query the device's current Modbus address.
     /// IMPORTANT: Ensure only ONE device is connected to the bus!
     QueryAddress,
 
+    /// Scan the bus for devices by probing every address in the given range.
+    /// Devices sharing one address are reported as a likely address conflict.
+    Scan {
+        /// The first address to probe (1-247 or 0x01-0xF7).
+        #[arg(long, default_value = "1", value_parser = parse_address)]
+        from: proto::Address,
+        /// The last address to probe (1-247 or 0x01-0xF7).
+        #[arg(long, default_value = "247", value_parser = parse_address)]
+        to: proto::Address,
+        /// Time to wait for an answer per address (e.g., "50ms").
+        #[arg(value_parser = humantime::parse_duration, long, default_value = "50ms")]
+        probe_timeout: Duration,
+    },
+
     /// Set a new Modbus address for the device.
     /// The new address must be unique on the bus. Requires addressing the device with its CURRENT address.
     SetAddress {
@@ -114,6 +293,75 @@ pub enum CliCommands {
     },
 }
 
+/// The options of the MQTT bridge.
+#[cfg(feature = "mqtt")]
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct MqttArgs {
+    /// The MQTT broker as host or host:port.
+    #[arg(long, default_value = "localhost:1883")]
+    pub broker: String,
+    /// The user name for the broker.
+    #[arg(long)]
+    pub username: Option<String>,
+    /// The password for the broker.
+    #[arg(long, requires = "username")]
+    pub password: Option<String>,
+    /// The base topic of the device's state and command topics.
+    #[arg(long, default_value = "r413d08")]
+    pub topic: String,
+    /// The Home Assistant discovery prefix.
+    #[arg(long, default_value = "homeassistant")]
+    pub discovery_prefix: String,
+    /// Do not publish Home Assistant discovery payloads.
+    #[arg(long)]
+    pub no_discovery: bool,
+    /// Time between two polls of the relay states (e.g., "1s").
+    #[arg(value_parser = parse_interval, long, default_value = "1s")]
+    pub interval: Duration,
+}
+
+/// A line entered in the interactive shell.
+#[derive(Parser, Debug)]
+#[command(
+    name = "",
+    about = "Enter a command, e.g. 'on 3' or 'slave 2'.",
+    no_binary_name = true,
+    disable_version_flag = true
+)]
+pub struct ShellLine {
+    #[command(subcommand)]
+    pub command: ShellCommand,
+}
+
+/// The commands of the interactive shell: the device commands and the shell's own commands.
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum ShellCommand {
+    /// Switch to the device with the given RS485 address (1-247 or 0x01-0xF7).
+    Slave {
+        #[arg(value_parser = parse_address)]
+        address: proto::Address,
+    },
+    /// Leave the shell.
+    #[command(alias = "quit")]
+    Exit,
+    #[command(flatten)]
+    Device(CliCommands),
+}
+
+/// The format of the command output.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Human readable text.
+    #[default]
+    Text,
+    /// A JSON object per result, errors are reported as JSON objects too.
+    Json,
+    /// A header line followed by the values.
+    Csv,
+    /// The open relays as hexadecimal mask (e.g., "0x25").
+    Mask,
+}
+
 const fn about_text() -> &'static str {
     "A command-line tool to control R413D08 8-channel relay modules via Modbus TCP or RTU."
 }
@@ -125,11 +373,91 @@ pub struct CliArgs {
     #[command(flatten)]
     pub verbose: Verbosity<InfoLevel>,
 
-    /// Connection type (TCP or RTU) and associated command.
+    /// Connection type (TCP or RTU) and associated command, or only the command to use the profile's connection.
     #[command(subcommand)]
     pub connection: CliConnection,
 
-    /// Modbus I/O timeout duration (e.g., "200ms", "1s").
-    #[arg(value_parser = humantime::parse_duration, long, default_value = "200ms")]
-    pub timeout: Duration,
+    /// Modbus I/O timeout duration (e.g., "200ms", "1s") [default: the profile's timeout or 200ms].
+    #[arg(value_parser = humantime::parse_duration, long)]
+    pub timeout: Option<Duration>,
+
+    /// Configuration file with connection profiles and relay names [default: ~/.config/r413d08/config.toml].
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Name of the connection profile from the configuration file.
+    #[arg(short, long, global = true)]
+    pub profile: Option<String>,
+
+    /// Output format of the results.
+    #[arg(short, long, value_enum, global = true, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_polling_intervals() {
+        assert_eq!(parse_interval("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_interval("10ms"), Ok(MIN_POLL_INTERVAL));
+        for invalid in ["0s", "1ms", "fast"] {
+            assert!(parse_interval(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    fn resolve(selector: &str, names: &RelayNames) -> Result<proto::PortMask> {
+        selector.parse::<RelaySelector>().unwrap().resolve(names)
+    }
+
+    #[test]
+    fn resolve_relay_selectors() {
+        let names = RelayNames::from([
+            ("pump".to_string(), proto::PortMask::from_bits(0b0000_0001)),
+            (
+                "lights".to_string(),
+                proto::PortMask::from_bits(0b0011_0000),
+            ),
+        ]);
+        let bits = |selector| resolve(selector, &names).unwrap().bits();
+        assert_eq!(bits("3"), 0b0000_1000);
+        assert_eq!(bits("0,2,5"), 0b0010_0101);
+        assert_eq!(bits("1-4"), 0b0001_1110);
+        assert_eq!(bits("all"), 0xFF);
+        assert_eq!(bits("ALL"), 0xFF);
+        assert_eq!(bits("lights"), 0b0011_0000);
+        assert_eq!(bits("pump, 7"), 0b1000_0001);
+        assert_eq!(bits("0b101"), 0b0000_0101);
+        assert_eq!(bits("0x25"), 0x25);
+        assert_eq!(bits("0X08"), 0x08);
+
+        // Hexadecimal values that look like a relay number are ambiguous.
+        for ambiguous in ["0x03", "0x00", "0X07", "pump,0x04"] {
+            let err = resolve(ambiguous, &names).unwrap_err().to_string();
+            assert!(err.contains("ambiguous"), "{ambiguous}: {err}");
+        }
+        assert_eq!(
+            resolve("0x03", &names).unwrap_err().to_string(),
+            "The mask '0x03' is ambiguous: use 3 for relay 3 or 0b11 for the mask"
+        );
+
+        let err = resolve("pump,fan", &names).unwrap_err().to_string();
+        assert!(
+            err.contains("'fan'") && err.contains("lights, pump"),
+            "{err}"
+        );
+        assert!(resolve("8", &RelayNames::new()).is_err());
+        assert!(resolve("0b0", &names).is_err());
+    }
+
+    #[test]
+    fn resolve_single_relay() {
+        let names = RelayNames::from([("pump".to_string(), proto::PortMask::from_bits(0b0100))]);
+        let selector = |s: &str| s.parse::<RelaySelector>().unwrap();
+        assert_eq!(*selector("pump").resolve_single(&names).unwrap(), 2);
+        assert_eq!(*selector("0b1000").resolve_single(&names).unwrap(), 3);
+        assert!(selector("1-2").resolve_single(&names).is_err());
+        assert!(selector("all").resolve_single(&names).is_err());
+    }
 }
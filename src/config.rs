@@ -0,0 +1,431 @@
+//! Loads connection profiles and relay names from the configuration file.
+//!
+//! The file is read from `--config` or, if not given, from `r413d08/config.toml`
+//! in the user's configuration directory (`~/.config` on Linux). Example:
+//!
+//! ```toml
+//! # The profile used without --profile, also with a connection on the command line.
+//! default = "greenhouse"
+//!
+//! [profiles.greenhouse]
+//! device = "/dev/ttyUSB0"
+//! address = 3
+//! timeout = "500ms"
+//!
+//! [profiles.greenhouse.relays]
+//! pump = 0
+//! valve-north = 1
+//! lights = "4-5"
+//!
+//! [profiles.garage]
+//! tcp = "192.168.0.222:502"
+//! ```
+
+use crate::commandline::{CliArgs, CliCommands, CliConnection, DEFAULT_TIMEOUT};
+use anyhow::{bail, Context, Result};
+use r413d08_lib::protocol as proto;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The human names of relays, each naming one or more relays.
+pub type RelayNames = BTreeMap<String, proto::PortMask>;
+
+/// The relays a name stands for: a relay number or a selector like "4-5".
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum RelayName {
+    Number(u8),
+    Selector(String),
+}
+
+/// A named connection of the configuration file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    /// The TCP endpoint; if set, the profile connects via Modbus/TCP.
+    tcp: Option<String>,
+    /// The serial device path for Modbus/RTU.
+    device: Option<String>,
+    /// The RS485 address for Modbus/RTU.
+    address: Option<u8>,
+    /// The Modbus I/O timeout (e.g., "500ms").
+    timeout: Option<String>,
+    /// The human names of the relays.
+    #[serde(default)]
+    relays: BTreeMap<String, RelayName>,
+}
+
+/// The content of the configuration file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// The profile used when the command line selects no profile.
+    default: Option<String>,
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+/// Returns the default location of the configuration file.
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("r413d08").join("config.toml"))
+}
+
+impl Config {
+    /// Loads the configuration file from `path` or, if not given, from `default_path`.
+    ///
+    /// A missing file at the default location yields an empty configuration,
+    /// a missing file given with `--config` is an error.
+    fn load(path: Option<&Path>, default_path: Option<PathBuf>) -> Result<(Self, Option<PathBuf>)> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path {
+                Some(path) => (path, false),
+                None => return Ok((Self::default(), None)),
+            },
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok((Self::default(), None));
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Cannot read config file {path:?}"))
+            }
+        };
+        let config = toml::from_str(&content)
+            .with_context(|| format!("Cannot parse config file {path:?}"))?;
+        Ok((config, Some(path)))
+    }
+}
+
+impl Profile {
+    /// Returns the connection of the profile.
+    fn connection(&self) -> Result<Connection> {
+        match (&self.tcp, &self.device, self.address) {
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                bail!("A profile either sets 'tcp' or 'device' and 'address', not both")
+            }
+            (Some(address), None, None) => Ok(Connection::Tcp {
+                address: address.clone(),
+            }),
+            (None, device, address) => Ok(Connection::Rtu {
+                device: device
+                    .clone()
+                    .unwrap_or_else(crate::commandline::default_device_name),
+                address: address
+                    .map(proto::Address::try_from)
+                    .transpose()
+                    .context("Invalid 'address'")?
+                    .unwrap_or_default(),
+            }),
+        }
+    }
+
+    /// Returns the Modbus I/O timeout of the profile, if set.
+    fn timeout(&self) -> Result<Option<Duration>> {
+        self.timeout
+            .as_deref()
+            .map(humantime::parse_duration)
+            .transpose()
+            .context("Invalid 'timeout'")
+    }
+
+    /// Returns the relay names of the profile.
+    ///
+    /// Names that read like a relay selector (e.g. "1", "0x03", "1-4" or "all")
+    /// are rejected, since the selector would mean other relays than the name.
+    fn relay_names(&self) -> Result<RelayNames> {
+        self.relays
+            .iter()
+            .map(|(name, relays)| {
+                if name.eq_ignore_ascii_case("all") || name.parse::<proto::PortMask>().is_ok() {
+                    bail!(
+                        "The relay name '{name}' reads like a relay selector, choose another name"
+                    );
+                }
+                let mask = match relays {
+                    RelayName::Number(index) => proto::Port::try_from(*index)
+                        .map(proto::PortMask::from)
+                        .map_err(anyhow::Error::from),
+                    RelayName::Selector(selector) => selector
+                        .parse::<proto::PortMask>()
+                        .map_err(anyhow::Error::from),
+                }
+                .with_context(|| format!("Invalid relays for the name '{name}'"))?;
+                Ok((name.clone(), mask))
+            })
+            .collect()
+    }
+}
+
+/// The connection to the device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Connection {
+    /// Modbus/TCP to the given endpoint.
+    Tcp { address: String },
+    /// Modbus/RTU via the serial device to the given RS485 address.
+    Rtu {
+        device: String,
+        address: proto::Address,
+    },
+}
+
+/// The settings of a CLI invocation, merged from the command line and the configuration file.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub connection: Connection,
+    pub timeout: Duration,
+    pub relay_names: RelayNames,
+}
+
+impl Settings {
+    /// Resolves the settings and the command to run.
+    ///
+    /// The profile is selected with `--profile` or is the default profile of
+    /// the configuration file, whether the file was given with `--config` or not.
+    /// The connection given on the command line takes precedence over the one
+    /// of the profile, the profile still provides the relay names. The `--timeout`
+    /// option takes precedence over the timeout of the profile.
+    pub fn resolve(args: &CliArgs) -> Result<(Self, &CliCommands)> {
+        Self::resolve_with_default(args, default_path())
+    }
+
+    /// Like [`Settings::resolve`], with the default location of the configuration file.
+    fn resolve_with_default(
+        args: &CliArgs,
+        default_path: Option<PathBuf>,
+    ) -> Result<(Self, &CliCommands)> {
+        let (connection, command) = match &args.connection {
+            CliConnection::Tcp { address, command } => (
+                Some(Connection::Tcp {
+                    address: address.clone(),
+                }),
+                command,
+            ),
+            CliConnection::Rtu {
+                device,
+                address,
+                command,
+            } => (
+                Some(Connection::Rtu {
+                    device: device.clone(),
+                    address: *address,
+                }),
+                command,
+            ),
+            CliConnection::Profile(command) => (None, command),
+        };
+
+        let (config, path) = Config::load(args.config.as_deref(), default_path)?;
+        let location = || match &path {
+            Some(path) => format!("{path:?}"),
+            None => "the configuration (no config file found)".to_string(),
+        };
+        let profile = match args.profile.as_ref().or(config.default.as_ref()) {
+            Some(name) => {
+                let profile = config
+                    .profiles
+                    .get(name)
+                    .with_context(|| format!("Profile '{name}' not found in {}", location()))?;
+                Some((name.clone(), profile.clone()))
+            }
+            None if connection.is_none() => bail!(
+                "No connection given: use the 'tcp' or 'rtu' command, select a profile with \
+                 --profile or set a default profile in {}",
+                location()
+            ),
+            None => None,
+        };
+
+        let Some((name, profile)) = profile else {
+            return Ok((
+                Self {
+                    connection: connection.expect("connection given without profile"),
+                    timeout: args.timeout.unwrap_or(DEFAULT_TIMEOUT),
+                    relay_names: RelayNames::new(),
+                },
+                command,
+            ));
+        };
+        let context = || format!("Invalid profile '{name}'");
+        let connection = match connection {
+            Some(connection) => connection,
+            None => profile.connection().with_context(context)?,
+        };
+        let timeout = match args.timeout {
+            Some(timeout) => timeout,
+            None => profile
+                .timeout()
+                .with_context(context)?
+                .unwrap_or(DEFAULT_TIMEOUT),
+        };
+        Ok((
+            Self {
+                connection,
+                timeout,
+                relay_names: profile.relay_names().with_context(context)?,
+            },
+            command,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    const CONFIG: &str = r#"
+        default = "greenhouse"
+
+        [profiles.greenhouse]
+        device = "/dev/ttyUSB1"
+        address = 3
+        timeout = "500ms"
+        relays = { pump = 0, lights = "4-5" }
+
+        [profiles.garage]
+        tcp = "192.168.0.222:502"
+    "#;
+
+    /// Writes the configuration to a file unique to the test and returns its path.
+    fn config_file(test: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("r413d08-config-{}-{test}.toml", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn resolve(config: &Path, args: &[&str]) -> Result<Settings> {
+        let config = config.to_str().unwrap();
+        let args = CliArgs::try_parse_from(["relay", "--config", config].iter().chain(args))?;
+        Settings::resolve(&args).map(|(settings, _)| settings)
+    }
+
+    #[test]
+    fn profile_and_command_line_precedence() {
+        let path = config_file("precedence", CONFIG);
+        let rtu = |device: &str, address| Connection::Rtu {
+            device: device.to_string(),
+            address: proto::Address::try_from(address).unwrap(),
+        };
+
+        // The default profile provides the connection, timeout and relay names.
+        let settings = resolve(&path, &["status"]).unwrap();
+        assert_eq!(settings.connection, rtu("/dev/ttyUSB1", 3));
+        assert_eq!(settings.timeout, Duration::from_millis(500));
+        assert_eq!(
+            settings.relay_names["lights"],
+            proto::PortMask::from_bits(0b0011_0000)
+        );
+
+        // --profile selects another profile, which has no timeout.
+        let settings = resolve(&path, &["--profile", "garage", "status"]).unwrap();
+        assert_eq!(
+            settings.connection,
+            Connection::Tcp {
+                address: "192.168.0.222:502".to_string()
+            }
+        );
+        assert_eq!(settings.timeout, DEFAULT_TIMEOUT);
+        assert!(settings.relay_names.is_empty());
+
+        // --timeout overrides the profile's timeout.
+        let settings = resolve(&path, &["--timeout", "2s", "status"]).unwrap();
+        assert_eq!(settings.timeout, Duration::from_secs(2));
+
+        // A connection on the command line overrides the profile's, the names remain.
+        let settings = resolve(&path, &["rtu", "-d", "/dev/ttyS0", "-a", "7", "status"]).unwrap();
+        assert_eq!(settings.connection, rtu("/dev/ttyS0", 7));
+        assert_eq!(settings.timeout, Duration::from_millis(500));
+        assert_eq!(settings.relay_names.len(), 2);
+
+        let err = resolve(&path, &["--profile", "attic", "status"]).unwrap_err();
+        assert!(
+            err.to_string().contains("Profile 'attic' not found"),
+            "{err}"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let path = config_file("no-default", "[profiles.garage]\ntcp = \"10.0.0.1:502\"");
+        let err = resolve(&path, &["status"]).unwrap_err();
+        assert!(err.to_string().starts_with("No connection given"), "{err}");
+        std::fs::remove_file(path).unwrap();
+
+        let path = config_file(
+            "both",
+            "default = \"x\"\n[profiles.x]\ntcp = \"10.0.0.1:502\"\naddress = 2",
+        );
+        let err = resolve(&path, &["status"]).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Invalid profile 'x': A profile either sets 'tcp' or 'device' and 'address', not both"
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let path = config_file("relays", "default = \"x\"\n[profiles.x.relays]\npump = 9");
+        let err = resolve(&path, &["status"]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Invalid relays for the name 'pump'"),
+            "{err:#}"
+        );
+        std::fs::remove_file(path).unwrap();
+
+        for name in ["1", "0x03", "1-4", "All"] {
+            let path = config_file(
+                "selector-name",
+                &format!("default = \"x\"\n[profiles.x.relays]\n\"{name}\" = 2"),
+            );
+            let err = resolve(&path, &["status"]).unwrap_err();
+            assert!(
+                format!("{err:#}").contains("reads like a relay selector"),
+                "{name}: {err:#}"
+            );
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn default_config_file() {
+        let path = config_file("default", CONFIG);
+        let resolve = |default_path: &Path, args: &[&str]| {
+            let args = CliArgs::try_parse_from(["relay"].iter().chain(args)).unwrap();
+            Settings::resolve_with_default(&args, Some(default_path.to_path_buf()))
+                .map(|(settings, _)| settings)
+        };
+
+        // The default profile applies without --config, also with a connection on the command line.
+        let settings = resolve(&path, &["status"]).unwrap();
+        assert_eq!(
+            settings.connection,
+            Connection::Rtu {
+                device: "/dev/ttyUSB1".to_string(),
+                address: proto::Address::try_from(3).unwrap(),
+            }
+        );
+        let settings = resolve(&path, &["tcp", "10.0.0.1:502", "status"]).unwrap();
+        assert_eq!(
+            settings.connection,
+            Connection::Tcp {
+                address: "10.0.0.1:502".to_string()
+            }
+        );
+        assert_eq!(settings.timeout, Duration::from_millis(500));
+        assert_eq!(settings.relay_names.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        // A missing default file only matters without a connection.
+        let settings = resolve(&path, &["tcp", "10.0.0.1:502", "status"]).unwrap();
+        assert_eq!(settings.timeout, DEFAULT_TIMEOUT);
+        assert!(settings.relay_names.is_empty());
+        let err = resolve(&path, &["status"]).unwrap_err();
+        assert!(err.to_string().starts_with("No connection given"), "{err}");
+    }
+}
@@ -0,0 +1,273 @@
+//! Provides a Modbus TCP gateway that shares one Modbus RTU line with many network clients.
+//!
+//! The [`Gateway`] accepts Modbus TCP connections and forwards every request to a
+//! single downstream client context, usually a Modbus RTU context on a serial port.
+//! The requests of all connections are queued in the order they arrive and sent one
+//! after the other, so the bus never sees overlapping frames.
+//!
+//! The unit id of a TCP request selects the slave address on the RTU line. By
+//! default the unit id is used as is, [`Gateway::map_unit`] routes a unit id to
+//! another slave. Note that R413D08 boards also answer on the unit id `0xFF`
+//! ([`proto::Address::BROADCAST`]), which Modbus TCP clients use by default.
+//!
+//! Failures on the line are answered with the gateway exceptions of the Modbus
+//! specification: [`ExceptionCode::GatewayTargetDevice`] if the slave does not
+//! answer within the timeout, [`ExceptionCode::GatewayPathUnavailable`] if the
+//! line itself fails. Consecutive requests are separated by the RTU
+//! [`INTER_FRAME_DELAY`], and a gateway created with [`Gateway::with_reconnect`]
+//! reopens the line after a timeout, so a late response cannot be taken for the
+//! answer to the next request.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use r413d08_lib::{gateway::Gateway, tokio_common::serial_port_builder};
+//! use tokio::net::TcpListener;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let gateway = Gateway::with_reconnect(|| async {
+//!         let serial = tokio_serial::SerialStream::open(&serial_port_builder("/dev/ttyUSB0"))?;
+//!         Ok(tokio_modbus::client::rtu::attach(serial))
+//!     })
+//!     .await?;
+//!     let listener = TcpListener::bind("0.0.0.0:502").await?;
+//!     gateway.serve(listener).await?;
+//!     Ok(())
+//! }
+//! ```
+
+use crate::{protocol as proto, tokio_common::INTER_FRAME_DELAY};
+use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+use tokio::{net::TcpListener, sync::Mutex, time::Instant};
+use tokio_modbus::{
+    client::{Client, Context},
+    prelude::SlaveContext,
+    server::tcp::{accept_tcp_connection, Server},
+    ExceptionCode, Response, Slave, SlaveId, SlaveRequest,
+};
+
+/// The time the gateway waits for the response of a slave by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A boxed future, as returned by a [`Connect`] function.
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A function that opens the downstream line, see [`Gateway::with_reconnect`].
+type Connect = dyn Fn() -> BoxFuture<'static, std::io::Result<Context>> + Send + Sync;
+
+/// The downstream line shared by all connections.
+struct Line {
+    ctx: Context,
+    last_transaction: Option<Instant>,
+    /// Set after a timeout or a transport failure, the context is rebuilt before the next request.
+    stale: bool,
+}
+
+/// A Modbus TCP server that forwards all requests through one downstream context.
+///
+/// The gateway can be cheaply cloned, all clones share the downstream context
+/// and its queue. It implements [`tokio_modbus::server::Service`], so it can
+/// also be plugged into any other `tokio-modbus` server.
+#[derive(Clone)]
+pub struct Gateway {
+    line: Arc<Mutex<Line>>,
+    connect: Option<Arc<Connect>>,
+    units: BTreeMap<SlaveId, SlaveId>,
+    timeout: Duration,
+}
+
+impl Gateway {
+    /// Creates a new `Gateway` forwarding to the given context with the [`DEFAULT_TIMEOUT`].
+    ///
+    /// The context is kept after a timeout, so a response that arrives late may
+    /// be taken for the answer to the next request. Use [`Gateway::with_reconnect`]
+    /// to reopen the line instead.
+    pub fn new(ctx: Context) -> Self {
+        Self::from_line(ctx, None)
+    }
+
+    /// Creates a new `Gateway` that reopens the downstream line after a failed request.
+    ///
+    /// The `connect` function is called once to open the line and again before
+    /// the next request whenever a slave did not answer in time or the line
+    /// failed. This discards any response that arrives after the timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the line cannot be opened initially.
+    pub async fn with_reconnect<F, Fut>(connect: F) -> std::io::Result<Self>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<Context>> + Send + 'static,
+    {
+        let ctx = connect().await?;
+        Ok(Self::from_line(
+            ctx,
+            Some(Arc::new(move || {
+                Box::pin(connect()) as BoxFuture<'static, _>
+            })),
+        ))
+    }
+
+    fn from_line(ctx: Context, connect: Option<Arc<Connect>>) -> Self {
+        Self {
+            line: Arc::new(Mutex::new(Line {
+                ctx,
+                last_transaction: None,
+                stale: false,
+            })),
+            connect,
+            units: BTreeMap::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets the time to wait for the response of a slave.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Forwards the requests for the given TCP unit id to the given slave address.
+    pub fn map_unit(mut self, unit: SlaveId, slave: proto::Address) -> Self {
+        self.units.insert(unit, *slave);
+        self
+    }
+
+    /// Returns the slave address the requests for the given TCP unit id are forwarded to.
+    pub fn slave(&self, unit: SlaveId) -> SlaveId {
+        self.units.get(&unit).copied().unwrap_or(unit)
+    }
+
+    /// Serves the gateway as a Modbus TCP server on the given listener.
+    ///
+    /// Each accepted connection is served by its own task, all of them sharing
+    /// the downstream context. This function only returns if accepting a new
+    /// connection fails.
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        let server = Server::new(listener);
+        let on_connected = |stream, socket_addr| {
+            let gateway = self.clone();
+            async move {
+                log::info!("Gateway client {socket_addr} connected");
+                accept_tcp_connection(stream, socket_addr, move |_| Ok(Some(gateway.clone())))
+            }
+        };
+        let on_process_error = |err| log::warn!("Gateway connection failed: {err}");
+        server.serve(&on_connected, on_process_error).await
+    }
+}
+
+impl tokio_modbus::server::Service for Gateway {
+    type Request = SlaveRequest<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, ExceptionCode>> + Send>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let slave = self.slave(req.slave);
+        let line = self.line.clone();
+        let connect = self.connect.clone();
+        let timeout = self.timeout;
+        Box::pin(async move {
+            // The lock is fair, so the requests are forwarded in the order they arrived.
+            let mut line = line.lock().await;
+            if let Some(last) = line.last_transaction {
+                tokio::time::sleep_until(last + INTER_FRAME_DELAY).await;
+            }
+            if let Some(connect) = connect.filter(|_| line.stale) {
+                match connect().await {
+                    Ok(ctx) => {
+                        line.ctx = ctx;
+                        line.stale = false;
+                    }
+                    Err(err) => {
+                        log::warn!("Reopening the line failed: {err}");
+                        return Err(ExceptionCode::GatewayPathUnavailable);
+                    }
+                }
+            }
+            line.ctx.set_slave(Slave(slave));
+            log::debug!("Unit {} -> slave {slave}: {:?}", req.slave, req.request);
+            let result = tokio::time::timeout(timeout, line.ctx.call(req.request)).await;
+            line.last_transaction = Some(Instant::now());
+            match result {
+                Ok(Ok(Ok(response))) => Ok(response),
+                Ok(Ok(Err(exception))) => {
+                    log::debug!("Slave {slave} rejected the request: {exception}");
+                    Err(exception)
+                }
+                Ok(Err(err)) => {
+                    line.stale = true;
+                    log::warn!("Forwarding to slave {slave} failed: {err}");
+                    Err(ExceptionCode::GatewayPathUnavailable)
+                }
+                Err(_) => {
+                    line.stale = true;
+                    log::debug!("Slave {slave} did not answer within {timeout:?}");
+                    Err(ExceptionCode::GatewayTargetDevice)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod tests {
+    use super::*;
+    use crate::{
+        simulator::testing::{address, port, Fixture},
+        tokio_async::R413D08,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn gateway_forwards_to_simulator() {
+        let fixture = Fixture::with_devices([address(2), address(5)]);
+        let connects = Arc::new(AtomicUsize::new(0));
+        let socket_addr = fixture.socket_addr;
+        let gateway = Gateway::with_reconnect({
+            let connects = connects.clone();
+            move || {
+                connects.fetch_add(1, Ordering::Relaxed);
+                tokio_modbus::client::tcp::connect(socket_addr)
+            }
+        })
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(50))
+        .map_unit(*proto::Address::BROADCAST, address(5));
+        assert_eq!(gateway.slave(2), 2);
+        assert_eq!(gateway.slave(*proto::Address::BROADCAST), 5);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway_addr = listener.local_addr().unwrap();
+        tokio::spawn(gateway.serve(listener));
+
+        // The unit id selects the slave, the mapped one a different slave.
+        let mut ctx = tokio_modbus::client::tcp::connect(gateway_addr)
+            .await
+            .unwrap();
+        ctx.set_slave(Slave(2));
+        R413D08::set_port_open(&mut ctx, port(1)).await.unwrap();
+        ctx.set_slave(Slave(*proto::Address::BROADCAST));
+        R413D08::set_all_open(&mut ctx).await.unwrap();
+        assert_eq!(fixture.open_ports_of(address(2)), port(1).into());
+        assert_eq!(fixture.open_ports_of(address(5)), proto::PortMask::ALL);
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+
+        // Nobody answers at address 9, so the line is reopened for the next request.
+        ctx.set_slave(Slave(9));
+        let err = R413D08::read_ports(&mut ctx).await.unwrap_err();
+        assert_eq!(
+            err.exception_code(),
+            Some(ExceptionCode::GatewayTargetDevice)
+        );
+        ctx.set_slave(Slave(2));
+        assert_eq!(
+            R413D08::read_ports(&mut ctx).await.unwrap(),
+            proto::PortMask::from(port(1)).into()
+        );
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,36 @@
+//! Routes Ctrl-C to the command that is running.
+//!
+//! Commands that run until they are stopped, like `watch` and `run`, handle
+//! Ctrl-C themselves and return, so the shell keeps running afterwards. Without
+//! a running command, Ctrl-C terminates the process as usual.
+
+use log::*;
+use std::sync::{Mutex, Once};
+
+/// The reaction of the running command to Ctrl-C.
+type Handler = Box<dyn Fn() + Send>;
+
+/// The handler of the running command.
+static HANDLER: Mutex<Option<Handler>> = Mutex::new(None);
+
+/// Calls the handler of the running command, or terminates like the default handler if there is none.
+fn interrupted() {
+    match HANDLER.lock().unwrap().as_ref() {
+        Some(handler) => handler(),
+        None => std::process::exit(130),
+    }
+}
+
+/// Runs `command`, calling `handler` whenever Ctrl-C is pressed meanwhile.
+pub fn handle<T>(handler: impl Fn() + Send + 'static, command: impl FnOnce() -> T) -> T {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        if let Err(err) = ctrlc::set_handler(interrupted) {
+            warn!("Cannot handle Ctrl-C: {err}");
+        }
+    });
+    *HANDLER.lock().unwrap() = Some(Box::new(handler));
+    let result = command();
+    *HANDLER.lock().unwrap() = None;
+    result
+}
@@ -16,9 +16,15 @@
 //! ## Key Features
 //!
 //! - **Protocol Implementation**: Complete implementation of the R413D08 Modbus protocol.
-//! - **Stateful, Thread-Safe Clients**: For easy and safe concurrent use.
+//! - **Stateful, Thread-Safe Clients**: For easy and safe concurrent use, with optional
+//!   automatic reconnect and retries of transient failures.
 //! - **Stateless, Low-Level Functions**: For maximum flexibility and control.
 //! - **Synchronous and Asynchronous APIs**: Both blocking and `async/await` APIs are available.
+//! - **Change Notifications**: Streams timestamped port changes and communication loss/recovery
+//!   (async), or invokes callbacks from a background poller thread (sync).
+//! - **Multi-Device Bus**: Shares one serial line between several modules with per-address handles.
+//! - **Bus Scanner**: Discovers all devices on a bus and flags likely address conflicts.
+//! - **Device Simulator**: A Modbus TCP server emulating the device for tests without hardware.
 //! - **Strongly-Typed API**: Utilizes Rust's type system for protocol correctness
 //!   (e.g., `Port`, `Address`, `PortState`).
 //!
@@ -43,13 +49,23 @@
 //! - `tokio-tcp-sync`: Enables the synchronous (`blocking`) TCP backend.
 //! - `tokio-rtu`: Enables the asynchronous (`async`) RTU backend.
 //! - `tokio-tcp`: Enables the asynchronous (`async`) TCP backend.
-//! - `safe-client-sync`: Enables the high-level, thread-safe, synchronous [`tokio_sync_safe_client::SafeClient`].
-//!   Requires either `tokio-rtu-sync` or `tokio-tcp-sync`.
-//! - `safe-client-async`: Enables the high-level, thread-safe, asynchronous [`tokio_async_safe_client::SafeClient`].
-//!   Requires either `tokio-rtu` or `tokio-tcp`.
+//! - `safe-client-sync`: Enables the high-level, thread-safe, synchronous [`tokio_sync_safe_client::SafeClient`]
+//!   with its [`tokio_sync_poller::Poller`] and [`tokio_sync_bus::Bus`]. Requires either `tokio-rtu-sync` or `tokio-tcp-sync`.
+//! - `safe-client-async`: Enables the high-level, thread-safe, asynchronous [`tokio_async_safe_client::SafeClient`]
+//!   and [`tokio_async_bus::Bus`]. Requires either `tokio-rtu` or `tokio-tcp`.
+//! - `simulator`: Enables the [`simulator::Simulator`], a software model of the
+//!   device served as a Modbus TCP server (e.g., for testing without hardware).
+//! - `coils`: Enables the [`coils::CoilAdapter`], which presents the relays of any
+//!   register-level Modbus service as coils.
+//! - `gateway`: Enables the [`gateway::Gateway`] forwarding Modbus TCP requests to an RTU line.
+//!   Includes `coils`.
 //! - `serde`: Enables `serde` support for the `protocol` types.
-//! - `bin-dependencies`: Enables all dependencies required for the `R413D08`
-//!   binary. This is not intended for library users.
+//! - `sequence`: Enables the [`sequence`] engine running scripted relay steps, defined in
+//!   Rust or loaded from TOML/YAML files. Requires either `tokio-rtu-sync` or `tokio-tcp-sync`.
+//! - `bin-dependencies`: Enables all dependencies required for the `relay`
+//!   binary with its core commands. This is not intended for library users.
+//!   The `gateway`, `sequence` and `simulator` features add the `gateway` and
+//!   `run` commands and the `relay-sim` binary.
 //!
 //! The `default` feature enables `bin-dependencies`.
 //!
@@ -102,6 +118,18 @@ pub mod protocol;
 ))]
 pub mod tokio_common;
 
+#[cfg(any(
+    all(
+        feature = "safe-client-sync",
+        any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")
+    ),
+    all(
+        feature = "safe-client-async",
+        any(feature = "tokio-rtu", feature = "tokio-tcp")
+    )
+))]
+mod timers;
+
 #[cfg_attr(
     docsrs,
     doc(cfg(any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")))
@@ -109,6 +137,13 @@ pub mod tokio_common;
 #[cfg(any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync"))]
 pub mod tokio_sync;
 
+#[cfg_attr(
+    docsrs,
+    doc(cfg(any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")))
+)]
+#[cfg(any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync"))]
+pub mod tokio_sync_scanner;
+
 #[cfg_attr(docsrs, doc(cfg(any(feature = "tokio-rtu", feature = "tokio-tcp"))))]
 #[cfg(any(feature = "tokio-rtu", feature = "tokio-tcp"))]
 pub mod tokio_async;
@@ -126,6 +161,32 @@ pub mod tokio_async;
 ))]
 pub mod tokio_sync_safe_client;
 
+#[cfg_attr(
+    docsrs,
+    doc(cfg(all(
+        feature = "safe-client-sync",
+        any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")
+    )))
+)]
+#[cfg(all(
+    feature = "safe-client-sync",
+    any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")
+))]
+pub mod tokio_sync_bus;
+
+#[cfg_attr(
+    docsrs,
+    doc(cfg(all(
+        feature = "safe-client-sync",
+        any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")
+    )))
+)]
+#[cfg(all(
+    feature = "safe-client-sync",
+    any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")
+))]
+pub mod tokio_sync_poller;
+
 #[cfg_attr(
     docsrs,
     doc(cfg(all(
@@ -138,3 +199,41 @@ pub mod tokio_sync_safe_client;
     any(feature = "tokio-rtu", feature = "tokio-tcp")
 ))]
 pub mod tokio_async_safe_client;
+
+#[cfg_attr(
+    docsrs,
+    doc(cfg(all(
+        feature = "safe-client-async",
+        any(feature = "tokio-rtu", feature = "tokio-tcp")
+    )))
+)]
+#[cfg(all(
+    feature = "safe-client-async",
+    any(feature = "tokio-rtu", feature = "tokio-tcp")
+))]
+pub mod tokio_async_bus;
+
+#[cfg_attr(docsrs, doc(cfg(feature = "simulator")))]
+#[cfg(feature = "simulator")]
+pub mod simulator;
+
+#[cfg_attr(docsrs, doc(cfg(feature = "gateway")))]
+#[cfg(feature = "gateway")]
+pub mod gateway;
+
+#[cfg_attr(docsrs, doc(cfg(feature = "coils")))]
+#[cfg(feature = "coils")]
+pub mod coils;
+
+#[cfg_attr(
+    docsrs,
+    doc(cfg(all(
+        feature = "sequence",
+        any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")
+    )))
+)]
+#[cfg(all(
+    feature = "sequence",
+    any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")
+))]
+pub mod sequence;
@@ -11,10 +11,28 @@ use clap::Parser;
 use dialoguer::Confirm;
 use flexi_logger::{Logger, LoggerHandle};
 use log::*;
-use r413d08_lib::{protocol as proto, tokio_sync_safe_client::SafeClient};
-use std::{ops::Deref, panic};
+use output::{Output, Report};
+#[cfg(feature = "gateway")]
+use r413d08_lib::{coils::CoilAdapter, gateway::Gateway};
+use r413d08_lib::{protocol as proto, tokio_sync_safe_client::SafeClient, tokio_sync_scanner};
+use std::{ops::Deref, panic, process::ExitCode, time::SystemTime};
 
 mod commandline;
+mod config;
+mod interrupt;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod output;
+#[cfg(feature = "sequence")]
+mod run;
+#[cfg(feature = "schedule")]
+mod schedule;
+#[cfg(feature = "http-server")]
+mod serve;
+mod shell;
+#[cfg(all(test, feature = "simulator"))]
+mod testing;
+mod watch;
 
 fn logging_init(loglevel: LevelFilter) -> LoggerHandle {
     let log_handle = Logger::try_with_env_or_str(loglevel.as_str())
@@ -46,37 +64,105 @@ fn logging_init(loglevel: LevelFilter) -> LoggerHandle {
     log_handle
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let args = commandline::CliArgs::parse();
 
     let _log_handle = logging_init(args.verbose.log_level_filter());
 
-    let (mut ctx, command) = match &args.connection {
-        commandline::CliConnection::Tcp { address, command } => {
-            let socket_addr = address
+    let (settings, command) = match config::Settings::resolve(&args) {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            Output::new(args.output, None).error(&err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let address = match &settings.connection {
+        config::Connection::Tcp { .. } => None,
+        config::Connection::Rtu { address, .. } => Some(*address),
+    };
+    let output = Output::new(args.output, address);
+    match run(&settings, command, &output) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            output.error(&err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Reads the port states and reports them, optionally after a command described by the message.
+fn report_states(client: &SafeClient, output: &Output, message: Option<String>) -> Result<()> {
+    let states = client.read_ports().context("Failed to read port status")?;
+    output.report(&Report::States {
+        message,
+        at: SystemTime::now(),
+        states,
+    });
+    Ok(())
+}
+
+/// Returns a human readable name of the selected relays, e.g. "Relay 3" or "Relays 0,2,5".
+fn relays_name(relays: proto::PortMask) -> String {
+    match relays.len() {
+        1 => format!("Relay {relays:#}"),
+        proto::NUMBER_OF_PORTS => "All relays".to_string(),
+        _ => format!("Relays {relays:#}"),
+    }
+}
+
+/// Switches the selected relays to the states computed from the currently open relays.
+///
+/// A single relay is switched with its own command, which needs no read. For
+/// several relays the current states are read once and the transition is
+/// planned with [`proto::Command::plan_transition`], so it uses the all-open,
+/// all-close and latch shortcuts where possible and per-port writes otherwise.
+fn switch_relays(
+    client: &SafeClient,
+    relays: proto::PortMask,
+    single: fn(proto::Port) -> proto::Command,
+    target: impl FnOnce(proto::PortMask) -> proto::PortMask,
+) -> std::result::Result<(), r413d08_lib::tokio_common::Error> {
+    if let (Some(relay), 1) = (relays.iter().next(), relays.len()) {
+        return client.execute(single(relay));
+    }
+    let current = proto::PortMask::from(client.read_ports()?);
+    for command in proto::Command::plan_transition(current, target(current)) {
+        client.execute(command)?;
+    }
+    Ok(())
+}
+
+/// A function that opens the connection to the device.
+type Connect = Box<dyn Fn() -> std::io::Result<tokio_modbus::client::sync::Context> + Send + Sync>;
+
+fn run(
+    settings: &config::Settings,
+    command: &commandline::CliCommands,
+    output: &Output,
+) -> Result<()> {
+    #[cfg(feature = "gateway")]
+    if let commandline::CliCommands::Gateway {
+        listen,
+        units,
+        coils,
+    } = command
+    {
+        return gateway(settings, *listen, units, *coils);
+    }
+    let (connect, target): (Connect, String) = match &settings.connection {
+        config::Connection::Tcp { address } => {
+            let socket_addr: std::net::SocketAddr = address
                 .parse()
                 .with_context(|| format!("Cannot parse TCP address '{address}'"))?;
             trace!("Connecting via TCP to {socket_addr}...");
             (
-                tokio_modbus::client::sync::tcp::connect(socket_addr)
-                    .with_context(|| format!("Cannot open {socket_addr:?}"))?,
-                command,
+                Box::new(move || tokio_modbus::client::sync::tcp::connect(socket_addr)),
+                format!("{socket_addr:?}"),
             )
         }
-        commandline::CliConnection::Rtu {
-            device,
-            address,
-            command,
-        } => {
+        config::Connection::Rtu { device, address } => {
             let address = if command == &commandline::CliCommands::QueryAddress {
-                println!("Ensure ONLY ONE device is connected to the RS485 bus.");
-                let confirmation = Confirm::new()
-                    .with_prompt("Do you want to continue?")
-                    .default(false)
-                    .show_default(true)
-                    .interact()
-                    .context("Failed to get user confirmation")?;
-                if !confirmation {
+                if !confirm_single_device()? {
                     return Ok(());
                 }
                 let broadcast_address = proto::Address::BROADCAST;
@@ -88,101 +174,280 @@ fn main() -> Result<()> {
                 *address
             };
             trace!("Connecting via RTU to {device} address {address}");
+            let builder = r413d08_lib::tokio_common::serial_port_builder(device);
             (
-                tokio_modbus::client::sync::rtu::connect_slave(
-                    &r413d08_lib::tokio_common::serial_port_builder(device),
-                    tokio_modbus::Slave(*address),
-                )
-                .with_context(|| format!("Cannot open RTU device {device}"))?,
-                command,
+                Box::new(move || {
+                    tokio_modbus::client::sync::rtu::connect_slave(
+                        &builder,
+                        tokio_modbus::Slave(*address),
+                    )
+                }),
+                format!("RTU device {device}"),
             )
         }
     };
-    ctx.set_timeout(Some(args.timeout));
-    let client = SafeClient::new(ctx);
+    let timeout = settings.timeout;
+    let connect = move || -> std::io::Result<_> {
+        let mut ctx = connect()?;
+        ctx.set_timeout(Some(timeout));
+        Ok(ctx)
+    };
+
+    // The server, the MQTT bridge and the scheduler run unattended, so they reconnect after the connection was lost.
+    #[cfg(feature = "http-server")]
+    if let commandline::CliCommands::Serve { listen } = command {
+        let client =
+            SafeClient::with_reconnect(connect).with_context(|| format!("Cannot open {target}"))?;
+        return serve::serve(client, settings, output, listen);
+    }
+    #[cfg(feature = "mqtt")]
+    if let commandline::CliCommands::Mqtt(mqtt_args) = command {
+        let client =
+            SafeClient::with_reconnect(connect).with_context(|| format!("Cannot open {target}"))?;
+        return mqtt::bridge(client, settings, mqtt_args);
+    }
+
+    #[cfg(feature = "schedule")]
+    if let commandline::CliCommands::Schedule { file } = command {
+        let client =
+            SafeClient::with_reconnect(connect).with_context(|| format!("Cannot open {target}"))?;
+        return schedule::schedule(client, settings, output, file);
+    }
+
+    let client = SafeClient::new(connect().with_context(|| format!("Cannot open {target}"))?);
+    match command {
+        commandline::CliCommands::Shell => shell::shell(client, settings, output),
+        command => execute(&client, settings, command, output),
+    }
+}
+
+/// Asks the user to confirm that only one device is connected to the bus.
+fn confirm_single_device() -> Result<bool> {
+    eprintln!("Ensure ONLY ONE device is connected to the RS485 bus.");
+    Confirm::new()
+        .with_prompt("Do you want to continue?")
+        .default(false)
+        .show_default(true)
+        .interact()
+        .context("Failed to get user confirmation")
+}
+
+/// Forwards the Modbus TCP requests received on `listen` to the RTU line until the process is terminated.
+#[cfg(feature = "gateway")]
+fn gateway(
+    settings: &config::Settings,
+    listen: std::net::SocketAddr,
+    units: &[(u8, proto::Address)],
+    coils: bool,
+) -> Result<()> {
+    let config::Connection::Rtu { device, .. } = &settings.connection else {
+        anyhow::bail!(
+            "The gateway forwards to an RTU line, use the 'rtu' command or an RTU profile"
+        );
+    };
+    let runtime = tokio::runtime::Runtime::new().context("Cannot start the async runtime")?;
+    runtime.block_on(async {
+        let builder = r413d08_lib::tokio_common::serial_port_builder(device);
+        let connect = move || {
+            let serial = tokio_serial::SerialStream::open(&builder);
+            async move { Ok(tokio_modbus::client::rtu::attach(serial?)) }
+        };
+        let gateway = Gateway::with_reconnect(connect)
+            .await
+            .with_context(|| format!("Cannot open RTU device {device}"))?;
+        let gateway = units.iter().fold(
+            gateway.with_timeout(settings.timeout),
+            |gateway, (unit, slave)| gateway.map_unit(*unit, *slave),
+        );
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Cannot listen on {listen}"))?;
+        info!("Forwarding Modbus TCP requests on {listen} to {device}");
+        if coils {
+            CoilAdapter::new(gateway).serve(listener).await
+        } else {
+            gateway.serve(listener).await
+        }
+        .context("Gateway stopped unexpectedly")
+    })
+}
 
+/// Executes a command with the connected client and reports the result.
+fn execute(
+    client: &SafeClient,
+    settings: &config::Settings,
+    command: &commandline::CliCommands,
+    output: &Output,
+) -> Result<()> {
     match command {
         commandline::CliCommands::Status => {
-            let rsp = client.read_ports().context("Failed to read port status")?;
-            println!("Relay Status:");
-            for (idx, state) in rsp.iter().enumerate() {
-                println!(
-                    "  Relay {}: {}",
-                    idx,
-                    if state == &proto::PortState::Close {
-                        "OFF"
-                    } else {
-                        "ON"
-                    }
-                );
-            }
+            report_states(client, output, None)?;
         }
-        commandline::CliCommands::On { relay } => {
-            client
-                .set_port_open(*relay)
-                .with_context(|| format!("Failed to turn ON relay {}", **relay))?;
-            println!("Relay {} turned ON", **relay);
+        commandline::CliCommands::On { relays } => {
+            let relays = &relays.resolve(&settings.relay_names)?;
+            switch_relays(client, *relays, proto::Command::Open, |current| {
+                current | *relays
+            })
+            .with_context(|| format!("Failed to turn ON {}", relays_name(*relays)))?;
+            report_states(
+                client,
+                output,
+                Some(format!("{} turned ON", relays_name(*relays))),
+            )?;
         }
         commandline::CliCommands::AllOn => {
             client
                 .set_all_open()
                 .context("Failed to turn ALL relays ON")?;
-            println!("All relays turned ON");
+            report_states(client, output, Some("All relays turned ON".to_string()))?;
         }
-        commandline::CliCommands::Off { relay } => {
-            client
-                .set_port_close(*relay)
-                .with_context(|| format!("Failed to turn OFF relay {}", **relay))?;
-            println!("Relay {} turned OFF", **relay);
+        commandline::CliCommands::Off { relays } => {
+            let relays = &relays.resolve(&settings.relay_names)?;
+            switch_relays(client, *relays, proto::Command::Close, |current| {
+                current - *relays
+            })
+            .with_context(|| format!("Failed to turn OFF {}", relays_name(*relays)))?;
+            report_states(
+                client,
+                output,
+                Some(format!("{} turned OFF", relays_name(*relays))),
+            )?;
         }
         commandline::CliCommands::AllOff => {
             client
                 .set_all_close()
                 .context("Failed to turn ALL relays OFF")?;
-            println!("All relays turned OFF");
+            report_states(client, output, Some("All relays turned OFF".to_string()))?;
         }
-        commandline::CliCommands::Toggle { relay } => {
-            client
-                .set_port_toggle(*relay)
-                .with_context(|| format!("Failed to toggle relay {}", **relay))?;
-            println!("Relay {} toggled", **relay);
+        commandline::CliCommands::Toggle { relays } => {
+            let relays = &relays.resolve(&settings.relay_names)?;
+            switch_relays(client, *relays, proto::Command::Toggle, |current| {
+                (current - *relays) | (*relays - current)
+            })
+            .with_context(|| format!("Failed to toggle {}", relays_name(*relays)))?;
+            report_states(
+                client,
+                output,
+                Some(format!("{} toggled", relays_name(*relays))),
+            )?;
         }
         commandline::CliCommands::Latch { relay } => {
+            let relay = &relay.resolve_single(&settings.relay_names)?;
             client
                 .set_port_latch(*relay)
                 .with_context(|| format!("Failed to latch relay {}", **relay))?;
-            println!("Relay {} latched ON (others OFF)", **relay);
+            report_states(
+                client,
+                output,
+                Some(format!("Relay {} latched ON (others OFF)", **relay)),
+            )?;
         }
-        commandline::CliCommands::Momentary { relay } => {
-            client
-                .set_port_momentary(*relay)
-                .with_context(|| format!("Failed to activate momentary relay {}", **relay))?;
-            println!("Relay {} activated momentarily", **relay);
+        commandline::CliCommands::Momentary { relays } => {
+            let relays = &relays.resolve(&settings.relay_names)?;
+            for relay in *relays {
+                client
+                    .set_port_momentary(relay)
+                    .with_context(|| format!("Failed to activate momentary relay {}", *relay))?;
+            }
+            report_states(
+                client,
+                output,
+                Some(format!("{} activated momentarily", relays_name(*relays))),
+            )?;
         }
-        commandline::CliCommands::Delay { relay, delay } => {
-            client
-                .set_port_delay(*relay, *delay)
-                .with_context(|| format!("Failed to set delay for relay {}", **relay))?;
-            println!(
-                "Relay {} activated with {} second delay before turning OFF",
-                **relay, delay
-            );
+        commandline::CliCommands::Delay { relays, delay } => {
+            let relays = &relays.resolve(&settings.relay_names)?;
+            for relay in *relays {
+                client
+                    .set_port_delay(relay, *delay)
+                    .with_context(|| format!("Failed to set delay for relay {}", *relay))?;
+            }
+            report_states(
+                client,
+                output,
+                Some(format!(
+                    "{} activated with {} second delay before turning OFF",
+                    relays_name(*relays),
+                    delay
+                )),
+            )?;
+        }
+        commandline::CliCommands::Watch {
+            interval,
+            changes_only,
+        } => {
+            watch::watch(client, output, *interval, *changes_only)?;
+        }
+        #[cfg(feature = "sequence")]
+        commandline::CliCommands::Run { file } => {
+            let message = run::run(client, file)?;
+            report_states(client, output, Some(message))?;
+        }
+        commandline::CliCommands::Shell => {
+            anyhow::bail!("The shell is already running");
+        }
+        #[cfg(feature = "gateway")]
+        commandline::CliCommands::Gateway { .. } => {
+            anyhow::bail!("The gateway can only be started from the command line");
+        }
+        #[cfg(feature = "http-server")]
+        commandline::CliCommands::Serve { .. } => {
+            anyhow::bail!("The server can only be started from the command line");
+        }
+        #[cfg(feature = "mqtt")]
+        commandline::CliCommands::Mqtt(_) => {
+            anyhow::bail!("The MQTT bridge can only be started from the command line");
+        }
+        #[cfg(feature = "schedule")]
+        commandline::CliCommands::Schedule { .. } => {
+            anyhow::bail!("The scheduler can only be started from the command line");
         }
         commandline::CliCommands::QueryAddress => {
             // Note: Connection was already set up with broadcast address above
             let address = client
                 .read_address()
                 .context("Failed to query device address (ensure only one device is connected)")?;
-            println!("Device responded with address: {address}");
+            output.report(&Report::Address(address));
+        }
+        commandline::CliCommands::Scan {
+            from,
+            to,
+            probe_timeout,
+        } => {
+            if **from > **to {
+                anyhow::bail!("The first address {from} is greater than the last address {to}");
+            }
+            if output.is_text() {
+                println!("Scanning addresses {from} to {to}...");
+            }
+            let addresses = (**from..=**to).filter_map(|a| proto::Address::try_from(a).ok());
+            let report = client
+                .scan_with_progress(addresses, *probe_timeout, |address, outcome| {
+                    trace!("Probed address {address}: {outcome:?}");
+                    if !output.is_text() {
+                        return;
+                    }
+                    match outcome {
+                        Some(tokio_sync_scanner::ProbeOutcome::Device) => {
+                            println!("  Found device at address {address}")
+                        }
+                        Some(tokio_sync_scanner::ProbeOutcome::Exception(exception)) => println!(
+                            "  Found device at address {address} (responded with exception: {exception})"
+                        ),
+                        Some(tokio_sync_scanner::ProbeOutcome::Conflict(details)) => println!(
+                            "  Possible address conflict at address {address}: {details}"
+                        ),
+                        None => {}
+                    }
+                })
+                .context("Failed to scan the bus")?;
+            output.report(&Report::Scan(report));
         }
         commandline::CliCommands::SetAddress { address } => {
             client
                 .set_address(*address)
                 .with_context(|| format!("Failed to set new Modbus address to {address}"))?;
-            println!(
-                "Successfully sent command to set Modbus address to {address}. \
-                 Remember to use this new address for future communication."
-            );
+            output.report(&Report::AddressChanged(*address));
         }
     }
 
@@ -0,0 +1,606 @@
+//! Implements the `mqtt` command, a bridge between the relays and an MQTT broker.
+//!
+//! Below the base topic (`--topic`, default `r413d08`) the bridge uses:
+//!
+//! - `{base}/availability`: `online` or `offline` (retained). The broker
+//!   publishes `offline` as last will if the bridge disappears, the bridge
+//!   publishes it while the device does not respond.
+//! - `{base}/relay/{n}/state`: `ON` or `OFF` (retained), published on every change.
+//! - `{base}/relay/{n}/set`: Accepts `ON`, `OFF`, `TOGGLE`, `PULSE` (about one
+//!   second) or `PULSE <seconds>` (1-255).
+//!
+//! For Home Assistant, every relay is announced as switch entity on
+//! `{discovery_prefix}/switch/{node_id}/relay_{n}/config`. The announcements
+//! are repeated when Home Assistant publishes `online` on `{discovery_prefix}/status`.
+
+use crate::{commandline::MqttArgs, config::Settings};
+use anyhow::{Context, Result};
+use log::*;
+use r413d08_lib::{protocol as proto, tokio_sync_safe_client::SafeClient};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+/// The payload of the availability topic while the bridge and the device work.
+const ONLINE: &str = "online";
+/// The payload of the availability topic if the bridge or the device failed.
+const OFFLINE: &str = "offline";
+/// The time to wait before the connection to the broker is retried.
+const BROKER_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The topics used by the bridge.
+#[derive(Debug, Clone)]
+struct Topics {
+    base: String,
+    discovery_prefix: String,
+    /// The identifier of the device in Home Assistant, derived from the base topic.
+    node_id: String,
+}
+
+impl Topics {
+    fn new(base: &str, discovery_prefix: &str) -> Self {
+        let base = base.trim_end_matches('/').to_string();
+        let node_id = base
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        Self {
+            base,
+            discovery_prefix: discovery_prefix.trim_end_matches('/').to_string(),
+            node_id,
+        }
+    }
+
+    fn availability(&self) -> String {
+        format!("{}/availability", self.base)
+    }
+
+    fn state(&self, port: proto::Port) -> String {
+        format!("{}/relay/{}/state", self.base, *port)
+    }
+
+    fn command(&self, port: proto::Port) -> String {
+        format!("{}/relay/{}/set", self.base, *port)
+    }
+
+    /// The filter matching the command topics of all relays.
+    fn commands(&self) -> String {
+        format!("{}/relay/+/set", self.base)
+    }
+
+    fn discovery(&self, port: proto::Port) -> String {
+        format!(
+            "{}/switch/{}/relay_{}/config",
+            self.discovery_prefix, self.node_id, *port
+        )
+    }
+
+    /// The topic on which Home Assistant announces that it (re)started.
+    fn home_assistant_status(&self) -> String {
+        format!("{}/status", self.discovery_prefix)
+    }
+
+    /// Returns the port of a command topic.
+    fn parse_command(&self, topic: &str) -> Option<proto::Port> {
+        let index = topic
+            .strip_prefix(&self.base)?
+            .strip_prefix("/relay/")?
+            .strip_suffix("/set")?;
+        proto::Port::try_from(index.parse::<u8>().ok()?).ok()
+    }
+}
+
+/// Parses the payload of a command topic into the command for the given port.
+fn parse_command(port: proto::Port, payload: &str) -> Result<proto::Command, String> {
+    let payload = payload.trim().to_ascii_uppercase();
+    let mut words = payload.split_whitespace();
+    let command = match (words.next(), words.next(), words.next()) {
+        (Some("ON"), None, None) => proto::Command::Open(port),
+        (Some("OFF"), None, None) => proto::Command::Close(port),
+        (Some("TOGGLE"), None, None) => proto::Command::Toggle(port),
+        (Some("PULSE"), None, None) => proto::Command::Momentary(port),
+        (Some("PULSE"), Some(seconds), None) => match seconds.parse::<u8>() {
+            Ok(seconds) if seconds > 0 => proto::Command::Delay(port, seconds),
+            _ => {
+                return Err(format!(
+                    "Invalid pulse duration '{seconds}', expected 1 to 255 seconds"
+                ))
+            }
+        },
+        _ => {
+            return Err(format!(
+                "Unknown command '{payload}', expected ON, OFF, TOGGLE, PULSE or PULSE <seconds>"
+            ))
+        }
+    };
+    Ok(command)
+}
+
+/// Returns the Home Assistant discovery payload of a relay.
+fn discovery_payload(topics: &Topics, port: proto::Port, name: &str) -> serde_json::Value {
+    json!({
+        "name": name,
+        "unique_id": format!("{}_relay_{}", topics.node_id, *port),
+        "state_topic": topics.state(port),
+        "command_topic": topics.command(port),
+        "availability_topic": topics.availability(),
+        "payload_on": "ON",
+        "payload_off": "OFF",
+        "state_on": "ON",
+        "state_off": "OFF",
+        "device": {
+            "identifiers": [topics.node_id],
+            "name": format!("R413D08 {}", topics.base),
+            "model": "R413D08",
+        },
+    })
+}
+
+/// Returns the name of every relay: its name from the profile, or "Relay n".
+fn relay_names(settings: &Settings) -> Vec<(proto::Port, String)> {
+    proto::PortMask::ALL
+        .iter()
+        .map(|port| {
+            let name = settings
+                .relay_names
+                .iter()
+                .find(|(_, mask)| **mask == proto::PortMask::from(port))
+                .map_or_else(|| format!("Relay {}", *port), |(name, _)| name.clone());
+            (port, name)
+        })
+        .collect()
+}
+
+/// A message from the MQTT event loop to the worker.
+#[derive(Debug)]
+enum Message {
+    /// The bridge (re)connected to the broker.
+    Connected,
+    /// Home Assistant restarted and needs the discovery payloads again.
+    HomeAssistantOnline,
+    /// A command was received for a relay.
+    Command(proto::Command),
+}
+
+/// Talks to the device and publishes to the broker; runs on its own thread.
+struct Worker {
+    client: SafeClient,
+    mqtt: Client,
+    topics: Topics,
+    names: Vec<(proto::Port, String)>,
+    discovery: bool,
+    /// The states last published, `None` to publish all of them with the next poll.
+    published: Option<proto::PortStates>,
+    /// The availability last published.
+    available: Option<bool>,
+}
+
+impl Worker {
+    fn publish(&self, topic: String, payload: impl Into<Vec<u8>>) {
+        if let Err(err) = self.mqtt.publish(topic, QoS::AtLeastOnce, true, payload) {
+            warn!("Cannot publish to the broker: {err}");
+        }
+    }
+
+    fn publish_discovery(&self) {
+        if !self.discovery {
+            return;
+        }
+        for (port, name) in &self.names {
+            let payload = discovery_payload(&self.topics, *port, name);
+            self.publish(self.topics.discovery(*port), payload.to_string());
+        }
+    }
+
+    fn set_available(&mut self, available: bool) {
+        if self.available != Some(available) {
+            self.publish(
+                self.topics.availability(),
+                if available { ONLINE } else { OFFLINE },
+            );
+            self.available = Some(available);
+        }
+    }
+
+    /// Reads the ports and publishes the states that changed.
+    fn poll(&mut self) {
+        match self.client.read_ports() {
+            Ok(states) => {
+                self.set_available(true);
+                for (idx, state) in states.iter().enumerate() {
+                    if self
+                        .published
+                        .is_some_and(|published| published[idx] == *state)
+                    {
+                        continue;
+                    }
+                    let port = proto::Port::try_from(idx as u8).expect("valid port index");
+                    let payload = match state {
+                        proto::PortState::Open => "ON",
+                        proto::PortState::Close => "OFF",
+                    };
+                    self.publish(self.topics.state(port), payload);
+                }
+                self.published = Some(states);
+            }
+            Err(err) => {
+                if self.available != Some(false) {
+                    warn!("Cannot read the relays: {err}");
+                }
+                self.set_available(false);
+            }
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Connected => {
+                let subscriptions = [self.topics.commands(), self.topics.home_assistant_status()];
+                for topic in subscriptions {
+                    if let Err(err) = self.mqtt.subscribe(topic, QoS::AtLeastOnce) {
+                        warn!("Cannot subscribe to the broker: {err}");
+                    }
+                }
+                self.publish_discovery();
+                // The retained messages might have been lost with the broker.
+                self.available = None;
+                self.published = None;
+            }
+            Message::HomeAssistantOnline => self.publish_discovery(),
+            Message::Command(command) => {
+                info!("Executing {command:?}");
+                if let Err(err) = self.client.execute(command) {
+                    warn!("Cannot execute {command:?}: {err}");
+                }
+            }
+        }
+    }
+
+    /// Handles the messages and polls the device until the event loop ends.
+    fn run(mut self, messages: mpsc::Receiver<Message>, interval: Duration) {
+        let mut next_poll = Instant::now();
+        loop {
+            match messages.recv_timeout(next_poll.saturating_duration_since(Instant::now())) {
+                Ok(message) => {
+                    self.handle(message);
+                    // Publish the result right away instead of after the next interval.
+                    next_poll = Instant::now();
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    self.poll();
+                    next_poll = Instant::now() + interval;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+/// Returns the options to connect to the broker, with `offline` on the availability topic as last will.
+fn mqtt_options(topics: &Topics, args: &MqttArgs) -> Result<MqttOptions> {
+    let (host, port) = match args.broker.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .with_context(|| format!("Invalid broker port in '{}'", args.broker))?,
+        ),
+        None => (args.broker.as_str(), 1883),
+    };
+    let mut options = MqttOptions::new(format!("r413d08-{}", topics.node_id), host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        topics.availability(),
+        OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &args.username {
+        options.set_credentials(username, args.password.clone().unwrap_or_default());
+    }
+    Ok(options)
+}
+
+/// Runs the MQTT bridge until the process is terminated.
+pub fn bridge(client: SafeClient, settings: &Settings, args: &MqttArgs) -> Result<()> {
+    let topics = Topics::new(&args.topic, &args.discovery_prefix);
+    let options = mqtt_options(&topics, args)?;
+    let (host, port) = options.broker_address();
+    let (mqtt, mut connection) = Client::new(options, 64);
+
+    let (sender, messages) = mpsc::channel();
+    let worker = Worker {
+        client,
+        mqtt,
+        topics: topics.clone(),
+        names: relay_names(settings),
+        discovery: !args.no_discovery,
+        published: None,
+        available: None,
+    };
+    let interval = args.interval;
+    std::thread::Builder::new()
+        .name("r413d08-mqtt".to_string())
+        .spawn(move || worker.run(messages, interval))
+        .context("Cannot start the MQTT worker")?;
+
+    info!("Bridging the relays to the MQTT broker {host}:{port}");
+    let home_assistant_status = topics.home_assistant_status();
+    for event in connection.iter() {
+        let message = match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to the MQTT broker");
+                Message::Connected
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                if publish.topic == home_assistant_status {
+                    if payload != ONLINE {
+                        continue;
+                    }
+                    Message::HomeAssistantOnline
+                } else if let Some(port) = topics.parse_command(&publish.topic) {
+                    match parse_command(port, &payload) {
+                        Ok(command) => Message::Command(command),
+                        Err(err) => {
+                            warn!("Ignoring message on {}: {err}", publish.topic);
+                            continue;
+                        }
+                    }
+                } else {
+                    continue;
+                }
+            }
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("MQTT connection error: {err}");
+                std::thread::sleep(BROKER_RECONNECT_DELAY);
+                continue;
+            }
+        };
+        if sender.send(message).is_err() {
+            anyhow::bail!("The MQTT worker stopped");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(index: u8) -> proto::Port {
+        proto::Port::try_from(index).unwrap()
+    }
+
+    #[test]
+    fn command_payloads() {
+        assert_eq!(
+            parse_command(port(3), "ON"),
+            Ok(proto::Command::Open(port(3)))
+        );
+        assert_eq!(
+            parse_command(port(3), " off\n"),
+            Ok(proto::Command::Close(port(3)))
+        );
+        assert_eq!(
+            parse_command(port(3), "toggle"),
+            Ok(proto::Command::Toggle(port(3)))
+        );
+        assert_eq!(
+            parse_command(port(3), "PULSE"),
+            Ok(proto::Command::Momentary(port(3)))
+        );
+        assert_eq!(
+            parse_command(port(3), "PULSE 30"),
+            Ok(proto::Command::Delay(port(3), 30))
+        );
+        assert!(parse_command(port(3), "PULSE 0").is_err());
+        assert!(parse_command(port(3), "PULSE 300").is_err());
+        assert!(parse_command(port(3), "ON 1").is_err());
+        assert!(parse_command(port(3), "").is_err());
+    }
+
+    #[test]
+    fn topics() {
+        let topics = Topics::new("house/relays/", "homeassistant");
+        assert_eq!(topics.node_id, "house_relays");
+        assert_eq!(topics.state(port(2)), "house/relays/relay/2/state");
+        assert_eq!(
+            topics.parse_command("house/relays/relay/2/set"),
+            Some(port(2))
+        );
+        assert_eq!(topics.parse_command("house/relays/relay/8/set"), None);
+        assert_eq!(topics.parse_command("other/relay/2/set"), None);
+        assert_eq!(
+            topics.discovery(port(2)),
+            "homeassistant/switch/house_relays/relay_2/config"
+        );
+
+        let payload = discovery_payload(&topics, port(2), "pump");
+        assert_eq!(payload["name"], "pump");
+        assert_eq!(payload["unique_id"], "house_relays_relay_2");
+        assert_eq!(payload["command_topic"], "house/relays/relay/2/set");
+        assert_eq!(payload["availability_topic"], "house/relays/availability");
+    }
+
+    /// Receives published messages until one matches, the test fails if none does in time.
+    #[cfg(feature = "simulator")]
+    fn expect(messages: &mpsc::Receiver<(String, String)>, topic: &str, payload: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match messages.recv_timeout(remaining) {
+                Ok((received, content)) if received == topic && content == payload => return,
+                Ok(_) => {}
+                Err(_) => panic!("Nothing published on {topic}: {payload}"),
+            }
+        }
+    }
+
+    /// Bridges a simulated device through a local broker.
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn bridge_against_broker() {
+        use crate::{config::Connection, testing::serve_simulator};
+        use r413d08_lib::simulator::Simulator;
+
+        // Start a broker on a free local port.
+        let broker_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = format!(
+            r#"
+            id = 0
+            [router]
+            max_connections = 10
+            max_outgoing_packet_count = 200
+            max_segment_size = 104857600
+            max_segment_count = 10
+            [v4.1]
+            name = "v4-1"
+            listen = "127.0.0.1:{broker_port}"
+            next_connection_delay_ms = 1
+            [v4.1.connections]
+            connection_timeout_ms = 60000
+            max_payload_size = 20480
+            max_inflight_count = 100
+            dynamic_filters = true
+            "#
+        );
+        let mut broker = rumqttd::Broker::new(toml::from_str(&config).unwrap());
+        std::thread::spawn(move || {
+            if let Err(err) = broker.start() {
+                panic!("The broker failed: {err}");
+            }
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::net::TcpStream::connect(("127.0.0.1", broker_port)).is_err() {
+            assert!(Instant::now() < deadline, "The broker does not listen");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // An observer records everything published, including retained messages.
+        let (observer, mut connection) =
+            Client::new(MqttOptions::new("observer", "127.0.0.1", broker_port), 64);
+        let (sender, messages) = mpsc::channel();
+        std::thread::spawn({
+            let observer = observer.clone();
+            move || {
+                for event in connection.iter() {
+                    match event {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            observer.subscribe("#", QoS::AtLeastOnce).unwrap();
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            let payload = String::from_utf8_lossy(&publish.payload).to_string();
+                            let _ = sender.send((publish.topic, payload));
+                        }
+                        Ok(_) | Err(_) => {}
+                    }
+                }
+            }
+        });
+
+        let simulator = Simulator::default();
+        let socket_addr = serve_simulator(&simulator);
+        let device = || {
+            let timeout = Some(Duration::from_millis(200));
+            let ctx = tokio_modbus::client::sync::tcp::connect_with_timeout(socket_addr, timeout)
+                .unwrap();
+            SafeClient::new(ctx).with_slave(proto::Address::default())
+        };
+        let settings = Settings {
+            connection: Connection::Tcp {
+                address: socket_addr.to_string(),
+            },
+            timeout: Duration::from_secs(1),
+            relay_names: [("pump".to_string(), "1".parse().unwrap())].into(),
+        };
+        let args = MqttArgs {
+            broker: format!("127.0.0.1:{broker_port}"),
+            username: None,
+            password: None,
+            topic: "test/relays".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            no_discovery: false,
+            interval: Duration::from_millis(50),
+        };
+        std::thread::spawn({
+            let (client, args) = (device(), args.clone());
+            move || bridge(client, &settings, &args)
+        });
+
+        // The bridge announces the relays and publishes their states.
+        let discovery = discovery_payload(
+            &Topics::new("test/relays", "homeassistant"),
+            port(1),
+            "pump",
+        );
+        expect(
+            &messages,
+            "homeassistant/switch/test_relays/relay_1/config",
+            &discovery.to_string(),
+        );
+        expect(&messages, "test/relays/availability", ONLINE);
+        expect(&messages, "test/relays/relay/7/state", "OFF");
+
+        // Commands switch the relays, changes by others are published too.
+        observer
+            .publish("test/relays/relay/1/set", QoS::AtLeastOnce, false, "ON")
+            .unwrap();
+        expect(&messages, "test/relays/relay/1/state", "ON");
+        assert_eq!(
+            simulator.port_states(proto::Address::default()).unwrap()[1],
+            proto::PortState::Open
+        );
+        device().set_port_open(port(5)).unwrap();
+        expect(&messages, "test/relays/relay/5/state", "ON");
+
+        // A restarted Home Assistant gets the announcements again.
+        observer
+            .publish("homeassistant/status", QoS::AtLeastOnce, false, ONLINE)
+            .unwrap();
+        expect(
+            &messages,
+            "homeassistant/switch/test_relays/relay_1/config",
+            &discovery.to_string(),
+        );
+
+        // The bridge reports the device offline while it does not respond.
+        device()
+            .set_address(proto::Address::try_from(9).unwrap())
+            .unwrap();
+        expect(&messages, "test/relays/availability", OFFLINE);
+
+        // The broker reports a bridge offline that disappears without disconnecting.
+        let args = MqttArgs {
+            topic: "test/vanishing".to_string(),
+            ..args
+        };
+        let options =
+            mqtt_options(&Topics::new(&args.topic, &args.discovery_prefix), &args).unwrap();
+        let (vanishing, mut connection) = Client::new(options, 64);
+        vanishing
+            .publish(
+                "test/vanishing/availability",
+                QoS::AtLeastOnce,
+                true,
+                ONLINE,
+            )
+            .unwrap();
+        for event in connection.iter() {
+            if let Ok(Event::Outgoing(rumqttc::Outgoing::Publish(_))) = event {
+                break;
+            }
+        }
+        expect(&messages, "test/vanishing/availability", ONLINE);
+        drop((vanishing, connection));
+        expect(&messages, "test/vanishing/availability", OFFLINE);
+    }
+}
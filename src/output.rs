@@ -0,0 +1,461 @@
+//! Renders the results of the CLI commands in the output format selected with `--output`.
+
+use crate::commandline::OutputFormat;
+use r413d08_lib::{protocol as proto, tokio_common, tokio_sync_scanner};
+use serde_json::json;
+use std::time::SystemTime;
+
+/// The result of a command, rendered by [`Output::report`].
+#[derive(Debug)]
+pub enum Report {
+    /// The port states, read at the given time, optionally after a command described by the message.
+    States {
+        message: Option<String>,
+        at: SystemTime,
+        states: proto::PortStates,
+    },
+    /// The address a device responded with.
+    Address(proto::Address),
+    /// The new address assigned to the device.
+    AddressChanged(proto::Address),
+    /// The devices and conflicts found by a bus scan.
+    Scan(tokio_sync_scanner::ScanReport),
+    /// A relay changed its state, detected at the given time; `states` are all port states read then.
+    Change {
+        at: SystemTime,
+        change: tokio_common::PortChange,
+        states: proto::PortStates,
+    },
+    /// Reading the ports failed while watching them.
+    CommunicationError { at: SystemTime, message: String },
+}
+
+/// Renders reports and errors in the selected format.
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    format: OutputFormat,
+    /// The RS485 address of the device, or `None` for TCP connections.
+    address: Option<proto::Address>,
+}
+
+/// Returns the CLI name of a port state: ON for an open relay, OFF for a closed one.
+fn state_name(state: &proto::PortState) -> &'static str {
+    match state {
+        proto::PortState::Open => "on",
+        proto::PortState::Close => "off",
+    }
+}
+
+/// Formats the time in RFC 3339 format (UTC) with millisecond precision.
+fn timestamp(at: SystemTime) -> String {
+    humantime::format_rfc3339_millis(at).to_string()
+}
+
+impl Output {
+    pub fn new(format: OutputFormat, address: Option<proto::Address>) -> Self {
+        Self { format, address }
+    }
+
+    /// Returns the output for the device with the given address, e.g. after switching devices.
+    pub fn with_address(self, address: Option<proto::Address>) -> Self {
+        Self { address, ..self }
+    }
+
+    /// Returns `true` if progress and hints meant for humans should be printed.
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    /// Returns the device address as a JSON value, `null` for TCP connections.
+    fn address_json(&self) -> serde_json::Value {
+        self.address.map_or(serde_json::Value::Null, |a| json!(*a))
+    }
+
+    /// Returns the device address as a CSV field, empty for TCP connections.
+    fn address_csv(&self) -> String {
+        self.address.map(|a| a.to_string()).unwrap_or_default()
+    }
+
+    /// Prints the report to stdout.
+    pub fn report(&self, report: &Report) {
+        match (self.format, report) {
+            (OutputFormat::Text, _) => self.report_text(report),
+            (OutputFormat::Json, _) => println!("{}", self.report_json(report)),
+            // The machine-readable rows on stdout stay clean of errors.
+            (_, Report::CommunicationError { at, message }) => {
+                eprintln!("{} Communication error: {message}", timestamp(*at))
+            }
+            (OutputFormat::Csv, _) => print!("{}", self.report_csv(report)),
+            (OutputFormat::Mask, _) => print!("{}", self.report_mask(report)),
+        }
+    }
+
+    fn report_text(&self, report: &Report) {
+        match report {
+            Report::States {
+                message: None,
+                states,
+                ..
+            } => {
+                println!("Relay Status:");
+                for (idx, state) in states.iter().enumerate() {
+                    println!("  Relay {idx}: {}", state_name(state).to_uppercase());
+                }
+            }
+            Report::States {
+                message: Some(message),
+                states,
+                ..
+            } => {
+                println!("{message}");
+                println!("Relays ON: {:#}", proto::PortMask::from(*states));
+            }
+            Report::Address(address) => println!("Device responded with address: {address}"),
+            Report::AddressChanged(address) => println!(
+                "Successfully sent command to set Modbus address to {address}. \
+                 Remember to use this new address for future communication."
+            ),
+            Report::Scan(report) => println!(
+                "Scan finished: {} device(s) found, {} possible address conflict(s)",
+                report.devices().count(),
+                report.conflicts().count()
+            ),
+            Report::Change { at, change, .. } => println!(
+                "{} Relay {}: {} -> {}",
+                timestamp(*at),
+                *change.port,
+                state_name(&change.from).to_uppercase(),
+                state_name(&change.to).to_uppercase()
+            ),
+            Report::CommunicationError { at, message } => {
+                println!("{} Communication error: {message}", timestamp(*at))
+            }
+        }
+    }
+
+    fn report_json(&self, report: &Report) -> serde_json::Value {
+        match report {
+            Report::States {
+                message,
+                at,
+                states,
+            } => {
+                let relays: Vec<_> = states
+                    .iter()
+                    .enumerate()
+                    .map(|(relay, state)| json!({ "relay": relay, "state": state_name(state) }))
+                    .collect();
+                let mut value = json!({
+                    "address": self.address_json(),
+                    "timestamp": timestamp(*at),
+                    "mask": proto::PortMask::from(*states).to_string(),
+                    "relays": relays,
+                });
+                if let Some(message) = message {
+                    value["message"] = json!(message);
+                }
+                value
+            }
+            Report::Address(address) => json!({ "address": **address }),
+            Report::AddressChanged(address) => json!({ "new_address": **address }),
+            Report::Scan(report) => json!({
+                "devices": report.devices().map(|a| *a).collect::<Vec<_>>(),
+                "conflicts": report
+                    .conflicts()
+                    .map(|(address, details)| json!({ "address": *address, "details": details }))
+                    .collect::<Vec<_>>(),
+            }),
+            Report::Change { at, change, states } => json!({
+                "address": self.address_json(),
+                "timestamp": timestamp(*at),
+                "relay": *change.port,
+                "from": state_name(&change.from),
+                "to": state_name(&change.to),
+                "mask": proto::PortMask::from(*states).to_string(),
+            }),
+            Report::CommunicationError { at, message } => json!({
+                "address": self.address_json(),
+                "timestamp": timestamp(*at),
+                "error": message,
+            }),
+        }
+    }
+
+    /// Returns the CSV rows of the report, each terminated by a newline.
+    fn report_csv(&self, report: &Report) -> String {
+        match report {
+            Report::States { at, states, .. } => {
+                let header: Vec<_> = (0..proto::NUMBER_OF_PORTS)
+                    .map(|idx| format!("relay_{idx}"))
+                    .collect();
+                let values: Vec<_> = states.iter().map(state_name).collect();
+                format!(
+                    "timestamp,address,{}\n{},{},{}\n",
+                    header.join(","),
+                    timestamp(*at),
+                    self.address_csv(),
+                    values.join(",")
+                )
+            }
+            Report::Address(address) => format!("address\n{address}\n"),
+            Report::AddressChanged(address) => format!("new_address\n{address}\n"),
+            Report::Scan(report) => {
+                let mut csv = "address,outcome,details\n".to_string();
+                for (address, outcome) in &report.responses {
+                    let (outcome, details) = match outcome {
+                        tokio_sync_scanner::ProbeOutcome::Device => ("device", String::new()),
+                        tokio_sync_scanner::ProbeOutcome::Exception(code) => {
+                            ("exception", code.to_string())
+                        }
+                        tokio_sync_scanner::ProbeOutcome::Conflict(details) => {
+                            ("conflict", details.clone())
+                        }
+                    };
+                    csv += &format!("{address},{outcome},\"{}\"\n", details.replace('"', "\"\""));
+                }
+                csv
+            }
+            Report::Change { at, change, .. } => format!(
+                "{},{},{},{},{}\n",
+                timestamp(*at),
+                self.address_csv(),
+                *change.port,
+                state_name(&change.from),
+                state_name(&change.to)
+            ),
+            // Reported on stderr, see `Output::report`.
+            Report::CommunicationError { .. } => String::new(),
+        }
+    }
+
+    /// Prints the CSV header for a stream of [`Report::Change`] rows.
+    pub fn change_header(&self) {
+        if self.format == OutputFormat::Csv {
+            println!("timestamp,address,relay,from,to");
+        }
+    }
+
+    /// Returns the port mask or addresses of the report, each terminated by a newline.
+    fn report_mask(&self, report: &Report) -> String {
+        match report {
+            Report::States { states, .. } | Report::Change { states, .. } => {
+                format!("{}\n", proto::PortMask::from(*states))
+            }
+            Report::Address(address) | Report::AddressChanged(address) => format!("{address}\n"),
+            Report::Scan(report) => report
+                .devices()
+                .map(|address| format!("{address}\n"))
+                .collect(),
+            // Reported on stderr, see `Output::report`.
+            Report::CommunicationError { .. } => String::new(),
+        }
+    }
+
+    /// Prints the error, as a JSON object on stdout for the JSON format, else to stderr.
+    pub fn error(&self, err: &anyhow::Error) {
+        if self.format != OutputFormat::Json {
+            eprintln!("Error: {err:?}");
+            return;
+        }
+        println!("{}", self.error_json(err));
+    }
+
+    /// Returns the error as a JSON object with its kind and the failed operation.
+    pub fn error_json(&self, err: &anyhow::Error) -> serde_json::Value {
+        let kind = match err.downcast_ref::<tokio_common::Error>() {
+            Some(err) if err.is_timeout() => "timeout",
+            Some(err) if err.is_device_rejection() => "device_rejection",
+            Some(err) if err.is_transient() => "communication",
+            Some(_) => "protocol",
+            None => "other",
+        };
+        let context = err
+            .downcast_ref::<tokio_common::Error>()
+            .and_then(tokio_common::Error::context);
+        json!({
+            "error": format!("{err:#}"),
+            "kind": kind,
+            "address": self.address_json(),
+            "operation": context.map(|c| c.operation.clone()),
+            "register": context.map(|c| c.register),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r413d08_lib::{tokio_common::ErrorContext, tokio_sync_scanner::ProbeOutcome};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn address(value: u8) -> proto::Address {
+        proto::Address::try_from(value).unwrap()
+    }
+
+    fn states_report(message: Option<&str>) -> Report {
+        Report::States {
+            message: message.map(str::to_string),
+            at: UNIX_EPOCH + Duration::from_millis(1500),
+            states: proto::PortMask::from_bits(0b1000_0101).into(),
+        }
+    }
+
+    fn scan_report() -> Report {
+        Report::Scan(tokio_sync_scanner::ScanReport {
+            responses: vec![
+                (address(2), ProbeOutcome::Device),
+                (
+                    address(5),
+                    ProbeOutcome::Conflict("bad \"crc\"".to_string()),
+                ),
+                (
+                    address(7),
+                    ProbeOutcome::Exception(tokio_modbus::ExceptionCode::IllegalFunction),
+                ),
+            ],
+        })
+    }
+
+    #[test]
+    fn json_reports() {
+        let output = Output::new(OutputFormat::Json, Some(address(3)));
+        assert_eq!(
+            output.report_json(&states_report(Some("Opened relay 0"))),
+            json!({
+                "address": 3,
+                "timestamp": "1970-01-01T00:00:01.500Z",
+                "mask": "0x85",
+                "message": "Opened relay 0",
+                "relays": [
+                    { "relay": 0, "state": "on" },
+                    { "relay": 1, "state": "off" },
+                    { "relay": 2, "state": "on" },
+                    { "relay": 3, "state": "off" },
+                    { "relay": 4, "state": "off" },
+                    { "relay": 5, "state": "off" },
+                    { "relay": 6, "state": "off" },
+                    { "relay": 7, "state": "on" },
+                ],
+            })
+        );
+        let output = output.with_address(None);
+        assert_eq!(
+            output.report_json(&states_report(None))["address"],
+            json!(null)
+        );
+        assert!(output
+            .report_json(&states_report(None))
+            .get("message")
+            .is_none());
+        assert_eq!(
+            output.report_json(&Report::AddressChanged(address(9))),
+            json!({ "new_address": 9 })
+        );
+        assert_eq!(
+            output.report_json(&scan_report()),
+            json!({
+                "devices": [2, 7],
+                "conflicts": [{ "address": 5, "details": "bad \"crc\"" }],
+            })
+        );
+        let change = tokio_common::PortChange {
+            port: proto::Port::try_from(2).unwrap(),
+            from: proto::PortState::Close,
+            to: proto::PortState::Open,
+        };
+        assert_eq!(
+            output.report_json(&Report::Change {
+                at: UNIX_EPOCH,
+                change,
+                states: proto::PortMask::from_bits(0b0000_0100).into(),
+            }),
+            json!({
+                "address": null,
+                "timestamp": "1970-01-01T00:00:00.000Z",
+                "relay": 2,
+                "from": "off",
+                "to": "on",
+                "mask": "0x04",
+            })
+        );
+    }
+
+    #[test]
+    fn csv_reports() {
+        let output = Output::new(OutputFormat::Csv, Some(address(3)));
+        assert_eq!(
+            output.report_csv(&states_report(None)),
+            "timestamp,address,relay_0,relay_1,relay_2,relay_3,relay_4,relay_5,relay_6,relay_7\n\
+             1970-01-01T00:00:01.500Z,0x03,on,off,on,off,off,off,off,on\n"
+        );
+        assert_eq!(
+            output.with_address(None).report_csv(&states_report(None)),
+            "timestamp,address,relay_0,relay_1,relay_2,relay_3,relay_4,relay_5,relay_6,relay_7\n\
+             1970-01-01T00:00:01.500Z,,on,off,on,off,off,off,off,on\n"
+        );
+        assert_eq!(
+            output.report_csv(&Report::Address(address(3))),
+            "address\n0x03\n"
+        );
+        assert_eq!(
+            output.report_csv(&scan_report()),
+            "address,outcome,details\n\
+             0x02,device,\"\"\n\
+             0x05,conflict,\"bad \"\"crc\"\"\"\n\
+             0x07,exception,\"Illegal function\"\n"
+        );
+    }
+
+    #[test]
+    fn mask_reports() {
+        let output = Output::new(OutputFormat::Mask, None);
+        assert_eq!(
+            output.report_mask(&states_report(Some("ignored"))),
+            "0x85\n"
+        );
+        assert_eq!(output.report_mask(&Report::Address(address(3))), "0x03\n");
+        assert_eq!(output.report_mask(&scan_report()), "0x02\n0x07\n");
+    }
+
+    #[test]
+    fn error_json_kinds() {
+        let output = Output::new(OutputFormat::Json, Some(address(1)));
+        let timeout = tokio_common::Error::Operation {
+            context: ErrorContext {
+                operation: "read ports".to_string(),
+                register: proto::PortStates::ADDRESS,
+                port: None,
+                slave: Some(1),
+            },
+            source: Box::new(tokio_common::Error::Modbus(tokio_modbus::Error::Transport(
+                std::io::ErrorKind::TimedOut.into(),
+            ))),
+        };
+        assert_eq!(
+            output.error_json(&anyhow::Error::new(timeout)),
+            json!({
+                "error": "read ports (register 0x0001, slave 1) failed: timed out",
+                "kind": "timeout",
+                "address": 1,
+                "operation": "read ports",
+                "register": 1,
+            })
+        );
+
+        let rejection =
+            tokio_common::Error::ModbusException(tokio_modbus::ExceptionCode::IllegalDataAddress);
+        let value = output.error_json(&anyhow::Error::new(rejection));
+        assert_eq!(value["kind"], "device_rejection");
+        assert_eq!(value["operation"], json!(null));
+
+        let broken_pipe = tokio_common::Error::Modbus(tokio_modbus::Error::Transport(
+            std::io::ErrorKind::BrokenPipe.into(),
+        ));
+        let value = output.error_json(&anyhow::Error::new(broken_pipe));
+        assert_eq!(value["kind"], "communication");
+
+        let value = output.error_json(&anyhow::anyhow!("No relay named 'pump'"));
+        assert_eq!(value["kind"], "other");
+        assert_eq!(value["error"], "No relay named 'pump'");
+    }
+}
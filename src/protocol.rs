@@ -3,10 +3,11 @@
 //! the protocol specification document.
 //!
 //! This module covers:
-//! - Representing port states ([`PortState`], [`PortStates`]).
+//! - Representing port states ([`PortState`], [`PortStates`]) and sets of ports ([`PortMask`]).
 //! - Identifying specific ports ([`Port`]) and the Modbus device address ([`Address`]).
 //! - Defining Modbus register addresses and data values for reading states and controlling ports.
 //! - Encoding and decoding values from/to Modbus register format ([`Word`]).
+//! - Encoding and decoding all write commands as a typed [`Command`].
 //! - Error handling for invalid port or address values.
 //!
 //! Assumes standard Modbus function codes "Read Holding Registers" (0x03) and
@@ -169,6 +170,13 @@ impl PortStates {
     }
 }
 
+/// Creates a [`PortStates`] from an array holding the state of every port.
+impl From<[PortState; NUMBER_OF_PORTS]> for PortStates {
+    fn from(states: [PortState; NUMBER_OF_PORTS]) -> Self {
+        Self(states)
+    }
+}
+
 impl IntoIterator for PortStates {
     type Item = PortState;
     type IntoIter = std::array::IntoIter<PortState, NUMBER_OF_PORTS>;
@@ -365,6 +373,333 @@ impl std::fmt::Display for Port {
     }
 }
 
+/// A compact set of ports, stored as a bit mask with one bit per port.
+///
+/// Bit 0 (the least significant bit) represents port 0, bit 7 represents port 7.
+/// A set bit means the port is part of the set, e.g., the relay is ON when the mask
+/// was created from [`PortStates`].
+///
+/// The mask supports the usual set algebra through the `|` (union), `&`
+/// (intersection), `-` (difference) and `!` (complement) operators, can be
+/// iterated as [`Port`]s and converted to and from [`PortStates`].
+///
+/// It can be parsed from a binary (`0b1010_0001`), a hexadecimal (`0xA1`) or a
+/// comma-separated port list (`0,5,7`, ranges like `1-4` are allowed as well).
+/// With the `serde` feature it is serialized as its `u8` value.
+///
+/// # Example
+/// ```
+/// # use r413d08_lib::protocol::{Port, PortMask};
+/// let mask: PortMask = "0,5,7".parse().unwrap();
+/// assert_eq!(mask, "0xA1".parse().unwrap());
+/// assert_eq!(mask, "0b1010_0001".parse().unwrap());
+///
+/// let both = PortMask::from(Port::try_from(2).unwrap()) | Port::try_from(5).unwrap().into();
+/// assert!(!mask.is_superset(both));
+/// assert_eq!((mask & both).iter().map(|port| *port).collect::<Vec<_>>(), [5]);
+///
+/// assert_eq!(format!("{mask}"), "0xa1");
+/// assert_eq!(format!("{mask:#}"), "0,5,7");
+/// assert_eq!(format!("{mask:#b}"), "0b10100001");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct PortMask(u8);
+
+impl PortMask {
+    /// The empty mask, containing no port.
+    pub const NONE: PortMask = PortMask(0x00);
+    /// The full mask, containing all [`NUMBER_OF_PORTS`] ports.
+    pub const ALL: PortMask = PortMask(0xFF);
+
+    /// Creates a mask from its raw bits (bit 0 = port 0).
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw bits of the mask (bit 0 = port 0).
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Returns `true` if the mask contains no port.
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the number of ports in the mask.
+    pub const fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Returns `true` if the given port is part of the mask.
+    pub const fn contains(&self, port: Port) -> bool {
+        self.0 & (1 << port.0) != 0
+    }
+
+    /// Adds the given port to the mask.
+    pub fn insert(&mut self, port: Port) {
+        self.0 |= 1 << port.0;
+    }
+
+    /// Removes the given port from the mask.
+    pub fn remove(&mut self, port: Port) {
+        self.0 &= !(1 << port.0);
+    }
+
+    /// Returns the ports contained in either mask.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns the ports contained in both masks.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Returns the ports contained in this mask but not in `other`.
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Returns the ports not contained in this mask.
+    pub const fn complement(self) -> Self {
+        Self(!self.0)
+    }
+
+    /// Returns `true` if all ports of `other` are also part of this mask.
+    pub const fn is_superset(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if all ports of this mask are also part of `other`.
+    pub const fn is_subset(&self, other: Self) -> bool {
+        other.is_superset(*self)
+    }
+
+    /// Returns an iterator over the ports contained in the mask, in ascending order.
+    pub fn iter(&self) -> PortMaskIter {
+        PortMaskIter(self.0)
+    }
+}
+
+/// An iterator over the ports of a [`PortMask`] in ascending order.
+///
+/// Created by [`PortMask::iter`].
+#[derive(Debug, Clone)]
+pub struct PortMaskIter(u8);
+
+impl Iterator for PortMaskIter {
+    type Item = Port;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0 == 0 {
+            return None;
+        }
+        let index = self.0.trailing_zeros() as u8;
+        // Clear the lowest set bit.
+        self.0 &= self.0 - 1;
+        Some(Port(index))
+    }
+}
+
+impl From<Port> for PortMask {
+    fn from(port: Port) -> Self {
+        Self(1 << port.0)
+    }
+}
+
+impl From<u8> for PortMask {
+    fn from(bits: u8) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<PortMask> for u8 {
+    fn from(mask: PortMask) -> Self {
+        mask.0
+    }
+}
+
+/// Creates a mask containing every port in the [`PortState::Open`] state.
+impl From<PortStates> for PortMask {
+    fn from(states: PortStates) -> Self {
+        states
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| **state == PortState::Open)
+            .fold(Self::NONE, |mask, (index, _)| Self(mask.0 | 1 << index))
+    }
+}
+
+/// Creates port states where every port of the mask is [`PortState::Open`] and all others are [`PortState::Close`].
+impl From<PortMask> for PortStates {
+    fn from(mask: PortMask) -> Self {
+        let mut states = [PortState::Close; NUMBER_OF_PORTS];
+        for port in mask.iter() {
+            states[port.0 as usize] = PortState::Open;
+        }
+        Self(states)
+    }
+}
+
+impl FromIterator<Port> for PortMask {
+    fn from_iter<T: IntoIterator<Item = Port>>(iter: T) -> Self {
+        iter.into_iter()
+            .fold(Self::NONE, |mask, port| mask | port.into())
+    }
+}
+
+impl IntoIterator for PortMask {
+    type Item = Port;
+    type IntoIter = PortMaskIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl std::ops::BitOr for PortMask {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        self.union(rhs)
+    }
+}
+
+impl std::ops::BitOrAssign for PortMask {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = self.union(rhs);
+    }
+}
+
+impl std::ops::BitAnd for PortMask {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.intersection(rhs)
+    }
+}
+
+impl std::ops::BitAndAssign for PortMask {
+    fn bitand_assign(&mut self, rhs: Self) {
+        *self = self.intersection(rhs);
+    }
+}
+
+impl std::ops::Sub for PortMask {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.difference(rhs)
+    }
+}
+
+impl std::ops::SubAssign for PortMask {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = self.difference(rhs);
+    }
+}
+
+impl std::ops::Not for PortMask {
+    type Output = Self;
+    fn not(self) -> Self::Output {
+        self.complement()
+    }
+}
+
+/// Error indicating that a string could not be parsed as a [`PortMask`].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error(
+    "Invalid port mask '{0}': expected a binary (0b...) or hexadecimal (0x...) mask, a comma-separated list of ports from {min} to {max} or 'none'",
+    min = Port::MIN,
+    max = Port::MAX
+)]
+pub struct ErrorInvalidPortMask(
+    /// The string that could not be parsed.
+    pub String,
+);
+
+impl std::str::FromStr for PortMask {
+    type Err = ErrorInvalidPortMask;
+
+    /// Parses a mask from a binary (`0b1010_0001`), a hexadecimal (`0xA1`) or a
+    /// comma-separated port list (`0,5,7` or `1-4,7`), or `none` for the empty mask.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ErrorInvalidPortMask(s.to_string());
+        let trimmed = s.trim();
+        let radix_digits = [("0b", 2), ("0B", 2), ("0x", 16), ("0X", 16)]
+            .into_iter()
+            .find_map(|(prefix, radix)| trimmed.strip_prefix(prefix).map(|d| (radix, d)));
+        if let Some((radix, digits)) = radix_digits {
+            let digits = digits.replace('_', "");
+            return u8::from_str_radix(&digits, radix)
+                .map(Self)
+                .map_err(|_| error());
+        }
+        if trimmed.is_empty() {
+            return Err(error());
+        }
+        if trimmed.eq_ignore_ascii_case("none") {
+            return Ok(Self::NONE);
+        }
+        let parse_port = |s: &str| {
+            s.trim()
+                .parse::<u8>()
+                .ok()
+                .and_then(|index| Port::try_from(index).ok())
+                .ok_or_else(error)
+        };
+        let mut mask = Self::NONE;
+        for item in trimmed.split(',') {
+            match item.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse_port(first)?, parse_port(last)?);
+                    if first.0 > last.0 {
+                        return Err(error());
+                    }
+                    mask |= (first.0..=last.0).map(Port).collect();
+                }
+                None => mask.insert(parse_port(item)?),
+            }
+        }
+        Ok(mask)
+    }
+}
+
+/// Formats the mask in hexadecimal (e.g., "0xa1").
+///
+/// The alternate form (`{:#}`) formats the mask as comma-separated port list (e.g., "0,5,7"),
+/// or as "none" if the mask is empty.
+impl std::fmt::Display for PortMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() && self.is_empty() {
+            write!(f, "none")
+        } else if f.alternate() {
+            let ports: Vec<String> = self.iter().map(|port| port.to_string()).collect();
+            write!(f, "{}", ports.join(","))
+        } else {
+            write!(f, "{:#04x}", self.0)
+        }
+    }
+}
+
+impl std::fmt::Binary for PortMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Binary::fmt(&self.0, f)
+    }
+}
+
+impl std::fmt::LowerHex for PortMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl std::fmt::UpperHex for PortMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+
 /// A zero-sized type providing constants for controlling all ports simultaneously.
 pub struct PortsAll;
 
@@ -551,6 +886,243 @@ impl std::fmt::Display for Address {
     }
 }
 
+/// A typed representation of every command that can be written to the device.
+///
+/// Each command is sent with Modbus function 0x06 (Write Single Register) and
+/// corresponds to exactly one (register address, register value) pair. Use
+/// [`Command::encode`] to obtain that pair and [`Command::decode`] to turn a pair
+/// observed on the bus (e.g., by a sniffer or a simulator) back into a command.
+///
+/// # Example
+/// ```
+/// # use r413d08_lib::protocol::{Command, Port};
+/// let command = Command::Delay(Port::try_from(2).unwrap(), 10);
+/// let (register, value) = command.encode();
+/// assert_eq!((register, value), (0x0003, 0x060A));
+/// assert_eq!(Command::decode(register, value), Ok(command));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Command {
+    /// Opens the port (turn relay ON), see [`Port::REG_DATA_SET_PORT_OPEN`].
+    Open(Port),
+    /// Closes the port (turn relay OFF), see [`Port::REG_DATA_SET_PORT_CLOSE`].
+    Close(Port),
+    /// Toggles the port state, see [`Port::REG_DATA_SET_PORT_TOGGLE`].
+    Toggle(Port),
+    /// Opens the port and closes all others, see [`Port::REG_DATA_SET_PORT_LATCH`].
+    Latch(Port),
+    /// Opens the port for ~1 second, see [`Port::REG_DATA_SET_PORT_MOMENTARY`].
+    Momentary(Port),
+    /// Opens the port and closes it after the given seconds, see [`Port::REG_DATA_SET_PORT_DELAY`].
+    Delay(Port, u8),
+    /// Opens all ports, see [`PortsAll::REG_DATA_SET_ALL_OPEN`].
+    AllOpen,
+    /// Closes all ports, see [`PortsAll::REG_DATA_SET_ALL_CLOSE`].
+    AllClose,
+    /// Assigns a new Modbus address to the device, see [`Address::ADDRESS`].
+    SetAddress(Address),
+}
+
+impl Command {
+    /// Encodes the command into the register address and the register value
+    /// to be written with Modbus function 0x06 (Write Single Register).
+    ///
+    /// # Returns
+    ///
+    /// A `(register, value)` tuple.
+    pub fn encode(&self) -> (u16, Word) {
+        match self {
+            Self::Open(port) => (
+                port.address_for_write_register(),
+                Port::REG_DATA_SET_PORT_OPEN,
+            ),
+            Self::Close(port) => (
+                port.address_for_write_register(),
+                Port::REG_DATA_SET_PORT_CLOSE,
+            ),
+            Self::Toggle(port) => (
+                port.address_for_write_register(),
+                Port::REG_DATA_SET_PORT_TOGGLE,
+            ),
+            Self::Latch(port) => (
+                port.address_for_write_register(),
+                Port::REG_DATA_SET_PORT_LATCH,
+            ),
+            Self::Momentary(port) => (
+                port.address_for_write_register(),
+                Port::REG_DATA_SET_PORT_MOMENTARY,
+            ),
+            Self::Delay(port, delay) => (
+                port.address_for_write_register(),
+                Port::encode_delay_for_write_register(*delay),
+            ),
+            Self::AllOpen => (PortsAll::ADDRESS, PortsAll::REG_DATA_SET_ALL_OPEN),
+            Self::AllClose => (PortsAll::ADDRESS, PortsAll::REG_DATA_SET_ALL_CLOSE),
+            Self::SetAddress(address) => (Address::ADDRESS, address.encode_for_write_register()),
+        }
+    }
+
+    /// Returns the port the command is addressed to, or `None` for commands
+    /// affecting all ports or the device itself.
+    pub fn port(&self) -> Option<Port> {
+        match self {
+            Self::Open(port)
+            | Self::Close(port)
+            | Self::Toggle(port)
+            | Self::Latch(port)
+            | Self::Momentary(port)
+            | Self::Delay(port, _) => Some(*port),
+            Self::AllOpen | Self::AllClose | Self::SetAddress(_) => None,
+        }
+    }
+
+    /// Decodes a command from the register address and the register value of a
+    /// Modbus function 0x06 (Write Single Register) request.
+    ///
+    /// # Arguments
+    ///
+    /// * `register`: The register address the value is written to.
+    /// * `value`: The [`Word`] written to the register.
+    ///
+    /// # Errors
+    ///
+    /// * [`Error::InvalidValueCode`]: If the register address is not writable or
+    ///   the value is not a known command code for this register.
+    /// * [`Error::InvalidData`]: If the value written to [`Address::ADDRESS`] is
+    ///   not a valid device address.
+    pub fn decode(register: u16, value: Word) -> Result<Self, Error> {
+        match register {
+            PortsAll::ADDRESS => match value {
+                PortsAll::REG_DATA_SET_ALL_OPEN => Ok(Self::AllOpen),
+                PortsAll::REG_DATA_SET_ALL_CLOSE => Ok(Self::AllClose),
+                _ => Err(Error::InvalidValueCode {
+                    entity: "all ports command".to_string(),
+                    code: value,
+                }),
+            },
+            Address::ADDRESS => Ok(Self::SetAddress(Address::decode_from_holding_registers(
+                &[value],
+            )?)),
+            _ => {
+                let port = register
+                    .checked_sub(1)
+                    .and_then(|index| u8::try_from(index).ok())
+                    .and_then(|index| Port::try_from(index).ok())
+                    .ok_or_else(|| Error::InvalidValueCode {
+                        entity: "register address".to_string(),
+                        code: register,
+                    })?;
+                match value {
+                    Port::REG_DATA_SET_PORT_OPEN => Ok(Self::Open(port)),
+                    Port::REG_DATA_SET_PORT_CLOSE => Ok(Self::Close(port)),
+                    Port::REG_DATA_SET_PORT_TOGGLE => Ok(Self::Toggle(port)),
+                    Port::REG_DATA_SET_PORT_LATCH => Ok(Self::Latch(port)),
+                    Port::REG_DATA_SET_PORT_MOMENTARY => Ok(Self::Momentary(port)),
+                    _ if value & 0xFF00 == Port::REG_DATA_SET_PORT_DELAY => {
+                        Ok(Self::Delay(port, value as u8))
+                    }
+                    _ => Err(Error::InvalidValueCode {
+                        entity: "port command".to_string(),
+                        code: value,
+                    }),
+                }
+            }
+        }
+    }
+}
+
+impl Command {
+    /// Computes the shortest command sequence that switches the ports from the
+    /// `current` states to the `target` states.
+    ///
+    /// Besides individual [`Command::Open`] and [`Command::Close`] writes, the
+    /// sequence may start with [`Command::AllOpen`], [`Command::AllClose`] or
+    /// [`Command::Latch`] to switch several ports with a single write. Such a
+    /// shortcut is only used if no port that already has its target state would
+    /// be switched in between, so no relay flickers while the sequence is executed.
+    /// If several sequences have the same length, individual writes are preferred.
+    ///
+    /// # Arguments
+    ///
+    /// * `current`: The ports currently open.
+    /// * `target`: The ports that should be open afterwards; all others are closed.
+    ///
+    /// # Returns
+    ///
+    /// The commands to execute in order. The sequence is empty if `current` equals `target`.
+    ///
+    /// # Example
+    /// ```
+    /// # use r413d08_lib::protocol::{Command, Port, PortMask};
+    /// let plan = Command::plan_transition(PortMask::from_bits(0b1111_0000), PortMask::from_bits(0b0000_0101));
+    /// let port = |index| Port::try_from(index).unwrap();
+    /// assert_eq!(plan, [Command::Latch(port(0)), Command::Open(port(2))]);
+    /// ```
+    pub fn plan_transition(current: PortMask, target: PortMask) -> Vec<Self> {
+        let open = |mask: PortMask| mask.iter().map(Self::Open);
+        let close = |mask: PortMask| mask.iter().map(Self::Close);
+
+        // Individual writes for every port that changes its state.
+        let mut best: Vec<Self> = close(current - target)
+            .chain(open(target - current))
+            .collect();
+
+        let mut candidates: Vec<Vec<Self>> = Vec::new();
+        // All ports switched on, then the unwanted ones off again. Only if no port stays off.
+        if (current | target) == PortMask::ALL {
+            candidates.push(
+                std::iter::once(Self::AllOpen)
+                    .chain(close(!target))
+                    .collect(),
+            );
+        }
+        // All ports switched off, then the wanted ones on again. Only if no port stays on.
+        if (current & target).is_empty() {
+            candidates.push(
+                std::iter::once(Self::AllClose)
+                    .chain(open(target))
+                    .collect(),
+            );
+        }
+        // One port latched on, then the remaining ones on. Only if at most this port stays on.
+        let staying = current & target;
+        if let Some(latched) = staying.iter().next().or_else(|| target.iter().next()) {
+            if staying.is_subset(latched.into()) {
+                candidates.push(
+                    std::iter::once(Self::Latch(latched))
+                        .chain(open(target - latched.into()))
+                        .collect(),
+                );
+            }
+        }
+
+        for candidate in candidates {
+            if candidate.len() < best.len() {
+                best = candidate;
+            }
+        }
+        best
+    }
+}
+
+/// Provides a human-readable description of the command (e.g., "delay port 2 for 10s").
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Open(port) => write!(f, "open port {port}"),
+            Self::Close(port) => write!(f, "close port {port}"),
+            Self::Toggle(port) => write!(f, "toggle port {port}"),
+            Self::Latch(port) => write!(f, "latch port {port}"),
+            Self::Momentary(port) => write!(f, "momentary port {port}"),
+            Self::Delay(port, delay) => write!(f, "delay port {port} for {delay}s"),
+            Self::AllOpen => write!(f, "open all ports"),
+            Self::AllClose => write!(f, "close all ports"),
+            Self::SetAddress(address) => write!(f, "set address {address}"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -760,4 +1332,197 @@ mod tests {
             "open, close, open, close, close, close, close, close"
         );
     }
+
+    // --- Command Tests ---
+    #[test]
+    fn command_encode_decode_round_trip() {
+        let port = Port::try_from(4).unwrap();
+        let commands = [
+            (Command::Open(port), (0x0005, 0x0100)),
+            (Command::Close(port), (0x0005, 0x0200)),
+            (Command::Toggle(port), (0x0005, 0x0300)),
+            (Command::Latch(port), (0x0005, 0x0400)),
+            (Command::Momentary(port), (0x0005, 0x0500)),
+            (Command::Delay(port, 0), (0x0005, 0x0600)),
+            (Command::Delay(port, 255), (0x0005, 0x06FF)),
+            (Command::AllOpen, (0x0000, 0x0700)),
+            (Command::AllClose, (0x0000, 0x0800)),
+            (Command::SetAddress(Address(0x20)), (0x00FF, 0x0020)),
+        ];
+        for (command, encoded) in commands {
+            assert_eq!(command.encode(), encoded, "Encoding of {command}");
+            assert_eq!(Command::decode(encoded.0, encoded.1), Ok(command));
+            assert_eq!(
+                command.port(),
+                (encoded.0 == 0x0005).then_some(port),
+                "Port of {command}"
+            );
+        }
+    }
+
+    #[test]
+    fn command_decode_errors() {
+        assert_matches!(
+            Command::decode(0x0009, Port::REG_DATA_SET_PORT_OPEN),
+            Err(Error::InvalidValueCode { code: 0x0009, .. })
+        );
+        assert_matches!(
+            Command::decode(0x0100, Port::REG_DATA_SET_PORT_OPEN),
+            Err(Error::InvalidValueCode { code: 0x0100, .. })
+        );
+        assert_matches!(
+            Command::decode(0x0001, 0x0900),
+            Err(Error::InvalidValueCode { code: 0x0900, .. })
+        );
+        assert_matches!(
+            Command::decode(0x0001, 0x0101),
+            Err(Error::InvalidValueCode { code: 0x0101, .. })
+        );
+        assert_matches!(
+            Command::decode(PortsAll::ADDRESS, Port::REG_DATA_SET_PORT_OPEN),
+            Err(Error::InvalidValueCode { code: 0x0100, .. })
+        );
+        assert_matches!(
+            Command::decode(Address::ADDRESS, 0x0000),
+            Err(Error::InvalidData { .. })
+        );
+    }
+
+    // --- PortMask Tests ---
+    #[test]
+    fn port_mask_parse() {
+        assert_eq!("0b1010_0001".parse(), Ok(PortMask(0xA1)));
+        assert_eq!("0xA1".parse(), Ok(PortMask(0xA1)));
+        assert_eq!("0xa1".parse(), Ok(PortMask(0xA1)));
+        assert_eq!("0,5,7".parse(), Ok(PortMask(0xA1)));
+        assert_eq!(" 7, 0 ,5 ".parse(), Ok(PortMask(0xA1)));
+        assert_eq!("1-4,7".parse(), Ok(PortMask(0b1001_1110)));
+        assert_eq!("3".parse(), Ok(PortMask(0b0000_1000)));
+        assert_eq!("0x00".parse(), Ok(PortMask::NONE));
+        assert_eq!("none".parse(), Ok(PortMask::NONE));
+        assert_eq!("None".parse(), Ok(PortMask::NONE));
+        for invalid in ["", "8", "0,8", "0x100", "0b1_0000_0000", "4-1", "a", "0,,1"] {
+            assert_eq!(
+                invalid.parse::<PortMask>(),
+                Err(ErrorInvalidPortMask(invalid.to_string())),
+                "Should reject '{invalid}'"
+            );
+        }
+    }
+
+    #[test]
+    fn port_mask_display() {
+        let mask = PortMask(0xA1);
+        assert_eq!(mask.to_string(), "0xa1");
+        assert_eq!(format!("{mask:#}"), "0,5,7");
+        assert_eq!(format!("{mask:#010b}"), "0b10100001");
+        assert_eq!(format!("{mask:#X}"), "0xA1");
+        assert_eq!(format!("{:#}", PortMask::NONE), "none");
+        // Every format can be parsed back
+        for mask in [mask, PortMask::NONE, PortMask::ALL] {
+            for formatted in [format!("{mask}"), format!("{mask:#}"), format!("{mask:#b}")] {
+                assert_eq!(formatted.parse(), Ok(mask));
+            }
+        }
+    }
+
+    #[test]
+    fn port_mask_set_algebra() {
+        let a = PortMask(0b0000_1111);
+        let b = PortMask(0b0011_1100);
+        assert_eq!(a | b, PortMask(0b0011_1111));
+        assert_eq!(a & b, PortMask(0b0000_1100));
+        assert_eq!(a - b, PortMask(0b0000_0011));
+        assert_eq!(!a, PortMask(0b1111_0000));
+        assert!(a.is_superset(a & b));
+        assert!((a & b).is_subset(b));
+        assert!(!a.is_subset(b));
+        assert_eq!(a.len(), 4);
+        assert!(PortMask::NONE.is_empty());
+
+        let mut mask = PortMask::NONE;
+        mask.insert(Port(6));
+        mask.insert(Port(1));
+        assert!(mask.contains(Port(6)));
+        assert!(!mask.contains(Port(0)));
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [Port(1), Port(6)]);
+        mask.remove(Port(6));
+        assert_eq!(mask, PortMask::from(Port(1)));
+        assert_eq!(PortMask::ALL.into_iter().count(), NUMBER_OF_PORTS);
+    }
+
+    #[test]
+    fn port_mask_port_states_conversion() {
+        let states = PortStates::decode_from_holding_registers(&[1, 0, 0, 0, 0, 1, 0, 1]);
+        let mask = PortMask::from(states);
+        assert_eq!(mask, PortMask(0xA1));
+        assert_eq!(PortStates::from(mask), states);
+        assert_eq!(
+            PortStates::from(PortMask::NONE),
+            PortStates([PortState::Close; NUMBER_OF_PORTS])
+        );
+    }
+
+    #[test]
+    fn command_plan_transition() {
+        let mask = PortMask::from_bits;
+        let port = |index| Port::try_from(index).unwrap();
+        // Applies a plan to the current mask like the device would.
+        let simulate = |current: PortMask, plan: &[Command]| {
+            plan.iter().fold(current, |mask, command| match command {
+                Command::Open(p) => mask | (*p).into(),
+                Command::Close(p) => mask - (*p).into(),
+                Command::Latch(p) => (*p).into(),
+                Command::AllOpen => PortMask::ALL,
+                Command::AllClose => PortMask::NONE,
+                _ => panic!("Unexpected command {command}"),
+            })
+        };
+
+        assert!(Command::plan_transition(mask(0xA5), mask(0xA5)).is_empty());
+        assert_eq!(
+            Command::plan_transition(mask(0x00), mask(0xFF)),
+            [Command::AllOpen]
+        );
+        assert_eq!(
+            Command::plan_transition(mask(0xFF), mask(0x00)),
+            [Command::AllClose]
+        );
+        assert_eq!(
+            Command::plan_transition(mask(0xFF), mask(0x10)),
+            [Command::Latch(port(4))]
+        );
+        assert_eq!(
+            Command::plan_transition(mask(0x01), mask(0x03)),
+            [Command::Open(port(1))]
+        );
+        assert_eq!(
+            Command::plan_transition(mask(0x0F), mask(0xF0)),
+            [
+                Command::Latch(port(4)),
+                Command::Open(port(5)),
+                Command::Open(port(6)),
+                Command::Open(port(7)),
+            ]
+        );
+        assert_eq!(
+            Command::plan_transition(mask(0x0F), mask(0xFE)),
+            [Command::AllOpen, Command::Close(port(0))]
+        );
+        // Port 0 stays on, so neither all-close nor latching another port is allowed
+        assert_eq!(
+            Command::plan_transition(mask(0x7F), mask(0x81)),
+            [Command::Latch(port(0)), Command::Open(port(7)),]
+        );
+
+        // Exhaustive check: every plan reaches the target and is never longer than individual writes
+        for current in 0..=u8::MAX {
+            for target in 0..=u8::MAX {
+                let (current, target) = (mask(current), mask(target));
+                let plan = Command::plan_transition(current, target);
+                assert_eq!(simulate(current, &plan), target);
+                assert!(plan.len() <= (current.bits() ^ target.bits()).count_ones() as usize);
+            }
+        }
+    }
 }
@@ -0,0 +1,35 @@
+//! Implements the `run` command, which runs a sequence file step by step.
+//!
+//! Every step is logged before it runs. Ctrl-C aborts the sequence, closing
+//! the port of a running pulse.
+
+use crate::interrupt;
+use anyhow::{Context, Result};
+use log::*;
+use r413d08_lib::{
+    sequence::{Control, Outcome, Sequence},
+    tokio_sync_safe_client::SafeClient,
+};
+use std::path::Path;
+
+/// Runs the sequence of the given file and returns a message describing how it ended.
+pub fn run(client: &SafeClient, path: &Path) -> Result<String> {
+    let sequence = Sequence::load(path)?;
+    let name = sequence.name.clone().unwrap_or_default();
+    let control = Control::new();
+    let abort = control.clone();
+    info!("Running sequence '{name}'");
+    let outcome = interrupt::handle(
+        move || abort.abort(),
+        || {
+            sequence.run(&mut client.clone(), &control, |progress| {
+                info!("{progress}")
+            })
+        },
+    );
+    let ended = match outcome.with_context(|| format!("Sequence '{name}' failed"))? {
+        Outcome::Completed => "completed",
+        Outcome::Aborted => "aborted",
+    };
+    Ok(format!("Sequence '{name}' {ended}"))
+}
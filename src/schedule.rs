@@ -0,0 +1,699 @@
+//! Implements the `schedule` command, a daemon firing relay actions at scheduled times.
+//!
+//! Each entry of the schedule file switches relays either at a time of day
+//! (`at`, optionally limited to `days`) or on a cron expression (`cron`, with
+//! optional seconds). Times are evaluated in the time zone of the entry, the
+//! file or the host, in that order. Example:
+//!
+//! ```toml
+//! timezone = "Europe/Berlin"
+//! catch_up = "10m"
+//!
+//! [[entries]]
+//! name = "morning irrigation"
+//! relays = "pump"
+//! action = "on"
+//! at = "06:00"
+//! for = "15m"
+//!
+//! [[entries]]
+//! relays = "all"
+//! action = "off"
+//! at = "23:00"
+//! days = "weekdays"
+//!
+//! [[entries]]
+//! relays = 3
+//! action = "momentary"
+//! cron = "*/15 8-18 * * MON-FRI"
+//! ```
+//!
+//! The actions are `on`, `off`, `toggle`, `latch` and `momentary`. An `on`
+//! action with `for` arms the delay timer of the device, re-armed for runs
+//! beyond 255 seconds, so the hardware turns the relays off even if this
+//! process dies. An entry without a next time, e.g. a cron expression that
+//! never matches again, is logged and disabled while the other entries keep
+//! running.
+//!
+//! The daemon compares the system clock against a monotonic clock. If the
+//! system clock jumps, e.g. after a suspend or an NTP correction, the schedule
+//! is re-synchronized. An entry missed by a forward jump runs once if it was
+//! due within `catch_up` (5 minutes by default) before the new time, `off`
+//! entries always run so no relay is left on, other missed entries are logged
+//! and skipped. A backward jump never fires an entry twice.
+
+use crate::{
+    commandline::{CliCommands, RelaySelector},
+    config::Settings,
+    output::Output,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use croner::Cron;
+use log::*;
+use r413d08_lib::tokio_sync_safe_client::{SafeClient, TimedPort};
+use serde::Deserialize;
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// The longest time the daemon sleeps before it checks the clock again.
+const MAX_SLEEP: Duration = Duration::from_secs(1);
+
+/// The difference between system and monotonic clock that counts as a clock jump.
+const CLOCK_JUMP_TOLERANCE: TimeDelta = TimeDelta::seconds(2);
+
+/// The catch-up window if the schedule file sets no `catch_up`, see [`Entry::resync`].
+const DEFAULT_CATCH_UP: TimeDelta = TimeDelta::minutes(5);
+
+/// The content of the schedule file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleFile {
+    /// The IANA time zone of all entries (e.g., "Europe/Berlin"), the host's by default.
+    timezone: Option<String>,
+    /// How long after its time an entry missed by a forward clock jump still runs (e.g., "10m").
+    catch_up: Option<String>,
+    #[serde(default)]
+    entries: Vec<EntryFile>,
+}
+
+/// An entry of the schedule file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EntryFile {
+    name: Option<String>,
+    relays: Relays,
+    action: Action,
+    /// The time of day, "HH:MM" or "HH:MM:SS".
+    at: Option<String>,
+    /// The days for `at`, all days by default.
+    days: Option<Days>,
+    /// A cron expression with 5 or 6 (with seconds) fields.
+    cron: Option<String>,
+    /// The time to keep the relays on (e.g., "15m"), only for `on`.
+    #[serde(rename = "for")]
+    duration: Option<String>,
+    timezone: Option<String>,
+}
+
+/// The relays of an entry: a relay number or a selector like "pump" or "4-5".
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Relays {
+    Number(u8),
+    Selector(String),
+}
+
+/// The days of an `at` entry: "daily", "weekdays", "weekends", a cron weekday
+/// field (e.g., "MON,THU" or "1-5") or a list of weekdays.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Days {
+    Named(String),
+    List(Vec<String>),
+}
+
+impl Days {
+    /// Returns the day-of-week field of a cron expression.
+    fn cron_field(&self) -> String {
+        match self {
+            Days::Named(days) => match days.to_ascii_lowercase().as_str() {
+                "daily" => String::from("*"),
+                "weekdays" => String::from("MON-FRI"),
+                "weekends" => String::from("SAT,SUN"),
+                _ => days.clone(),
+            },
+            Days::List(days) => days.join(","),
+        }
+    }
+}
+
+/// What an entry does with its relays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    On,
+    Off,
+    Toggle,
+    Latch,
+    Momentary,
+}
+
+/// The time zone the times of an entry are evaluated in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Zone {
+    Local,
+    Named(chrono_tz::Tz),
+}
+
+impl Zone {
+    fn parse(name: Option<&str>) -> Result<Self> {
+        match name {
+            None => Ok(Zone::Local),
+            Some(name) => name
+                .parse()
+                .map(Zone::Named)
+                .map_err(|err| anyhow!("Unknown time zone '{name}': {err}")),
+        }
+    }
+
+    /// Returns the first time after `after` matching the cron expression.
+    fn next_after(&self, cron: &Cron, after: DateTime<Utc>) -> Result<DateTime<Utc>> {
+        let next = match self {
+            Zone::Local => cron
+                .find_next_occurrence(&after.with_timezone(&chrono::Local), false)
+                .map(|next| next.with_timezone(&Utc)),
+            Zone::Named(tz) => cron
+                .find_next_occurrence(&after.with_timezone(tz), false)
+                .map(|next| next.with_timezone(&Utc)),
+        };
+        next.map_err(|err| anyhow!("No next time for the schedule: {err}"))
+    }
+
+    /// Returns the last time up to and including `until` matching the cron expression.
+    fn last_until(&self, cron: &Cron, until: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::Local => cron
+                .find_previous_occurrence(&until.with_timezone(&chrono::Local), true)
+                .map(|last| last.with_timezone(&Utc)),
+            Zone::Named(tz) => cron
+                .find_previous_occurrence(&until.with_timezone(tz), true)
+                .map(|last| last.with_timezone(&Utc)),
+        }
+        .ok()
+    }
+
+    /// Formats a time in this time zone for log messages.
+    fn format(&self, time: DateTime<Utc>) -> String {
+        const FORMAT: &str = "%a %Y-%m-%d %H:%M:%S %Z";
+        match self {
+            Zone::Local => time
+                .with_timezone(&chrono::Local)
+                .format(FORMAT)
+                .to_string(),
+            Zone::Named(tz) => time.with_timezone(tz).format(FORMAT).to_string(),
+        }
+    }
+}
+
+/// A validated entry of the schedule.
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    relays: RelaySelector,
+    action: Action,
+    duration: Option<Duration>,
+    /// The cron expression as written, or as derived from `at` and `days`.
+    expression: String,
+    cron: Cron,
+    zone: Zone,
+    /// The next time the entry fires, `None` once it has none.
+    next: Option<DateTime<Utc>>,
+    /// The time the entry last fired, it never fires again before.
+    last_fired: Option<DateTime<Utc>>,
+}
+
+/// Returns the cron expression for a time of day on the given days.
+fn time_of_day_cron(at: &str, days: Option<&Days>) -> Result<String> {
+    let time = chrono::NaiveTime::parse_from_str(at, "%H:%M:%S")
+        .or_else(|_| chrono::NaiveTime::parse_from_str(at, "%H:%M"))
+        .map_err(|_| anyhow!("Invalid time of day '{at}', expected HH:MM or HH:MM:SS"))?;
+    use chrono::Timelike;
+    Ok(format!(
+        "{} {} {} * * {}",
+        time.second(),
+        time.minute(),
+        time.hour(),
+        days.map_or_else(|| String::from("*"), Days::cron_field)
+    ))
+}
+
+impl Entry {
+    fn new(entry: EntryFile, zone: Zone, settings: &Settings) -> Result<Self> {
+        let relays: RelaySelector = match &entry.relays {
+            Relays::Number(number) => number.to_string(),
+            Relays::Selector(selector) => selector.clone(),
+        }
+        .parse()?;
+        if entry.action == Action::Latch {
+            relays.resolve_single(&settings.relay_names)?;
+        } else {
+            relays.resolve(&settings.relay_names)?;
+        }
+        let expression = match (&entry.at, &entry.cron, &entry.days) {
+            (Some(at), None, days) => time_of_day_cron(at, days.as_ref())?,
+            (None, Some(cron), None) => cron.clone(),
+            (None, Some(_), Some(_)) => bail!("'days' only applies to 'at', not to 'cron'"),
+            (Some(_), Some(_), _) => bail!("An entry either sets 'at' or 'cron', not both"),
+            (None, None, _) => bail!("An entry needs a time, set 'at' or 'cron'"),
+        };
+        let cron: Cron = expression
+            .parse()
+            .map_err(|err| anyhow!("Invalid schedule '{expression}': {err}"))?;
+        let duration = entry
+            .duration
+            .as_deref()
+            .map(|duration| {
+                humantime::parse_duration(duration)
+                    .with_context(|| format!("Invalid duration '{duration}'"))
+            })
+            .transpose()?;
+        if duration.is_some() && entry.action != Action::On {
+            bail!("'for' only applies to the 'on' action");
+        }
+        let zone = match &entry.timezone {
+            Some(name) => Zone::parse(Some(name))?,
+            None => zone,
+        };
+        let mut entry = Entry {
+            name: String::new(),
+            relays,
+            action: entry.action,
+            duration,
+            expression,
+            cron,
+            zone,
+            next: None,
+            last_fired: None,
+        }
+        .named(entry.name);
+        entry.plan(Utc::now())?;
+        Ok(entry)
+    }
+
+    /// Sets the name, describing the entry if none is given.
+    fn named(mut self, name: Option<String>) -> Self {
+        self.name = name.unwrap_or_else(|| format!("{} ({})", self.action(), self.expression));
+        self
+    }
+
+    /// Describes the action of the entry, e.g. "on pump for 15m".
+    fn action(&self) -> String {
+        let action = format!("{:?} {}", self.action, self.relays).to_lowercase();
+        match self.duration {
+            Some(duration) => format!("{action} for {}", humantime::format_duration(duration)),
+            None => action,
+        }
+    }
+
+    /// Plans the next time the entry fires after `now`, but never before it last fired.
+    fn plan(&mut self, now: DateTime<Utc>) -> Result<()> {
+        let after = self
+            .last_fired
+            .map_or(now, |last_fired| last_fired.max(now));
+        self.next = Some(self.zone.next_after(&self.cron, after)?);
+        Ok(())
+    }
+
+    /// Plans the next time the entry fires like [`Entry::plan`], disabling the entry if there is none.
+    fn replan(&mut self, now: DateTime<Utc>) {
+        if let Err(err) = self.plan(now) {
+            error!("'{}' disabled: {err:#}", self.name);
+            self.next = None;
+        }
+    }
+
+    /// Re-plans the entry after the system clock jumped to `now`.
+    ///
+    /// An entry skipped by a forward jump stays due for its last missed time, so
+    /// it runs once, if that time lies within `catch_up` before `now` or the
+    /// entry turns relays off. Otherwise the missed time is logged and skipped.
+    fn resync(&mut self, now: DateTime<Utc>, catch_up: TimeDelta) {
+        let Some(next) = self.next else {
+            return;
+        };
+        if next <= now {
+            let missed = self
+                .zone
+                .last_until(&self.cron, now)
+                .map_or(next, |last| last.max(next));
+            if self.action == Action::Off || now - missed <= catch_up {
+                warn!(
+                    "Catching up '{}' due at {}",
+                    self.name,
+                    self.zone.format(missed)
+                );
+                self.next = Some(missed);
+                return;
+            }
+            warn!(
+                "Skipped '{}' due at {}",
+                self.name,
+                self.zone.format(missed)
+            );
+        }
+        self.replan(now);
+    }
+
+    /// Switches the relays of the entry and reports the new states.
+    ///
+    /// The relays turned on for a duration are added to `timed`.
+    fn fire(
+        &self,
+        client: &SafeClient,
+        settings: &Settings,
+        output: &Output,
+        timed: &mut Vec<TimedRelay>,
+    ) -> Result<()> {
+        let relays = self.relays.clone();
+        let command = match (self.action, self.duration) {
+            (Action::On, Some(duration)) => {
+                // The device turns the relays off, re-armed in the background beyond 255 seconds.
+                let mask = relays.resolve(&settings.relay_names)?;
+                for port in mask {
+                    let run = client
+                        .set_port_for(port, duration)
+                        .with_context(|| format!("Failed to turn ON relay {}", *port))?;
+                    timed.push(TimedRelay {
+                        entry: self.name.clone(),
+                        run,
+                    });
+                }
+                let message = format!(
+                    "{} turned ON for {}",
+                    crate::relays_name(mask),
+                    humantime::format_duration(duration)
+                );
+                return crate::report_states(client, output, Some(message));
+            }
+            (Action::On, None) => CliCommands::On { relays },
+            (Action::Off, _) => CliCommands::Off { relays },
+            (Action::Toggle, _) => CliCommands::Toggle { relays },
+            (Action::Latch, _) => CliCommands::Latch { relay: relays },
+            (Action::Momentary, _) => CliCommands::Momentary { relays },
+        };
+        crate::execute(client, settings, &command, output)
+    }
+}
+
+/// A relay turned on for a duration by an entry.
+struct TimedRelay {
+    /// The name of the entry.
+    entry: String,
+    run: TimedPort,
+}
+
+impl TimedRelay {
+    /// Waits until the run is over and logs how it ended.
+    fn finish(self) {
+        let port = *self.run.port();
+        match self.run.wait() {
+            Ok(()) => info!("'{}': the timer of relay {port} is over", self.entry),
+            Err(err) => error!(
+                "'{}': relay {port} turned OFF early, re-arming its timer failed: {err:#}",
+                self.entry
+            ),
+        }
+    }
+}
+
+/// Removes the timed relays whose run is over and logs how it ended.
+fn reap(timed: &mut Vec<TimedRelay>) {
+    timed
+        .extract_if(.., |relay| relay.run.is_finished())
+        .for_each(TimedRelay::finish);
+}
+
+/// Loads and validates the entries and the catch-up window of the schedule file.
+fn load(path: &Path, settings: &Settings) -> Result<(Vec<Entry>, TimeDelta)> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read schedule file {path:?}"))?;
+    let file: ScheduleFile =
+        toml::from_str(&content).with_context(|| format!("Cannot parse schedule file {path:?}"))?;
+    let zone = Zone::parse(file.timezone.as_deref())?;
+    let catch_up = match file.catch_up.as_deref() {
+        Some(catch_up) => humantime::parse_duration(catch_up)
+            .ok()
+            .and_then(|catch_up| TimeDelta::from_std(catch_up).ok())
+            .with_context(|| format!("Invalid catch_up '{catch_up}' in schedule file {path:?}"))?,
+        None => DEFAULT_CATCH_UP,
+    };
+    let entries = file
+        .entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            Entry::new(entry, zone, settings)
+                .with_context(|| format!("Invalid entry {} of schedule file {path:?}", index + 1))
+        })
+        .collect::<Result<_>>()?;
+    Ok((entries, catch_up))
+}
+
+/// Returns how far the system clock moved beyond the monotonic clock since the given instants.
+fn clock_jump(wall: DateTime<Utc>, monotonic: Instant) -> TimeDelta {
+    let elapsed = TimeDelta::from_std(monotonic.elapsed()).unwrap_or(TimeDelta::MAX);
+    Utc::now() - wall - elapsed
+}
+
+/// Fires the entries of the schedule file until the process is terminated.
+pub fn schedule(
+    client: SafeClient,
+    settings: &Settings,
+    output: &Output,
+    path: &Path,
+) -> Result<()> {
+    let (mut entries, catch_up) = load(path, settings)?;
+    if entries.is_empty() {
+        bail!("The schedule file {path:?} has no entries");
+    }
+    for entry in &entries {
+        if let Some(next) = entry.next {
+            info!(
+                "Scheduled '{}': {}, next at {}",
+                entry.name,
+                entry.action(),
+                entry.zone.format(next)
+            );
+        }
+    }
+    let mut timed = Vec::new();
+    loop {
+        let wall = Utc::now();
+        let monotonic = Instant::now();
+        reap(&mut timed);
+        for entry in &mut entries {
+            let Some(due) = entry.next.filter(|next| *next <= wall) else {
+                continue;
+            };
+            info!("Running '{}': {}", entry.name, entry.action());
+            if let Err(err) = entry.fire(&client, settings, output, &mut timed) {
+                error!("'{}' failed: {err:#}", entry.name);
+            }
+            entry.last_fired = Some(due);
+            entry.replan(wall);
+            if let Some(next) = entry.next {
+                debug!("'{}' next at {}", entry.name, entry.zone.format(next));
+            }
+        }
+        let Some(next) = entries.iter().filter_map(|entry| entry.next).min() else {
+            timed.into_iter().for_each(TimedRelay::finish);
+            bail!("No entry of the schedule file {path:?} has a next time");
+        };
+        std::thread::sleep((next - wall).to_std().unwrap_or_default().min(MAX_SLEEP));
+
+        let jump = clock_jump(wall, monotonic);
+        if jump.abs() > CLOCK_JUMP_TOLERANCE {
+            warn!("The system clock jumped by {jump}, re-synchronizing the schedule");
+            let now = Utc::now();
+            for entry in &mut entries {
+                entry.resync(now, catch_up);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn settings() -> Settings {
+        Settings {
+            connection: crate::config::Connection::Tcp {
+                address: String::from("localhost:502"),
+            },
+            timeout: Duration::from_secs(1),
+            relay_names: [(String::from("irrigation"), "2".parse().unwrap())].into(),
+        }
+    }
+
+    fn entry(toml: &str) -> Result<Entry> {
+        Entry::new(
+            toml::from_str(toml)?,
+            Zone::parse(Some("Europe/Berlin"))?,
+            &settings(),
+        )
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn time_of_day_entries() {
+        let mut entry = entry(
+            r#"
+            name = "morning irrigation"
+            relays = 0
+            action = "on"
+            at = "06:00"
+            days = "weekdays"
+            for = "15m"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(entry.expression, "0 0 6 * * MON-FRI");
+        assert_eq!(entry.duration, Some(Duration::from_secs(15 * 60)));
+        assert_eq!(entry.action(), "on 0 for 15m");
+
+        // Friday afternoon, the next weekday morning in Berlin (CEST) is Monday.
+        entry.plan(utc(2026, 10, 16, 14, 0)).unwrap();
+        assert_eq!(entry.next, Some(utc(2026, 10, 19, 4, 0)));
+        // After the end of daylight saving time (CET).
+        entry.plan(utc(2026, 10, 30, 14, 0)).unwrap();
+        assert_eq!(entry.next, Some(utc(2026, 11, 2, 5, 0)));
+    }
+
+    #[test]
+    fn cron_entries() {
+        let mut entry = entry(
+            r#"
+            relays = "0,3"
+            action = "off"
+            cron = "30 23 * * SAT,SUN"
+            timezone = "UTC"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(entry.zone, Zone::Named(chrono_tz::UTC));
+        assert_eq!(entry.name, "off 0,3 (30 23 * * SAT,SUN)");
+        entry.plan(utc(2026, 10, 16, 14, 0)).unwrap();
+        assert_eq!(entry.next, Some(utc(2026, 10, 17, 23, 30)));
+    }
+
+    #[test]
+    fn never_fires_twice_after_clock_jumps_back() {
+        let mut entry = entry("relays = 1\naction = \"toggle\"\nat = \"12:00:30\"").unwrap();
+        assert_eq!(entry.expression, "30 0 12 * * *");
+        entry.last_fired = Some(utc(2026, 10, 16, 10, 0) + TimeDelta::seconds(30));
+        entry.plan(utc(2026, 10, 16, 9, 0)).unwrap();
+        assert_eq!(
+            entry.next,
+            Some(utc(2026, 10, 17, 10, 0) + TimeDelta::seconds(30))
+        );
+    }
+
+    #[test]
+    fn clock_jumps() {
+        let jump = |wall: DateTime<Utc>| clock_jump(wall, Instant::now());
+        assert!(jump(Utc::now()).abs() <= CLOCK_JUMP_TOLERANCE);
+        // The system clock moved an hour beyond the monotonic clock.
+        let forward = jump(Utc::now() - TimeDelta::hours(1));
+        assert!((forward - TimeDelta::hours(1)).abs() <= CLOCK_JUMP_TOLERANCE);
+        let backward = jump(Utc::now() + TimeDelta::hours(1));
+        assert!((backward + TimeDelta::hours(1)).abs() <= CLOCK_JUMP_TOLERANCE);
+    }
+
+    #[test]
+    fn resync_catches_up_missed_entries() {
+        let catch_up = TimeDelta::minutes(5);
+        let mut toggle = entry("relays = 1\naction = \"toggle\"\nat = \"12:00\"").unwrap();
+
+        // Missed within the catch-up window, the entry stays due.
+        toggle.next = Some(utc(2026, 10, 16, 10, 0));
+        toggle.resync(utc(2026, 10, 16, 10, 3), catch_up);
+        assert_eq!(toggle.next, Some(utc(2026, 10, 16, 10, 0)));
+
+        // Missed before the window, the entry is skipped.
+        toggle.resync(utc(2026, 10, 16, 12, 0), catch_up);
+        assert_eq!(toggle.next, Some(utc(2026, 10, 17, 10, 0)));
+
+        // A backward jump plans the entry again.
+        toggle.resync(utc(2026, 10, 16, 9, 0), catch_up);
+        assert_eq!(toggle.next, Some(utc(2026, 10, 16, 10, 0)));
+
+        // An off entry is always caught up.
+        let mut off = entry("relays = 1\naction = \"off\"\nat = \"12:00\"").unwrap();
+        off.next = Some(utc(2026, 10, 16, 10, 0));
+        off.resync(utc(2026, 10, 16, 20, 0), catch_up);
+        assert_eq!(off.next, Some(utc(2026, 10, 16, 10, 0)));
+
+        // An entry missed several times runs once, for its last missed time.
+        let mut cron = entry("relays = 1\naction = \"on\"\ncron = \"*/15 * * * *\"").unwrap();
+        cron.next = Some(utc(2026, 10, 16, 10, 0));
+        cron.resync(utc(2026, 10, 16, 10, 50), catch_up);
+        assert_eq!(cron.next, Some(utc(2026, 10, 16, 10, 45)));
+    }
+
+    #[test]
+    fn entries_without_next_time_are_disabled() {
+        let mut entry = entry("relays = 1\naction = \"off\"\nat = \"12:00\"").unwrap();
+        assert!(entry.next.is_some());
+        entry.replan(Utc.with_ymd_and_hms(9999, 12, 31, 23, 0, 0).unwrap());
+        assert_eq!(entry.next, None);
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn fire_keeps_timed_relays() {
+        use crate::{commandline::OutputFormat, testing::serve_simulator};
+        use r413d08_lib::{protocol as proto, simulator::Simulator};
+
+        let simulator = Simulator::default();
+        let ctx = tokio_modbus::client::sync::tcp::connect(serve_simulator(&simulator)).unwrap();
+        let client = SafeClient::new(ctx).with_slave(proto::Address::default());
+        let is_open = |index: usize| {
+            simulator.port_states(proto::Address::default()).unwrap()[index]
+                == proto::PortState::Open
+        };
+        let entry = entry("relays = \"irrigation\"\naction = \"on\"\nat = \"06:00\"\nfor = \"1s\"")
+            .unwrap();
+        let mut timed = Vec::new();
+        entry
+            .fire(
+                &client,
+                &settings(),
+                &Output::new(OutputFormat::Json, None),
+                &mut timed,
+            )
+            .unwrap();
+        assert_eq!(timed.len(), 1);
+        assert_eq!(*timed[0].run.port(), 2);
+        assert!(is_open(2));
+
+        // The device turns the relay off, then the run is reaped.
+        reap(&mut timed);
+        assert_eq!(timed.len(), 1);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !timed[0].run.is_finished() {
+            assert!(Instant::now() < deadline, "Timed out waiting for the run");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        reap(&mut timed);
+        assert!(timed.is_empty());
+        assert!(!is_open(2));
+    }
+
+    #[test]
+    fn reject_invalid_entries() {
+        let invalid = [
+            "relays = 0\naction = \"off\"\nat = \"06:00\"\nfor = \"1m\"",
+            "relays = 0\naction = \"on\"\nat = \"06:00\"\ncron = \"0 6 * * *\"",
+            "relays = 0\naction = \"on\"\ncron = \"0 6 * * *\"\ndays = \"weekdays\"",
+            "relays = 0\naction = \"on\"",
+            "relays = 0\naction = \"on\"\nat = \"25:00\"",
+            "relays = 0\naction = \"on\"\ncron = \"every morning\"",
+            "relays = 0\naction = \"on\"\nat = \"06:00\"\ntimezone = \"Mars/Olympus\"",
+            "relays = \"0-1\"\naction = \"latch\"\nat = \"06:00\"",
+            "relays = \"pump\"\naction = \"on\"\nat = \"06:00\"",
+            "relays = 0\naction = \"blink\"\nat = \"06:00\"",
+        ];
+        for toml in invalid {
+            assert!(entry(toml).is_err(), "{toml}");
+        }
+    }
+}
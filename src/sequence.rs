@@ -0,0 +1,802 @@
+//! Runs scripted relay choreography, e.g. the startup ritual of a test rig.
+//!
+//! A [`Sequence`] is a list of [`Step`]s: switching ports or whole masks,
+//! latching, pulsing, waiting, and loops repeating nested steps a number of
+//! times or forever. Sequences are built in Rust or loaded from TOML or YAML
+//! files, and run with [`Sequence::run`] through any synchronous client
+//! implementing [`RelayController`], e.g. the synchronous `SafeClient`. A
+//! [`Control`] pauses, resumes or aborts a running sequence from another
+//! thread, and every step is reported before it runs.
+//!
+//! A run blocks the calling thread while it waits between steps, so the
+//! asynchronous clients are not supported. From async code, run the sequence
+//! with a synchronous client on a thread of its own, e.g. with
+//! `tokio::task::spawn_blocking`.
+//!
+//! # File Format
+//!
+//! Ports are given as a number (`3`), a list (`[0, 1]`) or a selector string
+//! (`"1-4,7"` or `"0b0000_0011"`). Durations are numbers of seconds or strings
+//! like `"500ms"` or `"1m 30s"`. A loop without `times` repeats forever.
+//!
+//! ```toml
+//! name = "rig startup"
+//!
+//! [[steps]]
+//! mask = "0-1"            # Exactly ports 0 and 1 on
+//!
+//! [[steps]]
+//! wait = "2s"
+//!
+//! [[steps]]
+//! pulse = { port = 7, duration = "350ms" }
+//!
+//! [[steps]]
+//! loop = { times = 3, steps = [{ toggle = 2 }, { wait = 1 }] }
+//! ```
+//!
+//! The same sequence in YAML:
+//!
+//! ```yaml
+//! name: rig startup
+//! steps:
+//!   - mask: "0-1"
+//!   - wait: 2s
+//!   - pulse: { port: 7, duration: 350ms }
+//!   - loop:
+//!       times: 3
+//!       steps:
+//!         - toggle: 2
+//!         - wait: 1
+//! ```
+//!
+//! # Example
+//!
+//! ```no_run
+//! use r413d08_lib::{
+//!     protocol::{Port, PortMask},
+//!     sequence::{Control, Sequence, Step},
+//!     tokio_sync_safe_client::SafeClient,
+//! };
+//! use std::time::Duration;
+//!
+//! # fn example(client: SafeClient) -> Result<(), Box<dyn std::error::Error>> {
+//! // Blink port 0 ten times, then switch everything off
+//! let sequence = Sequence::new(vec![
+//!     Step::Loop {
+//!         times: Some(10),
+//!         steps: vec![
+//!             Step::Pulse { port: Port::try_from(0)?, duration: Duration::from_millis(200) },
+//!             Step::Wait(Duration::from_millis(300)),
+//!         ],
+//!     },
+//!     Step::Mask(PortMask::NONE),
+//! ]);
+//! let control = Control::new();
+//! sequence.run(&mut client.clone(), &control, |progress| println!("{progress}"))?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{protocol as proto, tokio_common::Error, tokio_sync::RelayController};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// One step of a [`Sequence`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    /// Opens the given ports, leaving all others as they are.
+    Set(#[serde(deserialize_with = "de::ports")] proto::PortMask),
+    /// Closes the given ports, leaving all others as they are.
+    Clear(#[serde(deserialize_with = "de::ports")] proto::PortMask),
+    /// Toggles the given ports.
+    Toggle(#[serde(deserialize_with = "de::ports")] proto::PortMask),
+    /// Opens exactly the given ports and closes all others.
+    Mask(#[serde(deserialize_with = "de::ports")] proto::PortMask),
+    /// Opens the given port and closes all others with the latch command of the device.
+    Latch(proto::Port),
+    /// Opens the given port for the given duration, then closes it.
+    ///
+    /// The step takes the whole duration. Whole seconds from 1 to 255 are
+    /// timed by the device, so the port closes even if the controller dies.
+    /// Pausing or aborting the run ends the pulse and closes the port right
+    /// away. With a safe client, a later command affecting the port through
+    /// the client or one of its clones wins, and the port is left alone.
+    Pulse {
+        /// The port to pulse.
+        port: proto::Port,
+        /// The time the port stays open.
+        #[serde(deserialize_with = "de::duration")]
+        duration: Duration,
+    },
+    /// Waits for the given duration.
+    Wait(#[serde(deserialize_with = "de::duration")] Duration),
+    /// Runs the nested steps the given number of times, or forever if `None`.
+    Loop {
+        /// The number of iterations, `None` to repeat until aborted.
+        #[serde(default)]
+        times: Option<u32>,
+        /// The steps of each iteration.
+        steps: Vec<Step>,
+    },
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Set(mask) => write!(f, "set {mask:#}"),
+            Self::Clear(mask) => write!(f, "clear {mask:#}"),
+            Self::Toggle(mask) => write!(f, "toggle {mask:#}"),
+            Self::Mask(mask) => write!(f, "mask {mask:#}"),
+            Self::Latch(port) => write!(f, "latch {port}"),
+            Self::Pulse { port, duration } => write!(
+                f,
+                "pulse {port} for {}",
+                humantime::format_duration(*duration)
+            ),
+            Self::Wait(duration) => write!(f, "wait {}", humantime::format_duration(*duration)),
+            Self::Loop { times, steps } => match times {
+                Some(times) => write!(f, "loop {times} times over {} steps", steps.len()),
+                None => write!(f, "loop forever over {} steps", steps.len()),
+            },
+        }
+    }
+}
+
+/// A named list of steps, see the [module documentation](self) for the file format.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sequence {
+    /// The name of the sequence, e.g. for log messages.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The steps, run one after the other.
+    pub steps: Vec<Step>,
+}
+
+/// Errors that can occur while loading a [`Sequence`].
+#[derive(thiserror::Error, Debug)]
+pub enum LoadError {
+    /// The sequence file could not be read.
+    #[error("Cannot read sequence file {path:?}")]
+    Io {
+        /// The path of the file.
+        path: PathBuf,
+        /// The underlying error.
+        source: std::io::Error,
+    },
+
+    /// The TOML content is not a valid sequence.
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+
+    /// The YAML content is not a valid sequence.
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml_ng::Error),
+
+    /// The file extension is neither `.toml`, `.yaml` nor `.yml`.
+    #[error("Unknown format of sequence file {0:?}, expected a .toml, .yaml or .yml file")]
+    UnknownFormat(PathBuf),
+}
+
+/// How a run of a [`Sequence`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// All steps were run.
+    Completed,
+    /// The run was stopped with [`Control::abort`].
+    Aborted,
+}
+
+/// The step about to run, reported by [`Sequence::run`].
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    /// The step about to run.
+    pub step: &'a Step,
+    /// The index of the step in the sequence, followed by its indices within enclosing loops.
+    pub position: &'a [usize],
+    /// The zero-based iteration of each enclosing loop, outermost first.
+    pub iterations: &'a [u32],
+    /// The number of top-level steps of the sequence.
+    pub total: usize,
+}
+
+impl std::fmt::Display for Progress<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}/{}", self.position[0] + 1, self.total)?;
+        for (index, iteration) in self.position[1..].iter().zip(self.iterations) {
+            write!(f, " #{} .{}", iteration + 1, index + 1)?;
+        }
+        write!(f, "] {}", self.step)
+    }
+}
+
+/// The state shared by the clones of a [`Control`].
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    aborted: bool,
+}
+
+/// Pauses, resumes or aborts a running [`Sequence`], e.g. from another thread.
+///
+/// All clones control the same runs. An aborted control stays aborted, create
+/// a new one for the next run.
+#[derive(Debug, Clone, Default)]
+pub struct Control {
+    shared: Arc<(Mutex<ControlState>, Condvar)>,
+}
+
+impl Control {
+    /// Creates a new `Control` that neither pauses nor aborts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pauses the run before the next step.
+    ///
+    /// A running wait is frozen and continues with the remaining time after
+    /// [`Control::resume`]. A running pulse ends right away and closes its
+    /// port, the run continues with the next step after resuming. All other
+    /// relays keep their states while paused.
+    pub fn pause(&self) {
+        self.update(|state| state.paused = true);
+    }
+
+    /// Resumes a paused run.
+    pub fn resume(&self) {
+        self.update(|state| state.paused = false);
+    }
+
+    /// Aborts the run before the next step, or during a wait or pulse.
+    ///
+    /// An aborted pulse closes its port.
+    pub fn abort(&self) {
+        self.update(|state| state.aborted = true);
+    }
+
+    /// Returns `true` if the run is paused.
+    pub fn is_paused(&self) -> bool {
+        self.shared.0.lock().unwrap().paused
+    }
+
+    /// Returns `true` if the run was aborted.
+    pub fn is_aborted(&self) -> bool {
+        self.shared.0.lock().unwrap().aborted
+    }
+
+    fn update(&self, change: impl FnOnce(&mut ControlState)) {
+        let (state, changed) = &*self.shared;
+        change(&mut state.lock().unwrap());
+        changed.notify_all();
+    }
+
+    /// Sleeps for the given duration, not counting the time paused.
+    ///
+    /// Blocks while paused even for a zero duration. Returns `false` as soon
+    /// as the run is aborted.
+    fn sleep(&self, duration: Duration) -> bool {
+        let (state, changed) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        let mut remaining = duration;
+        loop {
+            if state.aborted {
+                return false;
+            }
+            if state.paused {
+                state = changed.wait(state).unwrap();
+            } else if remaining.is_zero() {
+                return true;
+            } else {
+                let started = Instant::now();
+                state = changed.wait_timeout(state, remaining).unwrap().0;
+                remaining = remaining.saturating_sub(started.elapsed());
+            }
+        }
+    }
+
+    /// Sleeps for the duration of a pulse.
+    ///
+    /// Returns `false` as soon as the run is paused or aborted.
+    fn sleep_pulse(&self, duration: Duration) -> bool {
+        let (state, changed) = &*self.shared;
+        let state = state.lock().unwrap();
+        changed
+            .wait_timeout_while(state, duration, |state| !state.paused && !state.aborted)
+            .unwrap()
+            .1
+            .timed_out()
+    }
+}
+
+impl Sequence {
+    /// Creates an unnamed sequence of the given steps.
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self { name: None, steps }
+    }
+
+    /// Parses a sequence from TOML.
+    pub fn from_toml(content: &str) -> Result<Self, LoadError> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Parses a sequence from YAML.
+    pub fn from_yaml(content: &str) -> Result<Self, LoadError> {
+        // Steps are written as single-key maps like in TOML, not as YAML tags.
+        let deserializer = serde_yaml_ng::Deserializer::from_str(content);
+        Ok(serde_yaml_ng::with::singleton_map_recursive::deserialize(
+            deserializer,
+        )?)
+    }
+
+    /// Loads a sequence from a `.toml`, `.yaml` or `.yml` file.
+    ///
+    /// A sequence without a name is named after the file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let parse = match extension {
+            Some("toml") => Self::from_toml,
+            Some("yaml" | "yml") => Self::from_yaml,
+            _ => return Err(LoadError::UnknownFormat(path.to_path_buf())),
+        };
+        let content = std::fs::read_to_string(path).map_err(|source| LoadError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut sequence = parse(&content)?;
+        if sequence.name.is_none() {
+            sequence.name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned());
+        }
+        Ok(sequence)
+    }
+
+    /// Runs the steps through the given synchronous client until they are done or the run is aborted.
+    ///
+    /// The calling thread is blocked for the whole run. The `progress` function
+    /// is called before every step. The run stops at the first failing step and
+    /// returns its error.
+    pub fn run<R: RelayController>(
+        &self,
+        relays: &mut R,
+        control: &Control,
+        progress: impl FnMut(&Progress),
+    ) -> Result<Outcome, Error> {
+        let mut run = Run {
+            relays,
+            control,
+            progress,
+            position: Vec::new(),
+            iterations: Vec::new(),
+            total: self.steps.len(),
+        };
+        run.steps(&self.steps)
+    }
+}
+
+/// The state of a running [`Sequence`].
+struct Run<'a, R, P> {
+    relays: &'a mut R,
+    control: &'a Control,
+    progress: P,
+    position: Vec<usize>,
+    iterations: Vec<u32>,
+    total: usize,
+}
+
+impl<R: RelayController, P: FnMut(&Progress)> Run<'_, R, P> {
+    fn steps(&mut self, steps: &[Step]) -> Result<Outcome, Error> {
+        for (index, step) in steps.iter().enumerate() {
+            if !self.control.sleep(Duration::ZERO) {
+                return Ok(Outcome::Aborted);
+            }
+            self.position.push(index);
+            (self.progress)(&Progress {
+                step,
+                position: &self.position,
+                iterations: &self.iterations,
+                total: self.total,
+            });
+            let outcome = self.step(step)?;
+            self.position.pop();
+            if outcome == Outcome::Aborted {
+                return Ok(outcome);
+            }
+        }
+        Ok(Outcome::Completed)
+    }
+
+    fn step(&mut self, step: &Step) -> Result<Outcome, Error> {
+        match step {
+            Step::Set(mask) if *mask == proto::PortMask::ALL => self.relays.set_all_open()?,
+            Step::Set(mask) => self.each(*mask, proto::Command::Open)?,
+            Step::Clear(mask) if *mask == proto::PortMask::ALL => self.relays.set_all_close()?,
+            Step::Clear(mask) => self.each(*mask, proto::Command::Close)?,
+            Step::Toggle(mask) => self.each(*mask, proto::Command::Toggle)?,
+            Step::Mask(mask) => self.relays.apply_mask(*mask)?,
+            Step::Latch(port) => self.relays.set_port_latch(*port)?,
+            Step::Pulse { port, duration } => return self.pulse(*port, *duration),
+            Step::Wait(duration) => return Ok(self.sleep(*duration)),
+            Step::Loop { times, steps } => {
+                self.iterations.push(0);
+                while times.is_none_or(|times| self.iterations[self.iterations.len() - 1] < times) {
+                    if self.steps(steps)? == Outcome::Aborted {
+                        return Ok(Outcome::Aborted);
+                    }
+                    // Even a loop without steps stays pausable and abortable.
+                    if !self.control.sleep(Duration::ZERO) {
+                        return Ok(Outcome::Aborted);
+                    }
+                    *self.iterations.last_mut().unwrap() += 1;
+                }
+                self.iterations.pop();
+            }
+        }
+        Ok(Outcome::Completed)
+    }
+
+    /// Executes the command for every port of the mask.
+    fn each(
+        &mut self,
+        mask: proto::PortMask,
+        command: fn(proto::Port) -> proto::Command,
+    ) -> Result<(), Error> {
+        mask.iter()
+            .try_for_each(|port| self.relays.execute(command(port)))
+    }
+
+    fn pulse(&mut self, port: proto::Port, duration: Duration) -> Result<Outcome, Error> {
+        let control = self.control;
+        self.relays.pulse_with(port, duration, &mut |duration| {
+            control.sleep_pulse(duration)
+        })?;
+        if control.is_aborted() {
+            Ok(Outcome::Aborted)
+        } else {
+            Ok(Outcome::Completed)
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> Outcome {
+        if self.control.sleep(duration) {
+            Outcome::Completed
+        } else {
+            Outcome::Aborted
+        }
+    }
+}
+
+/// The deserializers of the sequence file format.
+mod de {
+    use crate::protocol as proto;
+    use serde::{de::Error, Deserialize, Deserializer};
+    use std::time::Duration;
+
+    /// The ports of a step: a port number, a list of port numbers or a selector.
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Ports {
+        Port(u8),
+        List(Vec<u8>),
+        Selector(String),
+    }
+
+    pub(super) fn ports<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<proto::PortMask, D::Error> {
+        let port = |index| proto::Port::try_from(index).map_err(D::Error::custom);
+        match Ports::deserialize(deserializer)? {
+            Ports::Port(index) => port(index).map(proto::PortMask::from),
+            Ports::List(indices) => indices.into_iter().map(port).collect(),
+            Ports::Selector(selector) => selector.parse().map_err(D::Error::custom),
+        }
+    }
+
+    /// A duration: a number of seconds or a string like "500ms".
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Length {
+        Seconds(f64),
+        Text(String),
+    }
+
+    pub(super) fn duration<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Duration, D::Error> {
+        match Length::deserialize(deserializer)? {
+            Length::Seconds(seconds) => {
+                Duration::try_from_secs_f64(seconds).map_err(D::Error::custom)
+            }
+            Length::Text(text) => humantime::parse_duration(&text).map_err(D::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(index: u8) -> proto::Port {
+        proto::Port::try_from(index).unwrap()
+    }
+
+    #[test]
+    fn parse_toml() {
+        let sequence = Sequence::from_toml(
+            r#"
+            name = "startup"
+
+            [[steps]]
+            set = [0, 1]
+
+            [[steps]]
+            clear = 3
+
+            [[steps]]
+            mask = "0b0000_0101"
+
+            [[steps]]
+            pulse = { port = 7, duration = "350ms" }
+
+            [[steps]]
+            loop = { times = 2, steps = [{ toggle = "1-2" }, { wait = 1.5 }] }
+
+            [[steps]]
+            loop = { steps = [{ latch = 4 }] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(sequence.name.as_deref(), Some("startup"));
+        assert_eq!(
+            sequence.steps,
+            [
+                Step::Set(proto::PortMask::from_bits(0b0000_0011)),
+                Step::Clear(port(3).into()),
+                Step::Mask(proto::PortMask::from_bits(0b0000_0101)),
+                Step::Pulse {
+                    port: port(7),
+                    duration: Duration::from_millis(350)
+                },
+                Step::Loop {
+                    times: Some(2),
+                    steps: vec![
+                        Step::Toggle(proto::PortMask::from_bits(0b0000_0110)),
+                        Step::Wait(Duration::from_millis(1500)),
+                    ]
+                },
+                Step::Loop {
+                    times: None,
+                    steps: vec![Step::Latch(port(4))]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_yaml() {
+        let sequence = Sequence::from_yaml(
+            "
+            steps:
+              - set: 0
+              - wait: 2s
+              - loop:
+                  times: 3
+                  steps:
+                    - pulse: { port: 1, duration: 1 }
+            ",
+        )
+        .unwrap();
+        assert_eq!(sequence.name, None);
+        assert_eq!(
+            sequence.steps,
+            [
+                Step::Set(port(0).into()),
+                Step::Wait(Duration::from_secs(2)),
+                Step::Loop {
+                    times: Some(3),
+                    steps: vec![Step::Pulse {
+                        port: port(1),
+                        duration: Duration::from_secs(1)
+                    }]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_steps() {
+        assert!(Sequence::from_toml("[[steps]]\nset = 8").is_err());
+        assert!(Sequence::from_toml("[[steps]]\nwait = \"soon\"").is_err());
+        assert!(Sequence::from_toml("[[steps]]\nblink = 1").is_err());
+        assert!(Sequence::from_yaml("steps:\n  - set: 1\n    clear: 2").is_err());
+        assert!(matches!(
+            Sequence::load("sequence.json"),
+            Err(LoadError::UnknownFormat(_))
+        ));
+    }
+
+    #[test]
+    fn progress_display() {
+        let step = Step::Wait(Duration::from_millis(500));
+        let progress = Progress {
+            step: &step,
+            position: &[2, 0],
+            iterations: &[4],
+            total: 5,
+        };
+        assert_eq!(progress.to_string(), "[3/5 #5 .1] wait 500ms");
+    }
+
+    /// Runs the sequence in a background thread, counting the reported steps.
+    #[cfg(all(
+        feature = "simulator",
+        feature = "safe-client-sync",
+        feature = "tokio-tcp-sync"
+    ))]
+    fn spawn_run(
+        sequence: &str,
+        client: &crate::tokio_sync_safe_client::SafeClient,
+        control: &Control,
+    ) -> (
+        std::thread::JoinHandle<Result<Outcome, Error>>,
+        Arc<std::sync::atomic::AtomicUsize>,
+    ) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let sequence = Sequence::from_toml(sequence).unwrap();
+        let steps = Arc::new(AtomicUsize::new(0));
+        let runner = {
+            let (mut client, control, steps) = (client.clone(), control.clone(), steps.clone());
+            std::thread::spawn(move || {
+                sequence.run(&mut client, &control, |_| {
+                    steps.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+        };
+        (runner, steps)
+    }
+
+    #[cfg(all(
+        feature = "simulator",
+        feature = "safe-client-sync",
+        feature = "tokio-tcp-sync"
+    ))]
+    #[test]
+    fn sequence_runs_pauses_and_aborts() {
+        use crate::simulator::testing::{holds_for, wait_until, Fixture};
+        use std::sync::atomic::Ordering;
+
+        let fixture = Fixture::new();
+        let client = fixture.sync_client();
+
+        // A complete run reports every step, including those of loops.
+        let sequence = Sequence::from_toml(
+            r#"
+            [[steps]]
+            set = [0, 1]
+            [[steps]]
+            toggle = 1
+            [[steps]]
+            loop = { times = 3, steps = [{ pulse = { port = 2, duration = "50ms" } }, { wait = "20ms" }] }
+            "#,
+        )
+        .unwrap();
+        let mut reported = Vec::new();
+        let outcome = sequence
+            .run(&mut client.clone(), &Control::new(), |progress| {
+                reported.push(progress.to_string())
+            })
+            .unwrap();
+        assert_eq!(outcome, Outcome::Completed);
+        assert_eq!(
+            fixture.open_ports(),
+            proto::PortMask::from_bits(0b0000_0001)
+        );
+        assert_eq!(reported.len(), 9);
+        assert_eq!(reported[3], "[3/3 #1 .1] pulse 2 for 50ms");
+        assert_eq!(reported[8], "[3/3 #3 .2] wait 20ms");
+
+        // Pausing freezes an endless loop until it is resumed or aborted.
+        let control = Control::new();
+        let (runner, steps) = spawn_run(
+            "[[steps]]\nloop = { steps = [{ toggle = 3 }, { wait = \"20ms\" }] }",
+            &client,
+            &control,
+        );
+        wait_until("the loop ran a few steps", || {
+            steps.load(Ordering::Relaxed) >= 5
+        });
+        control.pause();
+        // The step that was about to start when pausing may still run.
+        let reported = steps.load(Ordering::Relaxed);
+        holds_for(
+            "the paused run to start at most one more step",
+            Duration::from_millis(100),
+            || steps.load(Ordering::Relaxed) <= reported + 1,
+        );
+        let paused = fixture.open_ports();
+        holds_for(
+            "the paused run to leave the relays alone",
+            Duration::from_millis(100),
+            || fixture.open_ports() == paused,
+        );
+        assert!(!runner.is_finished());
+        let reported = steps.load(Ordering::Relaxed);
+        control.resume();
+        wait_until("the resumed loop ran a few steps", || {
+            steps.load(Ordering::Relaxed) >= reported + 5
+        });
+        control.abort();
+        assert_eq!(runner.join().unwrap().unwrap(), Outcome::Aborted);
+
+        // An aborted pulse closes its port.
+        let control = Control::new();
+        let (runner, _) = spawn_run(
+            "[[steps]]\npulse = { port = 5, duration = 10 }",
+            &client,
+            &control,
+        );
+        wait_until("the pulse opened port 5", || fixture.is_open(5));
+        control.abort();
+        assert_eq!(runner.join().unwrap().unwrap(), Outcome::Aborted);
+        assert!(!fixture.is_open(5));
+    }
+
+    #[cfg(all(
+        feature = "simulator",
+        feature = "safe-client-sync",
+        feature = "tokio-tcp-sync"
+    ))]
+    #[test]
+    fn pausing_ends_pulses() {
+        use crate::simulator::testing::{holds_for, wait_until, Fixture};
+
+        let fixture = Fixture::new();
+        let client = fixture.sync_client();
+
+        // Pulses timed by the device and in software both close their port when
+        // paused, the run continues with the next step after resuming.
+        for duration in ["10", "\"10s 500ms\""] {
+            let control = Control::new();
+            let (runner, _) = spawn_run(
+                &format!(
+                    "[[steps]]\npulse = {{ port = 6, duration = {duration} }}\n[[steps]]\nset = 7"
+                ),
+                &client,
+                &control,
+            );
+            wait_until("the pulse opened port 6", || fixture.is_open(6));
+            control.pause();
+            wait_until("pausing closed port 6", || !fixture.is_open(6));
+            holds_for(
+                "the paused run to stay before the next step",
+                Duration::from_millis(100),
+                || !fixture.is_open(7),
+            );
+            control.resume();
+            assert_eq!(runner.join().unwrap().unwrap(), Outcome::Completed);
+            assert!(fixture.is_open(7));
+            client.set_port_close(port(7)).unwrap();
+        }
+
+        // A later command wins over the pulse, which then leaves the port alone.
+        let control = Control::new();
+        let (runner, _) = spawn_run(
+            "[[steps]]\npulse = { port = 4, duration = \"10s 500ms\" }",
+            &client,
+            &control,
+        );
+        wait_until("the pulse opened port 4", || fixture.is_open(4));
+        client.set_port_open(port(4)).unwrap();
+        control.abort();
+        assert_eq!(runner.join().unwrap().unwrap(), Outcome::Aborted);
+        assert!(fixture.is_open(4));
+    }
+}
@@ -0,0 +1,337 @@
+//! Implements the `serve` command, a small HTTP/REST API for the relays.
+//!
+//! The server owns the connection to the device, so several services can
+//! control the relays through it. Every successful request returns the port
+//! states read after the command, serialized as JSON array of `"Open"`/`"Close"`:
+//!
+//! | Request               | Body               | Action                            |
+//! |-----------------------|--------------------|-----------------------------------|
+//! | `GET /relays`         |                    | Read the port states              |
+//! | `PUT /relays/{relay}` | `{"action": "on"}` | Switch one relay                  |
+//! | `POST /all/on`        |                    | Turn all relays on                |
+//! | `POST /all/off`       |                    | Turn all relays off               |
+//! | `POST /latch/{relay}` |                    | Turn one relay on, all others off |
+//!
+//! The action is `on`, `off`, `toggle`, `momentary` or `delay`, which also takes
+//! the `seconds` (0-255) until the relay turns off. A relay is given by its number
+//! or by its name from the profile, percent-encoded if it contains reserved
+//! characters. Errors are returned as JSON objects like the `--output json`
+//! errors of the CLI. Request bodies are limited to [`MAX_BODY_LENGTH`] bytes.
+
+use crate::{commandline::RelaySelector, config::Settings, output::Output};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use r413d08_lib::{protocol as proto, tokio_common, tokio_sync_safe_client::SafeClient};
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Read;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// The maximum length of a request body in bytes, longer bodies are rejected.
+const MAX_BODY_LENGTH: u64 = 1024;
+
+/// The action of a `PUT /relays/{relay}` request.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase", deny_unknown_fields)]
+enum RelayAction {
+    On,
+    Off,
+    Toggle,
+    Momentary,
+    Delay { seconds: u8 },
+}
+
+/// A request that could not be served: the HTTP status code and the JSON error.
+struct Failure {
+    status: u16,
+    body: serde_json::Value,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl std::fmt::Display) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+
+    /// Returns the failure for an error of the device or the connection to it.
+    fn device(err: tokio_common::Error, output: &Output) -> Self {
+        let status = if err.is_timeout() { 504 } else { 502 };
+        Self {
+            status,
+            body: output.error_json(&anyhow!(err)),
+        }
+    }
+}
+
+/// Handles one request and returns the port states afterwards.
+fn route(
+    client: &SafeClient,
+    settings: &Settings,
+    output: &Output,
+    method: &Method,
+    path: &[&str],
+    body: &str,
+) -> Result<proto::PortStates, Failure> {
+    let relay = |selector: &str| {
+        let Ok(selector) = selector.parse::<RelaySelector>();
+        selector
+            .resolve_single(&settings.relay_names)
+            .map_err(|err| Failure::new(400, err))
+    };
+    let device = |err| Failure::device(err, output);
+    match (method, path) {
+        (Method::Get, ["relays"]) => {}
+        (Method::Put, ["relays", selector]) => {
+            let port = relay(selector)?;
+            let action: RelayAction = serde_json::from_str(body)
+                .map_err(|err| Failure::new(400, format!("Invalid body: {err}")))?;
+            match action {
+                RelayAction::On => client.set_port_open(port),
+                RelayAction::Off => client.set_port_close(port),
+                RelayAction::Toggle => client.set_port_toggle(port),
+                RelayAction::Momentary => client.set_port_momentary(port),
+                RelayAction::Delay { seconds } => client.set_port_delay(port, seconds),
+            }
+            .map_err(device)?;
+        }
+        (Method::Post, ["all", "on"]) => client.set_all_open().map_err(device)?,
+        (Method::Post, ["all", "off"]) => client.set_all_close().map_err(device)?,
+        (Method::Post, ["latch", selector]) => {
+            client.set_port_latch(relay(selector)?).map_err(device)?
+        }
+        (_, ["relays"] | ["relays", _] | ["all", "on" | "off"] | ["latch", _]) => {
+            return Err(Failure::new(405, format!("Method {method} not allowed")));
+        }
+        _ => return Err(Failure::new(404, "Not found")),
+    }
+    client.read_ports().map_err(device)
+}
+
+/// Decodes the percent-encoded bytes of a path segment, e.g. `valve%20north`.
+fn percent_decode(segment: &str) -> Result<String, Failure> {
+    let invalid = || Failure::new(400, format!("Invalid percent-encoding in '{segment}'"));
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// Reads the body and the decoded path segments of the request and routes it.
+fn dispatch(
+    client: &SafeClient,
+    settings: &Settings,
+    output: &Output,
+    request: &mut Request,
+) -> Result<proto::PortStates, Failure> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_LENGTH + 1)
+        .read_to_string(&mut body)
+        .map_err(|err| Failure::new(400, format!("Cannot read the body: {err}")))?;
+    if body.len() as u64 > MAX_BODY_LENGTH {
+        return Err(Failure::new(
+            413,
+            format!("The body exceeds {MAX_BODY_LENGTH} bytes"),
+        ));
+    }
+    let path = request
+        .url()
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect::<Result<Vec<_>, _>>()?;
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    route(client, settings, output, request.method(), &path, &body)
+}
+
+/// Handles one request and sends the response.
+fn handle(client: &SafeClient, settings: &Settings, output: &Output, mut request: Request) {
+    let result = dispatch(client, settings, output, &mut request);
+    let (status, body) = match result {
+        Ok(states) => (200, json!(states)),
+        Err(failure) => (failure.status, failure.body),
+    };
+    debug!("{} {} -> {status}", request.method(), request.url());
+    let header = Header::from_bytes("Content-Type", "application/json")
+        .expect("the content type header is valid");
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(err) = request.respond(response) {
+        warn!("Cannot send the response: {err}");
+    }
+}
+
+/// Serves the HTTP API on the given address until the process is terminated.
+///
+/// Requests are handled one after the other, in the order they arrive.
+pub fn serve(client: SafeClient, settings: &Settings, output: &Output, listen: &str) -> Result<()> {
+    let server = Server::http(listen)
+        .map_err(|err| anyhow!(err))
+        .with_context(|| format!("Cannot listen on {listen}"))?;
+    info!("Serving the relay API on http://{listen}");
+    for request in server.incoming_requests() {
+        handle(&client, settings, output, request);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("relays").ok(), Some("relays".to_string()));
+        assert_eq!(
+            percent_decode("valve%20north").ok(),
+            Some("valve north".to_string())
+        );
+        assert_eq!(percent_decode("caf%C3%a9").ok(), Some("café".to_string()));
+        for invalid in ["%", "%2", "%zz", "%FF", "%+1"] {
+            assert_eq!(percent_decode(invalid).err().map(|f| f.status), Some(400));
+        }
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn route_status_codes() {
+        use crate::{commandline::OutputFormat, config::Connection, testing::serve_simulator};
+        use r413d08_lib::simulator::Simulator;
+        use std::time::Duration;
+
+        let address = |value| proto::Address::try_from(value).unwrap();
+        let simulator = Simulator::new([address(1), address(2)]);
+        let socket_addr = serve_simulator(&simulator);
+        let settings = Settings {
+            connection: Connection::Tcp {
+                address: socket_addr.to_string(),
+            },
+            timeout: Duration::from_millis(50),
+            relay_names: [("valve north".to_string(), "3".parse().unwrap())].into(),
+        };
+        let output = Output::new(OutputFormat::Json, None);
+        let client = |slave| {
+            let ctx = tokio_modbus::client::sync::tcp::connect_with_timeout(
+                socket_addr,
+                Some(settings.timeout),
+            )
+            .unwrap();
+            SafeClient::new(ctx).with_slave(slave)
+        };
+        let device = client(address(1));
+        let status = |client: &SafeClient, method, path: &[&str], body| match route(
+            client, &settings, &output, &method, path, body,
+        ) {
+            Ok(_) => 200,
+            Err(failure) => failure.status,
+        };
+        let on = r#"{"action": "on"}"#;
+
+        assert_eq!(status(&device, Method::Get, &["relays"], ""), 200);
+        assert_eq!(
+            status(&device, Method::Put, &["relays", "valve north"], on),
+            200
+        );
+        assert_eq!(
+            simulator.port_states(address(1)),
+            Some(proto::PortMask::from_bits(0b1000).into())
+        );
+        assert_eq!(
+            status(
+                &device,
+                Method::Put,
+                &["relays", "1"],
+                r#"{"action": "delay"}"#
+            ),
+            400
+        );
+        assert_eq!(status(&device, Method::Put, &["relays", "pump"], on), 400);
+        assert_eq!(status(&device, Method::Post, &["latch", "1-2"], ""), 400);
+        assert_eq!(status(&device, Method::Get, &["relays", "1", "x"], ""), 404);
+        assert_eq!(status(&device, Method::Get, &["all", "on"], ""), 405);
+        assert_eq!(status(&device, Method::Delete, &["relays"], ""), 405);
+
+        // Both devices answer the broadcast address, nobody answers at address 9.
+        let broadcast = client(proto::Address::BROADCAST);
+        assert_eq!(status(&broadcast, Method::Get, &["relays"], ""), 502);
+        assert_eq!(
+            status(&client(address(9)), Method::Post, &["all", "on"], ""),
+            504
+        );
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn serves_requests() {
+        use crate::{commandline::OutputFormat, config::Connection, testing::serve_simulator};
+        use r413d08_lib::simulator::Simulator;
+        use std::{io::Write, net::TcpStream, time::Duration};
+
+        let simulator = Simulator::default();
+        let socket_addr = serve_simulator(&simulator);
+        let settings = Settings {
+            connection: Connection::Tcp {
+                address: socket_addr.to_string(),
+            },
+            timeout: Duration::from_secs(1),
+            relay_names: [("valve/north".to_string(), "6".parse().unwrap())].into(),
+        };
+        let client =
+            SafeClient::new(tokio_modbus::client::sync::tcp::connect(socket_addr).unwrap());
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let server_addr = server.server_addr().to_ip().unwrap();
+        let request = |path: &str, body: &str| {
+            format!(
+                "PUT {path} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+        let requests = [
+            request("/relays/valve%2Fnorth", r#"{"action": "on"}"#),
+            request("/relays/1", &" ".repeat(MAX_BODY_LENGTH as usize + 1)),
+        ];
+        let handler = std::thread::spawn(move || {
+            let output = Output::new(OutputFormat::Json, None);
+            for request in server.incoming_requests().take(2) {
+                handle(&client, &settings, &output, request);
+            }
+        });
+        let statuses: Vec<String> = requests
+            .iter()
+            .map(|request| {
+                let mut stream = TcpStream::connect(server_addr).unwrap();
+                stream.write_all(request.as_bytes()).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).ok();
+                response.lines().next().unwrap_or_default().to_string()
+            })
+            .collect();
+        handler.join().unwrap();
+        assert_eq!(statuses[0], "HTTP/1.1 200 OK");
+        assert!(statuses[1].starts_with("HTTP/1.1 413"), "{}", statuses[1]);
+        assert_eq!(
+            simulator.port_states(proto::Address::default()),
+            Some(proto::PortMask::from_bits(0b0100_0000).into())
+        );
+    }
+}
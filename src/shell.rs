@@ -0,0 +1,329 @@
+//! Implements the `shell` command, an interactive prompt that keeps one connection open.
+//!
+//! The shell accepts the same commands as the command line (`status`, `on 3`,
+//! `latch pump`, ...) plus `slave <address>` to switch to another device on the
+//! bus and `exit`. The history is kept in the user's local data directory.
+
+use crate::{
+    commandline::{CliCommands, ShellCommand, ShellLine},
+    config::{Connection, Settings},
+    output::Output,
+};
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser};
+use log::*;
+use r413d08_lib::{protocol as proto, tokio_sync_safe_client::SafeClient};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    history::DefaultHistory,
+    Editor, Helper, Highlighter, Hinter, Validator,
+};
+use std::path::{Path, PathBuf};
+
+/// The commands whose first argument selects relays.
+const RELAY_COMMANDS: [&str; 6] = ["on", "off", "toggle", "latch", "momentary", "delay"];
+
+/// Completes command names and, as argument of the relay commands, relay names.
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct ShellHelper {
+    commands: Vec<String>,
+    relays: Vec<String>,
+}
+
+impl ShellHelper {
+    fn new(settings: &Settings) -> Self {
+        Self {
+            commands: ShellLine::command()
+                .get_subcommands()
+                .flat_map(|command| {
+                    std::iter::once(command.get_name()).chain(command.get_all_aliases())
+                })
+                .map(String::from)
+                .collect(),
+            relays: std::iter::once("all")
+                .chain(settings.relay_names.keys().map(String::as_str))
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let mut start = line.rfind(char::is_whitespace).map_or(0, |idx| idx + 1);
+        let preceding: Vec<&str> = line[..start].split_whitespace().collect();
+        let candidates = match preceding.as_slice() {
+            [] => &self.commands,
+            [command] if RELAY_COMMANDS.contains(command) => {
+                // Complete the last item of a list such as "pump,li".
+                start += line[start..].rfind(',').map_or(0, |idx| idx + 1);
+                &self.relays
+            }
+            _ => return Ok((pos, Vec::new())),
+        };
+        let word = &line[start..];
+        let matches = candidates
+            .iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: candidate.clone(),
+            })
+            .collect();
+        Ok((start, matches))
+    }
+}
+
+/// Returns the location of the history file.
+fn history_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("r413d08").join("shell_history"))
+}
+
+/// Saves the history, a failure is only logged as the session itself is not affected.
+fn save_history(editor: &mut Editor<ShellHelper, DefaultHistory>, path: &Path) {
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .map_err(ReadlineError::from)
+        .and_then(|()| editor.save_history(path));
+    if let Err(err) = result {
+        warn!("Cannot save the shell history to {path:?}: {err}");
+    }
+}
+
+/// The device a shell session talks to, switched by `slave` and `set-address`.
+struct Session {
+    client: SafeClient,
+    address: Option<proto::Address>,
+    output: Output,
+}
+
+impl Session {
+    fn new(client: SafeClient, settings: &Settings, output: &Output) -> Self {
+        let address = match &settings.connection {
+            Connection::Rtu { address, .. } => Some(*address),
+            Connection::Tcp { .. } => None,
+        };
+        let client = match address {
+            Some(address) => client.with_slave(address),
+            None => client,
+        };
+        Self {
+            client,
+            address,
+            output: *output,
+        }
+    }
+
+    /// Returns the prompt, which shows the address of the device if known.
+    fn prompt(&self) -> String {
+        match self.address {
+            Some(address) => format!("relay[{address}]> "),
+            None => "relay> ".to_string(),
+        }
+    }
+
+    /// Switches to the device with the given address.
+    fn switch_to(&mut self, address: proto::Address) {
+        self.client = self.client.clone().with_slave(address);
+        self.address = Some(address);
+        self.output = self.output.with_address(self.address);
+    }
+
+    /// Runs a command other than `exit`, reporting its error.
+    fn run(&mut self, command: &ShellCommand, settings: &Settings) {
+        let result = match command {
+            // Leaving the shell is up to the caller.
+            ShellCommand::Exit => Ok(()),
+            ShellCommand::Slave { address } => {
+                self.switch_to(*address);
+                Ok(())
+            }
+            ShellCommand::Device(CliCommands::QueryAddress) if self.address.is_some() => {
+                query_address(&self.client, settings, &self.output)
+            }
+            ShellCommand::Device(command) => {
+                crate::execute(&self.client, settings, command, &self.output)
+            }
+        };
+        match result {
+            Ok(()) => {
+                if let ShellCommand::Device(CliCommands::SetAddress { address }) = command {
+                    // The client already communicates with the new address.
+                    self.switch_to(*address);
+                }
+            }
+            Err(err) => self.output.error(&err),
+        }
+    }
+}
+
+/// Runs the interactive shell until the user leaves it with `exit` or Ctrl-D.
+pub fn shell(client: SafeClient, settings: &Settings, output: &Output) -> Result<()> {
+    let mut session = Session::new(client, settings, output);
+
+    let mut editor: Editor<ShellHelper, DefaultHistory> =
+        Editor::new().context("Cannot start the shell")?;
+    editor.set_helper(Some(ShellHelper::new(settings)));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history before the first session.
+        let _ = editor.load_history(path);
+    }
+    println!("Type 'help' for the list of commands, 'exit' or Ctrl-D to leave.");
+
+    loop {
+        let line = match editor.readline(&session.prompt()) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err).context("Failed to read the input"),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        // Saved right away, so the line is kept even if the process is killed.
+        let _ = editor.add_history_entry(line.as_str());
+        if let Some(path) = &history {
+            save_history(&mut editor, path);
+        }
+        let command = match ShellLine::try_parse_from(words) {
+            Ok(parsed) => parsed.command,
+            Err(err) => {
+                // Prints the error, or the help if it was requested.
+                let _ = err.print();
+                continue;
+            }
+        };
+        if command == ShellCommand::Exit {
+            break;
+        }
+        session.run(&command, settings);
+    }
+    Ok(())
+}
+
+/// Queries the address of the single device on the bus using the broadcast address.
+///
+/// The query uses its own client on the shared connection, so the shell's
+/// client keeps its slave address.
+fn query_address(client: &SafeClient, settings: &Settings, output: &Output) -> Result<()> {
+    if !crate::confirm_single_device()? {
+        return Ok(());
+    }
+    let broadcast =
+        SafeClient::from_shared(client.clone_shared()).with_slave(proto::Address::BROADCAST);
+    crate::execute(&broadcast, settings, &CliCommands::QueryAddress, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commandline::OutputFormat;
+    use rustyline::history::MemHistory;
+
+    fn settings(connection: Connection) -> Settings {
+        Settings {
+            connection,
+            timeout: crate::commandline::DEFAULT_TIMEOUT,
+            relay_names: [
+                ("pump".to_string(), proto::PortMask::from_bits(0b0001)),
+                ("lights".to_string(), proto::PortMask::from_bits(0b0110)),
+            ]
+            .into(),
+        }
+    }
+
+    fn tcp() -> Connection {
+        Connection::Tcp {
+            address: "127.0.0.1:502".to_string(),
+        }
+    }
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let helper = ShellHelper::new(&settings(tcp()));
+        let history = MemHistory::new();
+        let ctx = rustyline::Context::new(&history);
+        let (start, pairs) = helper.complete(line, line.len(), &ctx).unwrap();
+        (
+            start,
+            pairs.into_iter().map(|pair| pair.replacement).collect(),
+        )
+    }
+
+    #[test]
+    fn completes_commands_and_relay_names() {
+        assert_eq!(complete("sta"), (0, vec!["status".to_string()]));
+        assert!(complete("").1.contains(&"slave".to_string()));
+        assert_eq!(complete("qui"), (0, vec!["quit".to_string()]));
+        assert_eq!(
+            complete("on "),
+            (
+                3,
+                vec!["all".to_string(), "lights".to_string(), "pump".to_string()]
+            )
+        );
+        assert_eq!(complete("latch p"), (6, vec!["pump".to_string()]));
+        assert_eq!(complete("off pump,l"), (9, vec!["lights".to_string()]));
+        // Neither further arguments nor arguments of other commands are completed.
+        assert_eq!(complete("on pump p"), (9, Vec::new()));
+        assert_eq!(complete("slave p"), (7, Vec::new()));
+    }
+
+    #[test]
+    fn slave_switches_the_prompt_address() {
+        // Switching the device does not communicate, a connection that is never served suffices.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let ctx = tokio_modbus::client::sync::tcp::connect(listener.local_addr().unwrap()).unwrap();
+        let settings = settings(Connection::Rtu {
+            device: "/dev/ttyUSB1".to_string(),
+            address: proto::Address::try_from(3).unwrap(),
+        });
+        let mut session = Session::new(
+            SafeClient::new(ctx),
+            &settings,
+            &Output::new(OutputFormat::Json, None),
+        );
+        assert_eq!(session.prompt(), "relay[0x03]> ");
+
+        let address = proto::Address::try_from(0x10).unwrap();
+        session.run(&ShellCommand::Slave { address }, &settings);
+        assert_eq!(session.prompt(), "relay[0x10]> ");
+        assert_eq!(session.address, Some(address));
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn set_address_switches_the_prompt_address() {
+        use crate::testing::serve_simulator;
+        use r413d08_lib::simulator::Simulator;
+
+        let simulator = Simulator::default();
+        let ctx = tokio_modbus::client::sync::tcp::connect(serve_simulator(&simulator)).unwrap();
+        let settings = settings(tcp());
+        let mut session = Session::new(
+            SafeClient::new(ctx),
+            &settings,
+            &Output::new(OutputFormat::Json, None),
+        );
+        assert_eq!(session.prompt(), "relay> ");
+
+        let address = proto::Address::try_from(5).unwrap();
+        session.run(
+            &ShellCommand::Device(CliCommands::SetAddress { address }),
+            &settings,
+        );
+        assert_eq!(session.prompt(), "relay[0x05]> ");
+        assert!(simulator.port_states(address).is_some());
+    }
+}
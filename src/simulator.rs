@@ -0,0 +1,593 @@
+//! Provides a software simulation of the R413D08 relay module served over Modbus TCP.
+//!
+//! This module defines the [`Simulator`] struct, which models one or more R413D08
+//! boards and answers Modbus requests with the same register map as the real hardware:
+//!
+//! - Holding registers `0x0001`-`0x0008` report the port states and accept the
+//!   per-port command words defined on [`proto::Port`].
+//! - Register `0x0000` accepts the all-ports command words defined on [`proto::PortsAll`].
+//! - Register `0x00FF` holds the device address (see [`proto::Address`]).
+//!
+//! The momentary (~1 second) and delay timers are simulated as well. Timers are
+//! evaluated whenever a device is accessed, so a read issued after a deadline
+//! observes the port closed, exactly as it would on the real board.
+//!
+//! Requests addressed to the broadcast address ([`proto::Address::BROADCAST`]) are
+//! answered as long as only one device is simulated. Requests for unknown slave
+//! addresses are not answered at all, which makes the client run into its timeout
+//! like it would on a real RS485 bus.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use r413d08_lib::{protocol::Address, simulator::Simulator};
+//! use tokio::net::TcpListener;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     // Simulate two boards with the addresses 1 and 2
+//!     let simulator = Simulator::new([Address::try_from(1)?, Address::try_from(2)?]);
+//!     let listener = TcpListener::bind("127.0.0.1:5020").await?;
+//!     simulator.serve(listener).await?;
+//!     Ok(())
+//! }
+//! ```
+
+use crate::protocol as proto;
+use std::{
+    future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::net::TcpListener;
+use tokio_modbus::{
+    server::tcp::{accept_tcp_connection, Server},
+    ExceptionCode, Request, Response, SlaveId, SlaveRequest,
+};
+
+/// The time a port stays open after a momentary command.
+const MOMENTARY_DURATION: Duration = Duration::from_secs(1);
+
+/// The simulated state of a single R413D08 board.
+#[derive(Debug, Clone)]
+struct Device {
+    address: proto::Address,
+    ports: [proto::PortState; proto::NUMBER_OF_PORTS],
+    timers: [Option<Instant>; proto::NUMBER_OF_PORTS],
+}
+
+impl Device {
+    fn new(address: proto::Address) -> Self {
+        Self {
+            address,
+            ports: [proto::PortState::Close; proto::NUMBER_OF_PORTS],
+            timers: [None; proto::NUMBER_OF_PORTS],
+        }
+    }
+
+    /// Closes every port whose momentary or delay timer has expired at `now`.
+    fn expire_timers(&mut self, now: Instant) {
+        for (state, timer) in self.ports.iter_mut().zip(self.timers.iter_mut()) {
+            if timer.is_some_and(|deadline| deadline <= now) {
+                *state = proto::PortState::Close;
+                *timer = None;
+            }
+        }
+    }
+
+    /// Sets the state of a port and cancels or arms its timer.
+    fn set_port(&mut self, index: usize, state: proto::PortState, timer: Option<Instant>) {
+        self.ports[index] = state;
+        self.timers[index] = timer;
+    }
+
+    fn port_states(&mut self, now: Instant) -> proto::PortStates {
+        self.expire_timers(now);
+        proto::PortStates::from(self.ports)
+    }
+
+    fn read_holding_registers(
+        &mut self,
+        now: Instant,
+        address: u16,
+        quantity: u16,
+    ) -> Result<Vec<proto::Word>, ExceptionCode> {
+        self.expire_timers(now);
+        if address == proto::Address::ADDRESS && quantity == proto::Address::QUANTITY {
+            return Ok(vec![self.address.encode_for_write_register()]);
+        }
+        let first = proto::PortStates::ADDRESS;
+        let last = first + proto::PortStates::QUANTITY - 1;
+        let end = address as u32 + quantity as u32;
+        if quantity == 0 || address < first || end > last as u32 + 1 {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        Ok(
+            self.ports[(address - first) as usize..(end - first as u32) as usize]
+                .iter()
+                .map(|state| match state {
+                    proto::PortState::Close => 0x0000,
+                    proto::PortState::Open => 0x0001,
+                })
+                .collect(),
+        )
+    }
+
+    fn write_single_register(
+        &mut self,
+        now: Instant,
+        address: u16,
+        value: proto::Word,
+    ) -> Result<(), ExceptionCode> {
+        self.expire_timers(now);
+        let writable = address == proto::PortsAll::ADDRESS
+            || address == proto::Address::ADDRESS
+            || (proto::PortStates::ADDRESS
+                ..proto::PortStates::ADDRESS + proto::PortStates::QUANTITY)
+                .contains(&address);
+        if !writable {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        let command =
+            proto::Command::decode(address, value).map_err(|_| ExceptionCode::IllegalDataValue)?;
+        match command {
+            proto::Command::Open(port) => {
+                self.set_port(*port as usize, proto::PortState::Open, None)
+            }
+            proto::Command::Close(port) => {
+                self.set_port(*port as usize, proto::PortState::Close, None)
+            }
+            proto::Command::Toggle(port) => {
+                let state = match self.ports[*port as usize] {
+                    proto::PortState::Close => proto::PortState::Open,
+                    proto::PortState::Open => proto::PortState::Close,
+                };
+                self.set_port(*port as usize, state, None);
+            }
+            proto::Command::Latch(port) => {
+                for index in 0..proto::NUMBER_OF_PORTS {
+                    self.set_port(index, proto::PortState::Close, None);
+                }
+                self.set_port(*port as usize, proto::PortState::Open, None);
+            }
+            proto::Command::Momentary(port) => self.set_port(
+                *port as usize,
+                proto::PortState::Open,
+                Some(now + MOMENTARY_DURATION),
+            ),
+            proto::Command::Delay(port, delay) => self.set_port(
+                *port as usize,
+                proto::PortState::Open,
+                Some(now + Duration::from_secs(delay as u64)),
+            ),
+            proto::Command::AllOpen | proto::Command::AllClose => {
+                let state = if command == proto::Command::AllOpen {
+                    proto::PortState::Open
+                } else {
+                    proto::PortState::Close
+                };
+                for index in 0..proto::NUMBER_OF_PORTS {
+                    self.set_port(index, state, None);
+                }
+            }
+            proto::Command::SetAddress(address) => self.address = address,
+        }
+        log::info!("Device {}: {command}", self.address);
+        Ok(())
+    }
+}
+
+/// A simulation of one or more R413D08 relay modules sharing a Modbus bus.
+///
+/// The simulator keeps the state of every device behind an `Arc<Mutex<...>>`,
+/// so it can be cheaply cloned, served over TCP with [`Simulator::serve`] and
+/// inspected at the same time (e.g., from a test asserting on the port states).
+///
+/// It implements [`tokio_modbus::server::Service`], so it can also be plugged
+/// into any other `tokio-modbus` server.
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    devices: Arc<Mutex<Vec<Device>>>,
+}
+
+impl Default for Simulator {
+    /// Creates a simulator with a single device using the factory default address.
+    fn default() -> Self {
+        Self::new([proto::Address::default()])
+    }
+}
+
+impl Simulator {
+    /// Creates a new `Simulator` with one device per given address.
+    ///
+    /// All ports of the simulated devices start in the [`proto::PortState::Close`] state.
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses`: The Modbus addresses of the simulated devices.
+    pub fn new(addresses: impl IntoIterator<Item = proto::Address>) -> Self {
+        Self {
+            devices: Arc::new(Mutex::new(addresses.into_iter().map(Device::new).collect())),
+        }
+    }
+
+    /// Returns the current Modbus addresses of all simulated devices.
+    ///
+    /// The addresses reflect any change made by a set address command.
+    pub fn addresses(&self) -> Vec<proto::Address> {
+        let devices = self.devices.lock().unwrap();
+        devices.iter().map(|device| device.address).collect()
+    }
+
+    /// Returns the current port states of the device with the given address.
+    ///
+    /// Returns `None` if no simulated device uses this address.
+    pub fn port_states(&self, address: proto::Address) -> Option<proto::PortStates> {
+        let now = Instant::now();
+        let mut devices = self.devices.lock().unwrap();
+        devices
+            .iter_mut()
+            .find(|device| device.address == address)
+            .map(|device| device.port_states(now))
+    }
+
+    /// Processes a single Modbus request addressed to the given slave.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(response))`: The response of the addressed device.
+    /// * `Ok(None)`: No simulated device uses the slave address, so nobody answers.
+    /// * `Err(exception)`: The addressed device rejects the request.
+    pub fn process(
+        &self,
+        slave: SlaveId,
+        request: &Request<'_>,
+    ) -> Result<Option<Response>, ExceptionCode> {
+        let now = Instant::now();
+        let mut devices = self.devices.lock().unwrap();
+        let mut addressed = devices
+            .iter_mut()
+            .filter(|device| slave == *proto::Address::BROADCAST || slave == *device.address);
+        let device = match (addressed.next(), addressed.next()) {
+            (None, _) => return Ok(None),
+            (Some(device), None) => device,
+            // Several devices would answer at the same time and garble the response.
+            (Some(_), Some(_)) => return Err(ExceptionCode::GatewayTargetDevice),
+        };
+        match *request {
+            Request::ReadHoldingRegisters(address, quantity) => device
+                .read_holding_registers(now, address, quantity)
+                .map(|words| Some(Response::ReadHoldingRegisters(words))),
+            Request::WriteSingleRegister(address, value) => device
+                .write_single_register(now, address, value)
+                .map(|()| Some(Response::WriteSingleRegister(address, value))),
+            _ => Err(ExceptionCode::IllegalFunction),
+        }
+    }
+
+    /// Serves the simulated devices as a Modbus TCP server on the given listener.
+    ///
+    /// Each accepted connection is served by its own task, all of them sharing
+    /// the state of this simulator. This function only returns if accepting a
+    /// new connection fails.
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        let server = Server::new(listener);
+        let on_connected = |stream, socket_addr| {
+            let simulator = self.clone();
+            async move {
+                accept_tcp_connection(stream, socket_addr, move |_| Ok(Some(simulator.clone())))
+            }
+        };
+        let on_process_error = |err| log::warn!("Simulator connection failed: {err}");
+        server.serve(&on_connected, on_process_error).await
+    }
+}
+
+#[cfg(test)]
+impl Simulator {
+    /// Serves the simulator on an ephemeral local port from a background thread.
+    ///
+    /// Returns the socket address clients can connect to.
+    pub(crate) fn serve_in_background(&self) -> std::net::SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let simulator = self.clone();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                let listener = TcpListener::from_std(listener).unwrap();
+                simulator.serve(listener).await
+            })
+        });
+        socket_addr
+    }
+}
+
+/// A shared fixture for tests that run clients against simulated devices.
+#[cfg(test)]
+pub(crate) mod testing {
+    // Not every feature combination uses every helper.
+    #![allow(dead_code)]
+
+    use super::*;
+
+    /// How long [`wait_until`] waits for a condition before the test fails.
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// The time between two checks of a condition.
+    const CHECK_INTERVAL: Duration = Duration::from_millis(5);
+
+    /// Simulated devices served on an ephemeral local port.
+    pub(crate) struct Fixture {
+        pub(crate) simulator: Simulator,
+        pub(crate) socket_addr: std::net::SocketAddr,
+    }
+
+    impl Fixture {
+        /// Serves a single device with the default address.
+        pub(crate) fn new() -> Self {
+            Self::with_devices([proto::Address::default()])
+        }
+
+        /// Serves devices with the given addresses.
+        pub(crate) fn with_devices(addresses: impl IntoIterator<Item = proto::Address>) -> Self {
+            let simulator = Simulator::new(addresses);
+            let socket_addr = simulator.serve_in_background();
+            Self {
+                simulator,
+                socket_addr,
+            }
+        }
+
+        /// Returns the open ports of the device with the given address.
+        pub(crate) fn open_ports_of(&self, address: proto::Address) -> proto::PortMask {
+            self.simulator
+                .port_states(address)
+                .expect("device is simulated")
+                .into()
+        }
+
+        /// Returns the open ports of the device with the default address.
+        pub(crate) fn open_ports(&self) -> proto::PortMask {
+            self.open_ports_of(proto::Address::default())
+        }
+
+        /// Returns `true` if the port with the given index of the default device is open.
+        pub(crate) fn is_open(&self, index: u8) -> bool {
+            self.open_ports().contains(port(index))
+        }
+
+        /// Connects a synchronous context.
+        #[cfg(feature = "tokio-tcp-sync")]
+        pub(crate) fn sync_context(&self) -> tokio_modbus::client::sync::Context {
+            tokio_modbus::client::sync::tcp::connect(self.socket_addr).unwrap()
+        }
+
+        /// Connects a synchronous client to the device with the default address.
+        #[cfg(all(feature = "safe-client-sync", feature = "tokio-tcp-sync"))]
+        pub(crate) fn sync_client(&self) -> crate::tokio_sync_safe_client::SafeClient {
+            crate::tokio_sync_safe_client::SafeClient::new(self.sync_context())
+                .with_slave(proto::Address::default())
+        }
+
+        /// Connects an asynchronous context.
+        #[cfg(feature = "tokio-tcp")]
+        pub(crate) async fn async_context(&self) -> tokio_modbus::client::Context {
+            tokio_modbus::client::tcp::connect(self.socket_addr)
+                .await
+                .unwrap()
+        }
+
+        /// Connects an asynchronous client to the device with the default address.
+        #[cfg(all(feature = "safe-client-async", feature = "tokio-tcp"))]
+        pub(crate) async fn async_client(&self) -> crate::tokio_async_safe_client::SafeClient {
+            crate::tokio_async_safe_client::SafeClient::new(self.async_context().await)
+                .with_slave(proto::Address::default())
+        }
+    }
+
+    /// Returns the port with the given index.
+    pub(crate) fn port(index: u8) -> proto::Port {
+        proto::Port::try_from(index).unwrap()
+    }
+
+    /// Returns the address with the given value.
+    pub(crate) fn address(value: u8) -> proto::Address {
+        proto::Address::try_from(value).unwrap()
+    }
+
+    /// Returns a local listener that never accepts, and its address.
+    ///
+    /// Connecting to the address succeeds. Once the listener is dropped, the
+    /// first request on such a connection fails with a transport error.
+    pub(crate) fn closing_listener() -> (std::net::TcpListener, std::net::SocketAddr) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        (listener, socket_addr)
+    }
+
+    /// Checks `condition` until it holds, the test fails if it doesn't within [`WAIT_TIMEOUT`].
+    pub(crate) fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out waiting until {what}");
+            std::thread::sleep(CHECK_INTERVAL);
+        }
+    }
+
+    /// Checks `condition` for the given time, the test fails as soon as it doesn't hold.
+    pub(crate) fn holds_for(what: &str, duration: Duration, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            assert!(condition(), "Expected {what} for {duration:?}");
+            std::thread::sleep(CHECK_INTERVAL);
+        }
+    }
+
+    /// Like [`wait_until`], but lets other tasks run while waiting.
+    pub(crate) async fn wait_until_async(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out waiting until {what}");
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Like [`holds_for`], but lets other tasks run while checking.
+    pub(crate) async fn holds_for_async(
+        what: &str,
+        duration: Duration,
+        mut condition: impl FnMut() -> bool,
+    ) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            assert!(condition(), "Expected {what} for {duration:?}");
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
+
+impl tokio_modbus::server::Service for Simulator {
+    type Request = SlaveRequest<'static>;
+    type Response = Option<Response>;
+    type Exception = ExceptionCode;
+    type Future = future::Ready<Result<Self::Response, Self::Exception>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let result = self.process(req.slave, &req.request);
+        match &result {
+            Ok(Some(_)) => log::debug!("Slave {}: {:?}", req.slave, req.request),
+            Ok(None) => log::trace!("Slave {}: no device, ignored", req.slave),
+            Err(err) => log::debug!("Slave {}: {:?} rejected: {err}", req.slave, req.request),
+        }
+        future::ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    fn states(device: &mut Device, now: Instant) -> Vec<proto::Word> {
+        device
+            .read_holding_registers(now, proto::PortStates::ADDRESS, proto::PortStates::QUANTITY)
+            .unwrap()
+    }
+
+    #[test]
+    fn device_port_commands() {
+        let now = Instant::now();
+        let mut device = Device::new(proto::Address::default());
+        device
+            .write_single_register(now, 0x0002, proto::Port::REG_DATA_SET_PORT_OPEN)
+            .unwrap();
+        device
+            .write_single_register(now, 0x0004, proto::Port::REG_DATA_SET_PORT_TOGGLE)
+            .unwrap();
+        assert_eq!(states(&mut device, now), [0, 1, 0, 1, 0, 0, 0, 0]);
+
+        device
+            .write_single_register(now, 0x0004, proto::Port::REG_DATA_SET_PORT_TOGGLE)
+            .unwrap();
+        device
+            .write_single_register(now, 0x0002, proto::Port::REG_DATA_SET_PORT_CLOSE)
+            .unwrap();
+        assert_eq!(states(&mut device, now), [0; 8]);
+
+        device
+            .write_single_register(now, proto::PortsAll::ADDRESS, 0x0700)
+            .unwrap();
+        device
+            .write_single_register(now, 0x0008, proto::Port::REG_DATA_SET_PORT_LATCH)
+            .unwrap();
+        assert_eq!(states(&mut device, now), [0, 0, 0, 0, 0, 0, 0, 1]);
+
+        device
+            .write_single_register(now, proto::PortsAll::ADDRESS, 0x0800)
+            .unwrap();
+        assert_eq!(states(&mut device, now), [0; 8]);
+    }
+
+    #[test]
+    fn device_timers() {
+        let now = Instant::now();
+        let mut device = Device::new(proto::Address::default());
+        device
+            .write_single_register(now, 0x0001, proto::Port::REG_DATA_SET_PORT_MOMENTARY)
+            .unwrap();
+        device
+            .write_single_register(now, 0x0003, proto::Port::encode_delay_for_write_register(5))
+            .unwrap();
+        assert_eq!(states(&mut device, now), [1, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            states(&mut device, now + Duration::from_secs(1)),
+            [0, 0, 1, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            states(&mut device, now + Duration::from_secs(5)),
+            [0; 8],
+            "Delay timer should close the port"
+        );
+
+        // A plain open command cancels a running timer
+        device
+            .write_single_register(now, 0x0003, proto::Port::encode_delay_for_write_register(5))
+            .unwrap();
+        device
+            .write_single_register(now, 0x0003, proto::Port::REG_DATA_SET_PORT_OPEN)
+            .unwrap();
+        assert_eq!(
+            states(&mut device, now + Duration::from_secs(10)),
+            [0, 0, 1, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn device_rejects_invalid_requests() {
+        let now = Instant::now();
+        let mut device = Device::new(proto::Address::default());
+        assert_matches!(
+            device.read_holding_registers(now, 0x0002, 8),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_matches!(
+            device.write_single_register(now, 0x0009, proto::Port::REG_DATA_SET_PORT_OPEN),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_matches!(
+            device.write_single_register(now, 0x0001, 0x0900),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_matches!(
+            device.write_single_register(now, proto::Address::ADDRESS, 0x00F8),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+    }
+
+    #[test]
+    fn simulator_addressing() {
+        let one = proto::Address::try_from(1).unwrap();
+        let two = proto::Address::try_from(2).unwrap();
+        let simulator = Simulator::new([one, two]);
+        let read_address = Request::ReadHoldingRegisters(proto::Address::ADDRESS, 1);
+
+        assert_eq!(
+            simulator.process(2, &read_address),
+            Ok(Some(Response::ReadHoldingRegisters(vec![2])))
+        );
+        assert_eq!(simulator.process(3, &read_address), Ok(None));
+        assert_eq!(
+            simulator.process(*proto::Address::BROADCAST, &read_address),
+            Err(ExceptionCode::GatewayTargetDevice)
+        );
+
+        let set_address = Request::WriteSingleRegister(proto::Address::ADDRESS, 0x0010);
+        assert_matches!(simulator.process(2, &set_address), Ok(Some(_)));
+        assert_eq!(
+            simulator.addresses(),
+            [one, proto::Address::try_from(0x10).unwrap()]
+        );
+    }
+}
@@ -0,0 +1,22 @@
+//! Serves simulated devices for the tests of the commands.
+
+use r413d08_lib::simulator::Simulator;
+use std::net::SocketAddr;
+
+/// Serves the simulator on an ephemeral local port from a background thread.
+///
+/// Returns the socket address clients can connect to.
+pub fn serve_simulator(simulator: &Simulator) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let socket_addr = listener.local_addr().unwrap();
+    let simulator = simulator.clone();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            simulator.serve(listener).await
+        })
+    });
+    socket_addr
+}
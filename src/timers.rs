@@ -0,0 +1,353 @@
+//! This module provides the timer bookkeeping shared by the synchronous and
+//! asynchronous safe clients.
+//!
+//! It holds no I/O: the clients drive these state machines while they hold
+//! their context, which keeps them testable without a device.
+
+use crate::protocol as proto;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Counts the commands written to each port, shared by the clones of a safe client.
+///
+/// A software timer remembers the generation of its port when it opens it and
+/// only closes the port if no other command touched the port in the meantime.
+/// Both the increment and the check happen while the context is locked.
+#[derive(Debug, Default)]
+pub(crate) struct PortGenerations([AtomicU64; proto::NUMBER_OF_PORTS]);
+
+impl PortGenerations {
+    /// Records a command written to the given ports.
+    pub(crate) fn touch(&self, ports: proto::PortMask) {
+        for port in ports.iter() {
+            self.0[*port as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a command written by a software timer and returns its generation.
+    pub(crate) fn claim(&self, port: proto::Port) -> u64 {
+        self.0[*port as usize].fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Returns `true` if no command touched the port since it was claimed.
+    pub(crate) fn is_current(&self, port: proto::Port, generation: u64) -> bool {
+        self.0[*port as usize].load(Ordering::Relaxed) == generation
+    }
+}
+
+/// Returns the ports whose state a command may change.
+pub(crate) fn touched_ports(command: &proto::Command) -> proto::PortMask {
+    match command {
+        proto::Command::Latch(_) | proto::Command::AllOpen | proto::Command::AllClose => {
+            proto::PortMask::ALL
+        }
+        proto::Command::SetAddress(_) => proto::PortMask::NONE,
+        command => command.port().map_or(proto::PortMask::NONE, Into::into),
+    }
+}
+
+/// The longest delay the device can count down in one command.
+const MAX_DEVICE_DELAY: Duration = Duration::from_secs(u8::MAX as u64);
+
+/// How long before the end of a delay window a long-running timer re-arms it.
+const REARM_MARGIN: Duration = Duration::from_secs(15);
+
+/// How long a long-running timer waits before it retries a failed re-arm.
+const REARM_RETRY: Duration = Duration::from_secs(5);
+
+/// A device delay window armed by a long-running timer, see `set_port_for`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DelayWindow {
+    /// The generation of the port after the delay command, see [`PortGenerations`].
+    pub(crate) generation: u64,
+    /// The time the device closes the port unless the window is re-armed.
+    pub(crate) until: Instant,
+    /// The time to re-arm the window, `None` for the last window.
+    pub(crate) rearm_at: Option<Instant>,
+}
+
+impl DelayWindow {
+    /// Returns the device delay in seconds for the time remaining until the deadline.
+    ///
+    /// The remaining time is split into windows of at most 255 seconds, the
+    /// last window is rounded to whole seconds.
+    pub(crate) fn delay(remaining: Duration) -> u8 {
+        if remaining > MAX_DEVICE_DELAY {
+            u8::MAX
+        } else {
+            (remaining.as_secs_f64().round() as u8).max(1)
+        }
+    }
+
+    /// Returns the window acknowledged by the device at `now`.
+    pub(crate) fn armed(generation: u64, now: Instant, delay: u8, remaining: Duration) -> Self {
+        let until = now + Duration::from_secs(delay.into());
+        Self {
+            generation,
+            until,
+            rearm_at: (remaining > MAX_DEVICE_DELAY).then(|| until - REARM_MARGIN),
+        }
+    }
+
+    /// Schedules a retry after a failed re-arm, `false` if the window ends before.
+    pub(crate) fn retry(&mut self, now: Instant) -> bool {
+        let retry_at = now + REARM_RETRY;
+        self.rearm_at = Some(retry_at);
+        retry_at < self.until
+    }
+}
+
+/// What the worker of a long-running timer does next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimedRunStep {
+    /// The run was cancelled, close the port.
+    Close,
+    /// Arm a new window for the time remaining until the given deadline.
+    Rearm(Instant),
+    /// The device closed the port at the deadline, the run is over.
+    Finish,
+    /// Nothing to do until the given time or until the run is changed.
+    WaitUntil(Instant),
+}
+
+/// Returns the deadline of a run of the given duration starting now.
+///
+/// Fails if the duration reaches beyond the times the clock can represent.
+pub(crate) fn deadline_after(duration: Duration) -> Result<Instant, proto::Error> {
+    Instant::now()
+        .checked_add(duration)
+        .ok_or_else(|| proto::Error::EncodeError {
+            reason: format!("the duration {duration:?} is too long"),
+        })
+}
+
+/// The state of a long-running timer shared between its handle and its worker.
+#[derive(Debug)]
+pub(crate) struct TimedRun {
+    /// The time the port should close.
+    pub(crate) deadline: Instant,
+    /// Set when the deadline was extended, the window is re-armed right away.
+    pub(crate) extended: bool,
+    /// Set when the run was cancelled.
+    pub(crate) cancelled: bool,
+}
+
+impl TimedRun {
+    pub(crate) fn new(deadline: Instant) -> Self {
+        Self {
+            deadline,
+            extended: false,
+            cancelled: false,
+        }
+    }
+
+    /// Returns the next step of the worker for the currently armed window.
+    pub(crate) fn next_step(&mut self, window: &DelayWindow, now: Instant) -> TimedRunStep {
+        if self.cancelled {
+            TimedRunStep::Close
+        } else if std::mem::take(&mut self.extended) || window.rearm_at.is_some_and(|at| at <= now)
+        {
+            TimedRunStep::Rearm(self.deadline)
+        } else if now >= window.until {
+            TimedRunStep::Finish
+        } else {
+            TimedRunStep::WaitUntil(window.rearm_at.unwrap_or(window.until))
+        }
+    }
+
+    /// Moves the deadline later, `false` if the new deadline cannot be represented.
+    pub(crate) fn extend(&mut self, by: Duration) -> bool {
+        let Some(deadline) = self.deadline.checked_add(by) else {
+            return false;
+        };
+        self.deadline = deadline;
+        self.extended = true;
+        true
+    }
+}
+
+/// The health of a dead-man heartbeat, see `Heartbeat::status` of the safe clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeartbeatStatus {
+    /// The number of heartbeats that reached the device.
+    pub beats: u64,
+    /// The number of heartbeats that failed.
+    pub missed: u64,
+    /// The number of heartbeats that failed since the last one that succeeded.
+    pub consecutive_missed: u32,
+    /// The time of the last heartbeat that reached the device.
+    pub last_beat: Option<Instant>,
+}
+
+impl HeartbeatStatus {
+    /// Returns `true` if a heartbeat reached the device within the given timeout.
+    ///
+    /// Otherwise the device has dropped the relays by itself.
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        self.last_beat
+            .is_some_and(|last_beat| last_beat.elapsed() < timeout)
+    }
+
+    /// Records the outcome of a heartbeat.
+    pub(crate) fn record(&mut self, succeeded: bool) {
+        if succeeded {
+            self.beats += 1;
+            self.consecutive_missed = 0;
+            self.last_beat = Some(Instant::now());
+        } else {
+            self.missed += 1;
+            self.consecutive_missed += 1;
+        }
+    }
+}
+
+/// The default time between two heartbeats for the given device timeout.
+///
+/// Three heartbeats fit into one timeout, so a single missed heartbeat does
+/// not drop the relays.
+pub(crate) fn heartbeat_interval(timeout: u8) -> Duration {
+    Duration::from_secs(timeout.max(1).into()) / 3
+}
+
+/// The shortest time between two heartbeats, which keeps the bus usable for other requests.
+pub(crate) const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Limits a heartbeat interval to [`MIN_HEARTBEAT_INTERVAL`] and half the device timeout.
+///
+/// At most half the timeout, one missed heartbeat is followed by another
+/// before the device drops the relays.
+pub(crate) fn clamp_heartbeat_interval(interval: Duration, timeout: u8) -> Duration {
+    interval.clamp(
+        MIN_HEARTBEAT_INTERVAL,
+        Duration::from_secs(timeout.max(1).into()) / 2,
+    )
+}
+
+/// Returns when the heartbeat after the one due at `due` is due.
+///
+/// After a heartbeat that took longer than the interval, the schedule restarts
+/// from `now` instead of catching up with a burst of heartbeats.
+pub(crate) fn next_beat(due: Instant, interval: Duration, now: Instant) -> Instant {
+    let next = due + interval;
+    if next < now {
+        now + interval
+    } else {
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_generations() {
+        let port = |index| proto::Port::try_from(index).unwrap();
+        let generations = PortGenerations::default();
+        let generation = generations.claim(port(2));
+        generations.touch(touched_ports(&proto::Command::Open(port(3))));
+        assert!(generations.is_current(port(2), generation));
+        generations.touch(touched_ports(&proto::Command::AllClose));
+        assert!(!generations.is_current(port(2), generation));
+        assert_eq!(
+            touched_ports(&proto::Command::SetAddress(proto::Address::default())),
+            proto::PortMask::NONE
+        );
+    }
+
+    #[test]
+    fn timed_run_windows() {
+        let secs = Duration::from_secs;
+        assert_eq!(DelayWindow::delay(secs(45 * 60)), 255);
+        assert_eq!(DelayWindow::delay(secs(255)), 255);
+        assert_eq!(DelayWindow::delay(Duration::from_millis(10_400)), 10);
+        assert_eq!(DelayWindow::delay(Duration::ZERO), 1);
+
+        // A 45 minute run re-arms 15 s before the first window ends.
+        let now = Instant::now();
+        let mut run = TimedRun::new(now + secs(45 * 60));
+        let mut window = DelayWindow::armed(1, now, 255, secs(45 * 60));
+        assert_eq!(window.rearm_at, Some(now + secs(240)));
+        assert_eq!(
+            run.next_step(&window, now),
+            TimedRunStep::WaitUntil(now + secs(240))
+        );
+        assert_eq!(
+            run.next_step(&window, now + secs(240)),
+            TimedRunStep::Rearm(run.deadline)
+        );
+
+        // A failed re-arm is retried while the window lasts.
+        assert!(window.retry(now + secs(240)));
+        assert!(!window.retry(now + secs(251)));
+
+        // The last window runs out at the deadline, unless it is extended.
+        let last = DelayWindow::armed(2, now, 100, secs(100));
+        assert_eq!(last.rearm_at, None);
+        assert_eq!(
+            run.next_step(&last, now),
+            TimedRunStep::WaitUntil(now + secs(100))
+        );
+        assert!(run.extend(Duration::ZERO));
+        assert_eq!(run.next_step(&last, now), TimedRunStep::Rearm(run.deadline));
+        assert_eq!(run.next_step(&last, now + secs(100)), TimedRunStep::Finish);
+
+        // Deadlines beyond the clock are rejected.
+        assert!(deadline_after(Duration::MAX).is_err());
+        let deadline = run.deadline;
+        assert!(!run.extend(Duration::MAX));
+        assert_eq!(run.deadline, deadline);
+        run.cancelled = true;
+        assert_eq!(run.next_step(&last, now), TimedRunStep::Close);
+    }
+
+    #[test]
+    fn heartbeat_status() {
+        assert_eq!(heartbeat_interval(30), Duration::from_secs(10));
+        assert_eq!(heartbeat_interval(0), Duration::from_secs(1) / 3);
+        assert_eq!(
+            clamp_heartbeat_interval(Duration::ZERO, 3),
+            MIN_HEARTBEAT_INTERVAL
+        );
+        assert_eq!(
+            clamp_heartbeat_interval(Duration::from_secs(3), 3),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            clamp_heartbeat_interval(Duration::from_secs(1), 0),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            clamp_heartbeat_interval(Duration::from_secs(1), 3),
+            Duration::from_secs(1)
+        );
+
+        // A late heartbeat restarts the schedule.
+        let due = Instant::now();
+        let interval = Duration::from_secs(1);
+        assert_eq!(
+            next_beat(due, interval, due + Duration::from_millis(100)),
+            due + interval
+        );
+        let late = due + Duration::from_secs(5);
+        assert_eq!(next_beat(due, interval, late), late + interval);
+
+        let mut status = HeartbeatStatus::default();
+        assert!(!status.is_alive(Duration::from_secs(30)));
+        status.record(false);
+        status.record(false);
+        assert_eq!(
+            (status.beats, status.missed, status.consecutive_missed),
+            (0, 2, 2)
+        );
+        status.record(true);
+        assert_eq!(
+            (status.beats, status.missed, status.consecutive_missed),
+            (1, 2, 0)
+        );
+        assert!(status.is_alive(Duration::from_secs(30)));
+        assert!(!status.is_alive(Duration::ZERO));
+    }
+}
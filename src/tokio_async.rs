@@ -33,9 +33,125 @@
 //! }
 //! ```
 
-use crate::{protocol as proto, tokio_common::Result};
+use crate::{
+    protocol as proto,
+    tokio_common::{ErrorContext, Result},
+};
+use std::future::Future;
 use tokio_modbus::prelude::{Reader, Writer};
 
+/// A transport-agnostic, asynchronous interface to an R413D08 relay module.
+///
+/// This trait abstracts over the concrete client, so application code can be
+/// written against it and work unchanged with the stateless [`R413D08`] functions,
+/// the [`crate::tokio_async_safe_client::SafeClient`], a simulator or a test mock.
+///
+/// All returned futures are `Send`, so generic code can be spawned on a
+/// multi-threaded runtime.
+///
+/// It is implemented for:
+/// * [`tokio_modbus::client::Context`], delegating to the stateless [`R413D08`] functions.
+/// * [`crate::tokio_async_safe_client::SafeClient`].
+///
+/// # Example
+///
+/// ```no_run
+/// use r413d08_lib::{protocol::Port, tokio_async::RelayController};
+///
+/// async fn open_first_two(relays: &mut impl RelayController) -> Result<(), Box<dyn std::error::Error>> {
+///     relays.set_port_open(Port::try_from(0)?).await?;
+///     relays.set_port_open(Port::try_from(1)?).await?;
+///     Ok(())
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let socket_addr = "127.0.0.1:502".parse()?;
+///     let mut ctx = tokio_modbus::client::tcp::connect(socket_addr).await?;
+///     open_first_two(&mut ctx).await?;
+///     Ok(())
+/// }
+/// ```
+pub trait RelayController: Send {
+    /// Reads the current status (Open/Close) of all [`proto::NUMBER_OF_PORTS`] ports.
+    fn read_ports(&mut self) -> impl Future<Output = Result<proto::PortStates>> + Send;
+
+    /// Sets the specified port to the **Open** state (activates relay).
+    fn set_port_open(&mut self, port: proto::Port) -> impl Future<Output = Result<()>> + Send;
+
+    /// Sets **all** ports to the **Open** state simultaneously.
+    fn set_all_open(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Sets the specified port to the **Close** state (deactivates relay).
+    fn set_port_close(&mut self, port: proto::Port) -> impl Future<Output = Result<()>> + Send;
+
+    /// Sets **all** ports to the **Close** state simultaneously.
+    fn set_all_close(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Toggles the current state of the specified port (Open -> Close, Close -> Open).
+    fn set_port_toggle(&mut self, port: proto::Port) -> impl Future<Output = Result<()>> + Send;
+
+    /// Latches the specified port (opens it and closes all others).
+    fn set_port_latch(&mut self, port: proto::Port) -> impl Future<Output = Result<()>> + Send;
+
+    /// Activates the specified port momentarily (Open for ~1 second, then Close).
+    fn set_port_momentary(&mut self, port: proto::Port) -> impl Future<Output = Result<()>> + Send;
+
+    /// Activates the specified port with a delayed close after `delay` seconds (0-255).
+    fn set_port_delay(
+        &mut self,
+        port: proto::Port,
+        delay: u8,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Reads the configured Modbus device address from the device itself.
+    fn read_address(&mut self) -> impl Future<Output = Result<proto::Address>> + Send;
+
+    /// Sets a new Modbus device address.
+    fn set_address(&mut self, address: proto::Address) -> impl Future<Output = Result<()>> + Send;
+
+    /// Writes the given [`proto::Command`] to the device.
+    ///
+    /// The default implementation dispatches to the method matching the command.
+    fn execute(&mut self, command: proto::Command) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            match command {
+                proto::Command::Open(port) => self.set_port_open(port).await,
+                proto::Command::Close(port) => self.set_port_close(port).await,
+                proto::Command::Toggle(port) => self.set_port_toggle(port).await,
+                proto::Command::Latch(port) => self.set_port_latch(port).await,
+                proto::Command::Momentary(port) => self.set_port_momentary(port).await,
+                proto::Command::Delay(port, delay) => self.set_port_delay(port, delay).await,
+                proto::Command::AllOpen => self.set_all_open().await,
+                proto::Command::AllClose => self.set_all_close().await,
+                proto::Command::SetAddress(address) => self.set_address(address).await,
+            }
+        }
+    }
+
+    /// Switches all ports to the given target mask with the fewest Modbus writes.
+    ///
+    /// The default implementation reads the current states and executes the
+    /// commands computed by [`proto::Command::plan_transition`].
+    fn apply_mask(&mut self, target: proto::PortMask) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            let current = proto::PortMask::from(self.read_ports().await?);
+            for command in proto::Command::plan_transition(current, target) {
+                self.execute(command).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Switches all ports to the given target states with the fewest Modbus writes.
+    fn apply_states(
+        &mut self,
+        target: proto::PortStates,
+    ) -> impl Future<Output = Result<()>> + Send {
+        self.apply_mask(target.into())
+    }
+}
+
 /// An asynchronous client for interacting with an R413D08 relay module over Modbus.
 ///
 /// This client wraps a [`tokio_modbus::client::Context`] and provides
@@ -57,8 +173,11 @@ impl R413D08 {
         }
     }
     /// Helper function to read holding registers and decode them into a specific type.
+    ///
+    /// Errors are annotated with the given operation name and the register address.
     async fn read_and_decode<T, F>(
         ctx: &mut tokio_modbus::client::Context,
+        operation: &str,
         address: u16,
         quantity: u16,
         decoder: F,
@@ -66,9 +185,30 @@ impl R413D08 {
     where
         F: FnOnce(&[u16]) -> Result<T>,
     {
-        decoder(&Self::map_tokio_result(
-            ctx.read_holding_registers(address, quantity).await,
-        )?)
+        Self::map_tokio_result(ctx.read_holding_registers(address, quantity).await)
+            .and_then(|words| decoder(&words))
+            .map_err(|err| err.in_context(ErrorContext::read(operation, address)))
+    }
+
+    /// Writes the given [`proto::Command`] to the device.
+    ///
+    /// All `set_*` functions are shorthands for this function with the
+    /// corresponding command.
+    ///
+    /// # Arguments
+    ///
+    /// * `command`: The [`proto::Command`] to execute.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
+    pub async fn execute(
+        ctx: &mut tokio_modbus::client::Context,
+        command: proto::Command,
+    ) -> Result<()> {
+        let (register, value) = command.encode();
+        Self::map_tokio_result(ctx.write_single_register(register, value).await)
+            .map_err(|err| err.in_context(ErrorContext::command(&command)))
     }
 
     /// Reads the current status (Open/Close) of all [`proto::NUMBER_OF_PORTS`] ports.
@@ -81,6 +221,7 @@ impl R413D08 {
     pub async fn read_ports(ctx: &mut tokio_modbus::client::Context) -> Result<proto::PortStates> {
         Self::read_and_decode(
             ctx,
+            "read ports",
             proto::PortStates::ADDRESS,
             proto::PortStates::QUANTITY,
             |words| Ok(proto::PortStates::decode_from_holding_registers(words)),
@@ -101,13 +242,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(
-                port.address_for_write_register(),
-                proto::Port::REG_DATA_SET_PORT_OPEN,
-            )
-            .await,
-        )
+        Self::execute(ctx, proto::Command::Open(port)).await
     }
 
     /// Sets **all** ports to the **Open** state simultaneously.
@@ -116,13 +251,7 @@ impl R413D08 {
     ///
     /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
     pub async fn set_all_open(ctx: &mut tokio_modbus::client::Context) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(
-                proto::PortsAll::ADDRESS,
-                proto::PortsAll::REG_DATA_SET_ALL_OPEN,
-            )
-            .await,
-        )
+        Self::execute(ctx, proto::Command::AllOpen).await
     }
 
     /// Sets the specified port to the **Close** state (deactivates relay).
@@ -138,13 +267,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(
-                port.address_for_write_register(),
-                proto::Port::REG_DATA_SET_PORT_CLOSE,
-            )
-            .await,
-        )
+        Self::execute(ctx, proto::Command::Close(port)).await
     }
 
     /// Sets **all** ports to the **Close** state simultaneously.
@@ -153,13 +276,7 @@ impl R413D08 {
     ///
     /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
     pub async fn set_all_close(ctx: &mut tokio_modbus::client::Context) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(
-                proto::PortsAll::ADDRESS,
-                proto::PortsAll::REG_DATA_SET_ALL_CLOSE,
-            )
-            .await,
-        )
+        Self::execute(ctx, proto::Command::AllClose).await
     }
 
     /// Toggles the current state of the specified port (Open -> Close, Close -> Open). Also called "Self-locking".
@@ -175,13 +292,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(
-                port.address_for_write_register(),
-                proto::Port::REG_DATA_SET_PORT_TOGGLE,
-            )
-            .await,
-        )
+        Self::execute(ctx, proto::Command::Toggle(port)).await
     }
 
     /// Latches the specified port (Inter-locking): Sets the given `port` to Open and all *other* ports to Close.
@@ -197,13 +308,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(
-                port.address_for_write_register(),
-                proto::Port::REG_DATA_SET_PORT_LATCH,
-            )
-            .await,
-        )
+        Self::execute(ctx, proto::Command::Latch(port)).await
     }
 
     /// Activates the specified port momentarily (Non-locking): Opens the port for ~1 second, then automatically Closes.
@@ -219,13 +324,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(
-                port.address_for_write_register(),
-                proto::Port::REG_DATA_SET_PORT_MOMENTARY,
-            )
-            .await,
-        )
+        Self::execute(ctx, proto::Command::Momentary(port)).await
     }
 
     /// Initiates a delayed action on the specified port (typically Open -> Delay -> Close).
@@ -243,13 +342,50 @@ impl R413D08 {
         port: proto::Port,
         delay: u8,
     ) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(
-                port.address_for_write_register(),
-                proto::Port::encode_delay_for_write_register(delay),
-            )
-            .await,
-        )
+        Self::execute(ctx, proto::Command::Delay(port, delay)).await
+    }
+
+    /// Switches all ports to the given target mask with the fewest Modbus writes.
+    ///
+    /// Reads the current port states and executes the command sequence computed by
+    /// [`proto::Command::plan_transition`], which uses the all-open, all-close and
+    /// latch commands where they save writes.
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: The ports that should be open; all others are closed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
+    /// The ports may then be left in an intermediate state.
+    pub async fn apply_mask(
+        ctx: &mut tokio_modbus::client::Context,
+        target: proto::PortMask,
+    ) -> Result<()> {
+        let current = proto::PortMask::from(Self::read_ports(ctx).await?);
+        for command in proto::Command::plan_transition(current, target) {
+            Self::execute(ctx, command).await?;
+        }
+        Ok(())
+    }
+
+    /// Switches all ports to the given target states with the fewest Modbus writes.
+    ///
+    /// See [`R413D08::apply_mask`] for details.
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: The [`proto::PortStates`] every port should have afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
+    pub async fn apply_states(
+        ctx: &mut tokio_modbus::client::Context,
+        target: proto::PortStates,
+    ) -> Result<()> {
+        Self::apply_mask(ctx, target.into()).await
     }
 
     /// Reads the configured Modbus device address from the device itself.
@@ -297,6 +433,7 @@ impl R413D08 {
     pub async fn read_address(ctx: &mut tokio_modbus::client::Context) -> Result<proto::Address> {
         Self::read_and_decode(
             ctx,
+            "read address",
             proto::Address::ADDRESS,
             proto::Address::QUANTITY,
             |words| Ok(proto::Address::decode_from_holding_registers(words)?),
@@ -342,9 +479,60 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::Context,
         address: proto::Address,
     ) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(proto::Address::ADDRESS, address.encode_for_write_register())
-                .await,
-        )
+        Self::execute(ctx, proto::Command::SetAddress(address)).await
+    }
+}
+
+impl RelayController for tokio_modbus::client::Context {
+    async fn read_ports(&mut self) -> Result<proto::PortStates> {
+        R413D08::read_ports(self).await
+    }
+
+    async fn set_port_open(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_open(self, port).await
+    }
+
+    async fn set_all_open(&mut self) -> Result<()> {
+        R413D08::set_all_open(self).await
+    }
+
+    async fn set_port_close(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_close(self, port).await
+    }
+
+    async fn set_all_close(&mut self) -> Result<()> {
+        R413D08::set_all_close(self).await
+    }
+
+    async fn set_port_toggle(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_toggle(self, port).await
+    }
+
+    async fn set_port_latch(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_latch(self, port).await
+    }
+
+    async fn set_port_momentary(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_momentary(self, port).await
+    }
+
+    async fn set_port_delay(&mut self, port: proto::Port, delay: u8) -> Result<()> {
+        R413D08::set_port_delay(self, port, delay).await
+    }
+
+    async fn read_address(&mut self) -> Result<proto::Address> {
+        R413D08::read_address(self).await
+    }
+
+    async fn set_address(&mut self, address: proto::Address) -> Result<()> {
+        R413D08::set_address(self, address).await
+    }
+
+    async fn execute(&mut self, command: proto::Command) -> Result<()> {
+        R413D08::execute(self, command).await
+    }
+
+    async fn apply_mask(&mut self, target: proto::PortMask) -> Result<()> {
+        R413D08::apply_mask(self, target).await
     }
 }
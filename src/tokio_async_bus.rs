@@ -0,0 +1,320 @@
+//! Provides a thread-safe, asynchronous manager for several R413D08 modules on one bus.
+//!
+//! This module defines the [`Bus`] struct, which owns a single `tokio-modbus`
+//! `Context` (typically one RS485 serial port) and hands out a [`BusDevice`]
+//! handle per slave address. All handles share the context through a
+//! `tokio::sync::Mutex`, so the traffic of all devices is serialized. Each
+//! transaction switches the slave address under the lock and keeps the silent
+//! interval between frames required by the Modbus RTU specification.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use r413d08_lib::{
+//!     protocol::{Address, Port},
+//!     tokio_common::serial_port_builder,
+//!     tokio_async_bus::Bus,
+//! };
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let ctx = tokio_modbus::client::rtu::attach(tokio_serial::SerialStream::open(
+//!         &serial_port_builder("/dev/ttyUSB0"),
+//!     )?);
+//!     let bus = Bus::new(ctx);
+//!
+//!     let pumps = bus.device(Address::try_from(1)?);
+//!     let valves = bus.device(Address::try_from(2)?);
+//!     pumps.set_port_open(Port::try_from(0)?).await?;
+//!     println!("Valves: {}", valves.read_ports().await?);
+//!     Ok(())
+//! }
+//! ```
+
+use crate::{
+    protocol as proto,
+    tokio_async::{RelayController, R413D08},
+    tokio_common::{Result, INTER_FRAME_DELAY},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+use tokio_modbus::{client::Context, prelude::SlaveContext, Slave};
+
+/// The shared state of a bus: the context and the time of the last transaction.
+struct BusState {
+    ctx: Context,
+    inter_frame_delay: Duration,
+    last_transaction: Option<Instant>,
+    /// The slave of the current transaction.
+    slave: Slave,
+}
+
+impl BusState {
+    /// Waits for the inter-frame delay and addresses the context to the given slave.
+    async fn begin(&mut self, slave: Slave) {
+        if let Some(last) = self.last_transaction {
+            tokio::time::sleep_until(last + self.inter_frame_delay).await;
+        }
+        self.ctx.set_slave(slave);
+        self.slave = slave;
+    }
+
+    /// Records the end of a transaction and passes its result through.
+    ///
+    /// Errors are annotated with the slave of the transaction.
+    fn end<T>(&mut self, result: Result<T>) -> Result<T> {
+        self.last_transaction = Some(Instant::now());
+        result.map_err(|err| err.with_slave(self.slave))
+    }
+}
+
+/// A thread-safe, asynchronous manager for several R413D08 modules sharing one bus.
+///
+/// The bus owns the Modbus context and can be cheaply cloned. Use [`Bus::device`]
+/// to obtain a handle for the device with a given slave address.
+#[derive(Clone)]
+pub struct Bus {
+    state: Arc<Mutex<BusState>>,
+}
+
+impl Bus {
+    /// Creates a new `Bus` using the RTU [`INTER_FRAME_DELAY`] between transactions.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx`: An asynchronous Modbus client context, already connected.
+    pub fn new(ctx: Context) -> Self {
+        Self::with_inter_frame_delay(ctx, INTER_FRAME_DELAY)
+    }
+
+    /// Creates a new `Bus` with a custom silent interval between two transactions.
+    ///
+    /// A longer delay can help with slow devices or RS485 adapters that need
+    /// time to switch the line direction.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx`: An asynchronous Modbus client context, already connected.
+    /// * `inter_frame_delay`: The minimum time between the end of one transaction
+    ///   and the start of the next one.
+    pub fn with_inter_frame_delay(ctx: Context, inter_frame_delay: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(BusState {
+                ctx,
+                inter_frame_delay,
+                last_transaction: None,
+                slave: Slave::broadcast(),
+            })),
+        }
+    }
+
+    /// Returns a handle for the device with the given slave address.
+    ///
+    /// Handles are cheap to create and clone, and can be moved between tasks.
+    pub fn device(&self, address: proto::Address) -> BusDevice {
+        BusDevice {
+            state: self.state.clone(),
+            address: Arc::new(std::sync::Mutex::new(address)),
+        }
+    }
+}
+
+/// A handle for one R413D08 module on a shared [`Bus`].
+///
+/// Every operation locks the bus, switches the context to this device's slave
+/// address and performs the transaction. Clones of a handle share the address,
+/// so [`BusDevice::set_address`] updates all of them.
+#[derive(Clone)]
+pub struct BusDevice {
+    state: Arc<Mutex<BusState>>,
+    address: Arc<std::sync::Mutex<proto::Address>>,
+}
+
+impl BusDevice {
+    /// Returns the slave address this handle communicates with.
+    pub fn address(&self) -> proto::Address {
+        *self.address.lock().unwrap()
+    }
+
+    /// Locks the bus and prepares the context for a transaction with this device.
+    async fn begin(&self) -> MutexGuard<'_, BusState> {
+        let mut state = self.state.lock().await;
+        state.begin(Slave(*self.address())).await;
+        state
+    }
+
+    /// Writes the given [`proto::Command`] to the device.
+    ///
+    /// A [`proto::Command::SetAddress`] command updates the handle's address
+    /// just like [`BusDevice::set_address`].
+    pub async fn execute(&self, command: proto::Command) -> Result<()> {
+        if let proto::Command::SetAddress(address) = command {
+            return self.set_address(address).await;
+        }
+        let mut state = self.begin().await;
+        let result = R413D08::execute(&mut state.ctx, command).await;
+        state.end(result)
+    }
+
+    /// Reads the current status (Open/Close) of all ports.
+    pub async fn read_ports(&self) -> Result<proto::PortStates> {
+        let mut state = self.begin().await;
+        let result = R413D08::read_ports(&mut state.ctx).await;
+        state.end(result)
+    }
+
+    /// Sets the specified port to the **Open** state.
+    pub async fn set_port_open(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Open(port)).await
+    }
+
+    /// Sets **all** ports to the **Open** state.
+    pub async fn set_all_open(&self) -> Result<()> {
+        self.execute(proto::Command::AllOpen).await
+    }
+
+    /// Sets the specified port to the **Close** state.
+    pub async fn set_port_close(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Close(port)).await
+    }
+
+    /// Sets **all** ports to the **Close** state.
+    pub async fn set_all_close(&self) -> Result<()> {
+        self.execute(proto::Command::AllClose).await
+    }
+
+    /// Toggles the current state of the specified port.
+    pub async fn set_port_toggle(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Toggle(port)).await
+    }
+
+    /// Latches the specified port (opens it and closes all others).
+    pub async fn set_port_latch(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Latch(port)).await
+    }
+
+    /// Activates the specified port momentarily.
+    pub async fn set_port_momentary(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Momentary(port)).await
+    }
+
+    /// Activates the specified port with a delayed close.
+    pub async fn set_port_delay(&self, port: proto::Port, delay: u8) -> Result<()> {
+        self.execute(proto::Command::Delay(port, delay)).await
+    }
+
+    /// Switches all ports to the given target mask with the fewest Modbus writes.
+    ///
+    /// The bus stays locked for the whole sequence, so no other device's
+    /// traffic interleaves.
+    pub async fn apply_mask(&self, target: proto::PortMask) -> Result<()> {
+        let slave = Slave(*self.address());
+        let mut state = self.begin().await;
+        let result = R413D08::read_ports(&mut state.ctx).await;
+        let current = proto::PortMask::from(state.end(result)?);
+        for command in proto::Command::plan_transition(current, target) {
+            state.begin(slave).await;
+            let result = R413D08::execute(&mut state.ctx, command).await;
+            state.end(result)?;
+        }
+        Ok(())
+    }
+
+    /// Switches all ports to the given target states with the fewest Modbus writes.
+    pub async fn apply_states(&self, target: proto::PortStates) -> Result<()> {
+        self.apply_mask(target.into()).await
+    }
+
+    /// Reads the configured Modbus device address from the device itself.
+    pub async fn read_address(&self) -> Result<proto::Address> {
+        let mut state = self.begin().await;
+        let result = R413D08::read_address(&mut state.ctx).await;
+        state.end(result)
+    }
+
+    /// Sets a new Modbus device address.
+    ///
+    /// Upon success, the handle (and all its clones) communicate with the
+    /// device using the new address.
+    pub async fn set_address(&self, address: proto::Address) -> Result<()> {
+        let mut state = self.begin().await;
+        let result = R413D08::set_address(&mut state.ctx, address).await;
+        state.end(result)?;
+        *self.address.lock().unwrap() = address;
+        Ok(())
+    }
+}
+
+impl RelayController for BusDevice {
+    async fn read_ports(&mut self) -> Result<proto::PortStates> {
+        BusDevice::read_ports(self).await
+    }
+
+    async fn set_port_open(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_open(self, port).await
+    }
+
+    async fn set_all_open(&mut self) -> Result<()> {
+        BusDevice::set_all_open(self).await
+    }
+
+    async fn set_port_close(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_close(self, port).await
+    }
+
+    async fn set_all_close(&mut self) -> Result<()> {
+        BusDevice::set_all_close(self).await
+    }
+
+    async fn set_port_toggle(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_toggle(self, port).await
+    }
+
+    async fn set_port_latch(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_latch(self, port).await
+    }
+
+    async fn set_port_momentary(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_momentary(self, port).await
+    }
+
+    async fn set_port_delay(&mut self, port: proto::Port, delay: u8) -> Result<()> {
+        BusDevice::set_port_delay(self, port, delay).await
+    }
+
+    async fn read_address(&mut self) -> Result<proto::Address> {
+        BusDevice::read_address(self).await
+    }
+
+    async fn set_address(&mut self, address: proto::Address) -> Result<()> {
+        BusDevice::set_address(self, address).await
+    }
+
+    async fn execute(&mut self, command: proto::Command) -> Result<()> {
+        BusDevice::execute(self, command).await
+    }
+
+    async fn apply_mask(&mut self, target: proto::PortMask) -> Result<()> {
+        BusDevice::apply_mask(self, target).await
+    }
+}
+
+#[cfg(all(test, feature = "simulator", feature = "tokio-tcp"))]
+mod tests {
+    use super::*;
+    use crate::simulator::testing::Fixture;
+
+    #[tokio::test]
+    async fn async_bus_device_with_broadcast_address() {
+        let fixture = Fixture::new();
+        let broadcast = Bus::new(fixture.async_context().await).device(proto::Address::BROADCAST);
+        assert_eq!(broadcast.address(), proto::Address::BROADCAST);
+        assert_eq!(
+            broadcast.read_address().await.unwrap(),
+            proto::Address::default()
+        );
+    }
+}
@@ -6,11 +6,43 @@
 //! that all operations are serialized, making it safe to share across multiple
 //! async tasks (e.g., using an `Arc<SafeClient>`).
 
-use crate::{protocol as proto, tokio_async::R413D08, tokio_common::Result};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use crate::{
+    protocol as proto,
+    timers::{self, DelayWindow, PortGenerations, TimedRun, TimedRunStep},
+    tokio_async::{RelayController, R413D08},
+    tokio_common::{
+        self, ChangeDetector, Error, HeartbeatStatus, Result, RetryPolicy, WatchEvent,
+        MIN_POLL_INTERVAL,
+    },
+};
+use futures_util::Stream;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, Mutex, Notify};
+use tokio::time::MissedTickBehavior;
 use tokio_modbus::{client::Context, prelude::SlaveContext, Slave};
 
+/// A boxed future, as returned by a [`Connect`] function.
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A function that establishes a new connection, see [`SafeClient::with_reconnect`].
+type Connect = dyn Fn() -> BoxFuture<'static, std::io::Result<Context>> + Send + Sync;
+
+/// The state required to rebuild a broken connection.
+struct Reconnect {
+    connect: Box<Connect>,
+    /// Set after a transport failure, the context is rebuilt before the next use.
+    stale: AtomicBool,
+}
+
 /// A thread-safe, asynchronous client for an R413D08 relay module.
 ///
 /// This client encapsulates a [`tokio_modbus::client::Context`] within an
@@ -22,6 +54,13 @@ use tokio_modbus::{client::Context, prelude::SlaveContext, Slave};
 /// the client's internal slave ID after successfully changing the device's
 /// Modbus address, preventing desynchronization errors.
 ///
+/// Created with [`SafeClient::with_reconnect`], the client transparently
+/// rebuilds its connection after a transport failure and retries transient
+/// errors according to its [`RetryPolicy`].
+///
+/// The client implements [`crate::tokio_async::RelayController`], so it can be passed
+/// to code written against that trait.
+///
 /// # Example
 ///
 /// ```no_run
@@ -54,18 +93,89 @@ use tokio_modbus::{client::Context, prelude::SlaveContext, Slave};
 #[derive(Clone)]
 pub struct SafeClient {
     ctx: Arc<Mutex<Context>>,
+    /// The slave ID of the device if known, applied before every operation.
+    slave: Arc<std::sync::Mutex<Option<Slave>>>,
+    reconnect: Option<Arc<Reconnect>>,
+    retry_policy: RetryPolicy,
+    /// The commands written to each port, see [`SafeClient::pulse`].
+    generations: Arc<PortGenerations>,
 }
 
 impl SafeClient {
     /// Creates a new `SafeClient` instance.
     ///
+    /// The client does not retry failed operations, see [`SafeClient::with_retry_policy`].
+    ///
     /// # Arguments
     ///
     /// * `ctx`: An asynchronous Modbus client context, already connected.
     pub fn new(ctx: Context) -> Self {
-        Self {
+        Self::from_shared(Arc::new(Mutex::new(ctx)))
+    }
+
+    /// Creates a new `SafeClient` that rebuilds its connection after transport failures.
+    ///
+    /// The `connect` function is called once to establish the initial connection
+    /// and again whenever a transport failure (e.g., a replugged USB-RS485
+    /// adapter or a dropped TCP connection) left the context unusable. It must
+    /// return a context configured for the device, including its slave ID.
+    ///
+    /// The client uses the default [`RetryPolicy`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the initial connection cannot be established.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use r413d08_lib::tokio_async_safe_client::SafeClient;
+    /// use tokio_modbus::client::tcp;
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let socket_addr = "127.0.0.1:502".parse()?;
+    ///     let client = SafeClient::with_reconnect(move || tcp::connect(socket_addr)).await?;
+    ///     println!("Port status: {}", client.read_ports().await?);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn with_reconnect<F, Fut>(connect: F) -> Result<Self>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<Context>> + Send + 'static,
+    {
+        let ctx = connect().await.map_err(tokio_modbus::Error::Transport)?;
+        Ok(Self {
             ctx: Arc::new(Mutex::new(ctx)),
-        }
+            slave: Arc::default(),
+            reconnect: Some(Arc::new(Reconnect {
+                connect: Box::new(move || Box::pin(connect())),
+                stale: AtomicBool::new(false),
+            })),
+            retry_policy: RetryPolicy::default(),
+            generations: Arc::default(),
+        })
+    }
+
+    /// Sets the slave address of the device.
+    ///
+    /// The client then addresses every request to this device, even if the
+    /// connection was rebuilt or the shared context was used for another
+    /// device in the meantime. The address is also reported in errors.
+    pub fn with_slave(self, address: proto::Address) -> Self {
+        *self.slave.lock().unwrap() = Some(Slave(*address));
+        self
+    }
+
+    /// Sets the policy for retrying operations that failed with a transient error.
+    ///
+    /// Operations that are not idempotent ([`SafeClient::set_port_toggle`] and
+    /// [`SafeClient::set_address`]) are never retried, since the device may have
+    /// executed the command although the response got lost.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
     }
 
     /// Creates a new `SafeClient` from an existing `Arc<Mutex<Context>>`.
@@ -73,69 +183,360 @@ impl SafeClient {
     /// This allows multiple `SafeClient` instances to share the exact same
     /// underlying connection context.
     pub fn from_shared(ctx: Arc<Mutex<Context>>) -> Self {
-        Self { ctx }
+        Self {
+            ctx,
+            slave: Arc::default(),
+            reconnect: None,
+            retry_policy: RetryPolicy::NONE,
+            generations: Arc::default(),
+        }
     }
 
     /// Clones and returns the underlying `Arc<Mutex<Context>>`.
     ///
     /// This allows the shared context to be used by other parts of an
     /// application that may need direct access to the Modbus context.
+    /// A reconnect replaces the context inside the mutex, so the shared
+    /// context stays valid.
     pub fn clone_shared(&self) -> Arc<Mutex<Context>> {
         self.ctx.clone()
     }
 
+    /// Runs an operation on the locked context, reconnecting and retrying as configured.
+    async fn with_context<T>(
+        &self,
+        idempotent: bool,
+        mut operation: impl for<'a> FnMut(&'a mut Context) -> BoxFuture<'a, Result<T>> + Send,
+    ) -> Result<T> {
+        let mut guard = self.ctx.lock().await;
+        let mut retry = 0;
+        loop {
+            let slave = *self.slave.lock().unwrap();
+            let result = match self.reconnect_if_stale(&mut guard).await {
+                Ok(()) => {
+                    if let Some(slave) = slave {
+                        guard.set_slave(slave);
+                    }
+                    operation(&mut guard).await
+                }
+                Err(err) => Err(err),
+            };
+            let result = result.map_err(|err| match slave {
+                Some(slave) => err.with_slave(slave),
+                None => err,
+            });
+            match result {
+                Err(err) if err.is_transient() => {
+                    if let Some(reconnect) = &self.reconnect {
+                        reconnect.stale.store(true, Ordering::Relaxed);
+                    }
+                    if !idempotent || retry >= self.retry_policy.max_retries {
+                        return Err(err);
+                    }
+                    tokio::time::sleep(self.retry_policy.backoff(retry)).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Replaces the context with a new connection if the last one failed.
+    async fn reconnect_if_stale(&self, ctx: &mut Context) -> Result<()> {
+        let Some(reconnect) = &self.reconnect else {
+            return Ok(());
+        };
+        if reconnect.stale.load(Ordering::Relaxed) {
+            *ctx = (reconnect.connect)()
+                .await
+                .map_err(tokio_modbus::Error::Transport)?;
+            reconnect.stale.store(false, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Writes the given [`proto::Command`] to the device.
+    ///
+    /// A [`proto::Command::SetAddress`] command updates the client's slave ID
+    /// just like [`SafeClient::set_address`].
+    pub async fn execute(&self, command: proto::Command) -> Result<()> {
+        if let proto::Command::SetAddress(address) = command {
+            return self.set_address(address).await;
+        }
+        let idempotent = !matches!(command, proto::Command::Toggle(_));
+        let generations = self.generations.clone();
+        self.with_context(idempotent, move |ctx| {
+            let generations = generations.clone();
+            Box::pin(async move {
+                R413D08::execute(ctx, command).await?;
+                generations.touch(timers::touched_ports(&command));
+                Ok(())
+            })
+        })
+        .await
+    }
+
     /// Reads the current status (Open/Close) of all ports.
     pub async fn read_ports(&self) -> Result<proto::PortStates> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::read_ports(&mut guard).await
+        self.with_context(true, |ctx| Box::pin(R413D08::read_ports(ctx)))
+            .await
     }
 
     /// Sets the specified port to the **Open** state.
     pub async fn set_port_open(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_port_open(&mut guard, port).await
+        self.execute(proto::Command::Open(port)).await
     }
 
     /// Sets **all** ports to the **Open** state.
     pub async fn set_all_open(&self) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_all_open(&mut guard).await
+        self.execute(proto::Command::AllOpen).await
     }
 
     /// Sets the specified port to the **Close** state.
     pub async fn set_port_close(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_port_close(&mut guard, port).await
+        self.execute(proto::Command::Close(port)).await
     }
 
     /// Sets **all** ports to the **Close** state.
     pub async fn set_all_close(&self) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_all_close(&mut guard).await
+        self.execute(proto::Command::AllClose).await
     }
 
     /// Toggles the current state of the specified port.
     pub async fn set_port_toggle(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_port_toggle(&mut guard, port).await
+        self.execute(proto::Command::Toggle(port)).await
     }
 
     /// Latches the specified port (opens it and closes all others).
     pub async fn set_port_latch(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_port_latch(&mut guard, port).await
+        self.execute(proto::Command::Latch(port)).await
     }
 
     /// Activates the specified port momentarily.
     pub async fn set_port_momentary(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_port_momentary(&mut guard, port).await
+        self.execute(proto::Command::Momentary(port)).await
     }
 
     /// Activates the specified port with a delayed close.
     pub async fn set_port_delay(&self, port: proto::Port, delay: u8) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_port_delay(&mut guard, port, delay).await
+        self.execute(proto::Command::Delay(port, delay)).await
+    }
+
+    /// Opens the specified port for the given duration.
+    ///
+    /// A whole number of seconds from 1 to 255 uses the delay command of the
+    /// device ([`SafeClient::set_port_delay`]) and completes right away. Any
+    /// other duration, e.g. 350 ms or 2.5 s, is timed in software: the port is
+    /// opened, and the returned future completes after closing it again. The
+    /// port is closed by a task of its own, so it is closed even if the future
+    /// is dropped while waiting.
+    ///
+    /// The client is not locked while waiting, so other tasks can use it in
+    /// the meantime. If any of them sends a command affecting the port through
+    /// this client or one of its clones (including another pulse), the later
+    /// command wins and the port is not closed by this pulse.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use r413d08_lib::{protocol::Port, tokio_async_safe_client::SafeClient};
+    /// use std::time::Duration;
+    ///
+    /// # async fn example(client: SafeClient) -> Result<(), Box<dyn std::error::Error>> {
+    /// // Run the dosing pump on port 2 for 350 ms
+    /// client.pulse(Port::try_from(2)?, Duration::from_millis(350)).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn pulse(&self, port: proto::Port, duration: Duration) -> Result<()> {
+        if let Some(delay) = tokio_common::device_delay(duration) {
+            return self.set_port_delay(port, delay).await;
+        }
+        let generations = self.generations.clone();
+        let (generation, opened) = self
+            .with_context(true, move |ctx| {
+                let generations = generations.clone();
+                Box::pin(async move {
+                    R413D08::set_port_open(ctx, port).await?;
+                    Ok((generations.claim(port), tokio::time::Instant::now()))
+                })
+            })
+            .await?;
+        let client = self.clone();
+        let close = tokio::spawn(async move {
+            tokio::time::sleep_until(opened + duration).await;
+            let generations = client.generations.clone();
+            client
+                .with_context(true, move |ctx| {
+                    let generations = generations.clone();
+                    Box::pin(async move {
+                        if generations.is_current(port, generation) {
+                            R413D08::set_port_close(ctx, port).await?;
+                            generations.touch(port.into());
+                        }
+                        Ok(())
+                    })
+                })
+                .await
+        });
+        close
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+
+    /// Opens the specified port until the given duration has passed, even beyond 255 seconds.
+    ///
+    /// The delay command of the device counts at most 255 seconds. For a longer
+    /// duration, e.g. a 45 minute irrigation run, a background task chains delay
+    /// commands and re-arms each one shortly before it runs out. The port is
+    /// always closed by the device itself, so if this process dies, the relay
+    /// still turns off within 255 seconds. The duration has a resolution of one
+    /// second, use [`SafeClient::pulse`] for shorter, more precise durations.
+    ///
+    /// The first delay command is sent before this call returns. The returned
+    /// [`TimedPort`] extends or cancels the run, dropping it leaves the run
+    /// going. A later command affecting the port through this client or one of
+    /// its clones ends the run when it is re-armed next, and the port keeps the
+    /// state set by that command.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the first delay command fails, or if the duration
+    /// reaches beyond the times the clock can represent.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use r413d08_lib::{protocol::Port, tokio_async_safe_client::SafeClient};
+    /// use std::time::Duration;
+    ///
+    /// # async fn example(client: SafeClient) -> Result<(), Box<dyn std::error::Error>> {
+    /// // Water zone 4 for 45 minutes, then another 10 minutes
+    /// let run = client
+    ///     .set_port_for(Port::try_from(4)?, Duration::from_secs(45 * 60))
+    ///     .await?;
+    /// run.extend(Duration::from_secs(10 * 60));
+    /// run.wait().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn set_port_for(&self, port: proto::Port, duration: Duration) -> Result<TimedPort> {
+        let deadline = timers::deadline_after(duration)?;
+        let window = self
+            .arm_window(port, deadline, None)
+            .await?
+            .expect("a new run is never superseded");
+        let shared = Arc::new((
+            std::sync::Mutex::new(TimedRun::new(deadline)),
+            Notify::new(),
+        ));
+        let worker = tokio::spawn(self.clone().keep_armed(port, window, shared.clone()));
+        Ok(TimedPort {
+            port,
+            shared,
+            worker,
+        })
+    }
+
+    /// Arms the next delay window of a timed run, `None` if the run was superseded.
+    async fn arm_window(
+        &self,
+        port: proto::Port,
+        deadline: Instant,
+        generation: Option<u64>,
+    ) -> Result<Option<DelayWindow>> {
+        let generations = self.generations.clone();
+        self.with_context(true, move |ctx| {
+            let generations = generations.clone();
+            Box::pin(async move {
+                if generation.is_some_and(|generation| !generations.is_current(port, generation)) {
+                    return Ok(None);
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let delay = DelayWindow::delay(remaining);
+                R413D08::set_port_delay(ctx, port, delay).await?;
+                let generation = generations.claim(port);
+                Ok(Some(DelayWindow::armed(
+                    generation,
+                    Instant::now(),
+                    delay,
+                    remaining,
+                )))
+            })
+        })
+        .await
+    }
+
+    /// Keeps re-arming the delay windows of a timed run until it is over.
+    async fn keep_armed(
+        self,
+        port: proto::Port,
+        mut window: DelayWindow,
+        shared: Arc<(std::sync::Mutex<TimedRun>, Notify)>,
+    ) -> Result<()> {
+        let (run, wake) = &*shared;
+        loop {
+            let now = Instant::now();
+            let step = run.lock().unwrap().next_step(&window, now);
+            match step {
+                TimedRunStep::Close => {
+                    let generations = self.generations.clone();
+                    return self
+                        .with_context(true, move |ctx| {
+                            let generations = generations.clone();
+                            Box::pin(async move {
+                                if generations.is_current(port, window.generation) {
+                                    R413D08::set_port_close(ctx, port).await?;
+                                    generations.touch(port.into());
+                                }
+                                Ok(())
+                            })
+                        })
+                        .await;
+                }
+                TimedRunStep::Rearm(deadline) => {
+                    match self
+                        .arm_window(port, deadline, Some(window.generation))
+                        .await
+                    {
+                        Ok(Some(next)) => window = next,
+                        Ok(None) => return Ok(()),
+                        Err(err) if !window.retry(now) => return Err(err),
+                        Err(_) => {}
+                    }
+                }
+                TimedRunStep::Finish => return Ok(()),
+                TimedRunStep::WaitUntil(at) => {
+                    let at = tokio::time::Instant::from_std(at);
+                    let _ = tokio::time::timeout_at(at, wake.notified()).await;
+                }
+            }
+        }
+    }
+
+    /// Switches all ports to the given target mask with the fewest Modbus writes.
+    ///
+    /// The current states are read and the commands are written while holding
+    /// the lock, so no other operation can interleave. A retry starts over by
+    /// reading the current states again.
+    pub async fn apply_mask(&self, target: proto::PortMask) -> Result<()> {
+        let generations = self.generations.clone();
+        self.with_context(true, move |ctx| {
+            let generations = generations.clone();
+            Box::pin(async move {
+                R413D08::apply_mask(ctx, target).await?;
+                generations.touch(proto::PortMask::ALL);
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    /// Switches all ports to the given target states with the fewest Modbus writes.
+    ///
+    /// See [`SafeClient::apply_mask`] for details.
+    pub async fn apply_states(&self, target: proto::PortStates) -> Result<()> {
+        self.apply_mask(target.into()).await
     }
 
     /// Reads the configured Modbus device address.
@@ -143,8 +544,8 @@ impl SafeClient {
     /// It's recommended to use the broadcast address for this operation,
     /// ensuring only one device is on the bus.
     pub async fn read_address(&self) -> Result<proto::Address> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::read_address(&mut guard).await
+        self.with_context(true, |ctx| Box::pin(R413D08::read_address(ctx)))
+            .await
     }
 
     /// Sets a new Modbus device address.
@@ -154,9 +555,493 @@ impl SafeClient {
     /// internal slave ID to match. This keeps the client synchronized with the
     /// device state, preventing subsequent communication errors.
     pub async fn set_address(&self, address: proto::Address) -> Result<()> {
-        let mut guard = self.ctx.lock().await;
-        R413D08::set_address(&mut guard, address).await?;
-        guard.set_slave(Slave(*address));
+        self.with_context(false, |ctx| {
+            Box::pin(async move {
+                R413D08::set_address(ctx, address).await?;
+                ctx.set_slave(Slave(*address));
+                Ok(())
+            })
+        })
+        .await?;
+        *self.slave.lock().unwrap() = Some(Slave(*address));
         Ok(())
     }
+
+    /// Polls the ports at the given interval and streams the changes.
+    ///
+    /// The first reading only establishes the baseline. Afterwards, every port
+    /// that flipped between two readings yields a [`crate::tokio_common::WatchEventKind::PortChanged`]
+    /// event. A failing reading yields [`crate::tokio_common::WatchEventKind::CommunicationLost`] once,
+    /// and the next successful reading yields [`crate::tokio_common::WatchEventKind::CommunicationRestored`]
+    /// followed by the changes since the last known states.
+    ///
+    /// The stream never ends, drop it to stop polling. If the consumer falls
+    /// behind, missed polls are skipped rather than performed in a burst.
+    /// Intervals shorter than [`MIN_POLL_INTERVAL`] are raised to it.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use futures_util::StreamExt;
+    /// use r413d08_lib::{tokio_async_safe_client::SafeClient, tokio_common::WatchEventKind};
+    /// use std::time::Duration;
+    ///
+    /// # async fn example(client: SafeClient) {
+    /// let mut events = std::pin::pin!(client.watch(Duration::from_millis(500)));
+    /// while let Some(event) = events.next().await {
+    ///     if let WatchEventKind::PortChanged(change) = event.kind {
+    ///         println!("{change}");
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn watch(&self, interval: Duration) -> impl Stream<Item = WatchEvent> + Send + 'static {
+        let mut ticker = tokio::time::interval(interval.max(MIN_POLL_INTERVAL));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let state = (
+            self.clone(),
+            ticker,
+            ChangeDetector::default(),
+            VecDeque::new(),
+        );
+        futures_util::stream::unfold(
+            state,
+            |(client, mut ticker, mut detector, mut pending)| async move {
+                while pending.is_empty() {
+                    ticker.tick().await;
+                    pending.extend(detector.update(client.read_ports().await));
+                }
+                let event = pending.pop_front();
+                event.map(|event| (event, (client, ticker, detector, pending)))
+            },
+        )
+    }
+}
+
+/// A port opened with [`SafeClient::set_port_for`].
+///
+/// Dropping the handle does not stop the run, the port still closes at its deadline.
+pub struct TimedPort {
+    port: proto::Port,
+    shared: Arc<(std::sync::Mutex<TimedRun>, Notify)>,
+    worker: tokio::task::JoinHandle<Result<()>>,
+}
+
+impl TimedPort {
+    /// Returns the port of the run.
+    pub fn port(&self) -> proto::Port {
+        self.port
+    }
+
+    /// Returns the time the port closes.
+    pub fn deadline(&self) -> Instant {
+        self.shared.0.lock().unwrap().deadline
+    }
+
+    /// Returns the time left until the port closes.
+    pub fn remaining(&self) -> Duration {
+        self.deadline().saturating_duration_since(Instant::now())
+    }
+
+    /// Returns `true` if the run is over.
+    pub fn is_finished(&self) -> bool {
+        self.worker.is_finished()
+    }
+
+    /// Moves the deadline of the run later by the given duration.
+    ///
+    /// The delay of the device is re-armed right away. Returns `false` if the
+    /// run is already over or the new deadline is beyond the times the clock
+    /// can represent.
+    pub fn extend(&self, by: Duration) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        let (run, wake) = &*self.shared;
+        let extended = run.lock().unwrap().extend(by);
+        if extended {
+            wake.notify_one();
+        }
+        extended
+    }
+
+    /// Closes the port right away and ends the run.
+    ///
+    /// The port is left alone if a later command already superseded the run.
+    pub async fn cancel(self) -> Result<()> {
+        let (run, wake) = &*self.shared;
+        run.lock().unwrap().cancelled = true;
+        wake.notify_one();
+        self.wait().await
+    }
+
+    /// Waits until the run is over.
+    ///
+    /// Fails if the delay could not be re-armed before it ran out, in which
+    /// case the device closed the port before the deadline.
+    pub async fn wait(self) -> Result<()> {
+        self.worker
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+}
+
+/// A function called for every missed heartbeat, see [`Heartbeat::on_missed`].
+type OnMissed = dyn Fn(&Error, &HeartbeatStatus) + Send + Sync;
+
+/// Keeps selected relays open only while the controller is alive.
+///
+/// A background task keeps re-issuing the delay command of the device
+/// ([`proto::Port::REG_DATA_SET_PORT_DELAY`]) for each port well before its
+/// timeout runs out. If the process, the host or the bus link dies, the
+/// heartbeats stop and the device drops the relays by itself once the timeout
+/// has passed. Dropping a running `Heartbeat` has the same effect, use
+/// [`Heartbeat::stop`] to close the relays right away.
+///
+/// # Example
+///
+/// ```no_run
+/// use r413d08_lib::{
+///     protocol::{Port, PortMask},
+///     tokio_async_safe_client::{Heartbeat, SafeClient},
+/// };
+///
+/// # async fn example(client: SafeClient) -> Result<(), Box<dyn std::error::Error>> {
+/// let mut ports = PortMask::NONE;
+/// ports.insert(Port::try_from(0)?);
+/// // Keep the conveyor running while we are alive, stop within 3 s otherwise
+/// let mut heartbeat = Heartbeat::new(client, ports, 3)
+///     .on_missed(|err, status| eprintln!("Missed heartbeat #{}: {err}", status.consecutive_missed));
+/// heartbeat.start().await?;
+/// // ... run the conveyor ...
+/// heartbeat.stop().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Heartbeat {
+    client: SafeClient,
+    ports: proto::PortMask,
+    timeout: u8,
+    interval: Duration,
+    on_missed: Option<Arc<OnMissed>>,
+    status: Arc<std::sync::Mutex<HeartbeatStatus>>,
+    /// The channel stopping the running worker task, closed when dropped.
+    worker: Option<(oneshot::Sender<()>, tokio::task::JoinHandle<()>)>,
+}
+
+impl Heartbeat {
+    /// Creates a stopped heartbeat for the given ports and device timeout in seconds.
+    ///
+    /// A timeout of 0 is raised to 1 second. By default, three heartbeats are
+    /// sent per timeout.
+    pub fn new(client: SafeClient, ports: proto::PortMask, timeout: u8) -> Self {
+        Self {
+            client,
+            ports,
+            timeout: timeout.max(1),
+            interval: timers::heartbeat_interval(timeout),
+            on_missed: None,
+            status: Arc::default(),
+            worker: None,
+        }
+    }
+
+    /// Sets the time between two heartbeats.
+    ///
+    /// The interval is limited to at least 10 ms and at most half the timeout,
+    /// so a single missed heartbeat does not drop the relays.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = timers::clamp_heartbeat_interval(interval, self.timeout);
+        self
+    }
+
+    /// Calls the given function from the worker task for every missed heartbeat.
+    pub fn on_missed(
+        mut self,
+        on_missed: impl Fn(&Error, &HeartbeatStatus) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_missed = Some(Arc::new(on_missed));
+        self
+    }
+
+    /// Sends the first heartbeat, opening the ports, and starts the worker task.
+    ///
+    /// Fails without starting if the first heartbeat fails. Does nothing if
+    /// the heartbeat is already running.
+    pub async fn start(&mut self) -> Result<()> {
+        if self.is_running() {
+            return Ok(());
+        }
+        let result = self.beat().await;
+        self.status.lock().unwrap().record(result.is_ok());
+        result?;
+        let (stop, stopped) = oneshot::channel();
+        let heartbeat = Self {
+            client: self.client.clone(),
+            status: self.status.clone(),
+            on_missed: self.on_missed.clone(),
+            worker: None,
+            ..*self
+        };
+        let worker = tokio::spawn(heartbeat.keep_beating(stopped));
+        self.worker = Some((stop, worker));
+        Ok(())
+    }
+
+    /// Stops the worker task and closes the ports.
+    ///
+    /// Does nothing if the heartbeat is not running.
+    pub async fn stop(&mut self) -> Result<()> {
+        let Some((stop, worker)) = self.worker.take() else {
+            return Ok(());
+        };
+        drop(stop);
+        worker
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+        for port in self.ports.iter() {
+            self.client.set_port_close(port).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the worker task is running.
+    pub fn is_running(&self) -> bool {
+        self.worker.is_some()
+    }
+
+    /// Returns the counters of the heartbeats sent so far.
+    pub fn status(&self) -> HeartbeatStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// Returns `true` if a heartbeat reached the device within the timeout, i.e. the relays are held.
+    pub fn is_alive(&self) -> bool {
+        self.status()
+            .is_alive(Duration::from_secs(self.timeout.into()))
+    }
+
+    /// Re-issues the delay command for all ports.
+    async fn beat(&self) -> Result<()> {
+        for port in self.ports.iter() {
+            self.client.set_port_delay(port, self.timeout).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the heartbeats until the stop channel is closed.
+    async fn keep_beating(self, mut stopped: oneshot::Receiver<()>) {
+        let mut next = tokio::time::Instant::now() + self.interval;
+        while tokio::time::timeout_at(next, &mut stopped).await.is_err() {
+            next = timers::next_beat(next.into_std(), self.interval, Instant::now()).into();
+            let result = self.beat().await;
+            let status = {
+                let mut status = self.status.lock().unwrap();
+                status.record(result.is_ok());
+                *status
+            };
+            if let (Err(err), Some(on_missed)) = (&result, &self.on_missed) {
+                on_missed(err, &status);
+            }
+        }
+    }
+}
+
+impl RelayController for SafeClient {
+    async fn read_ports(&mut self) -> Result<proto::PortStates> {
+        SafeClient::read_ports(self).await
+    }
+
+    async fn set_port_open(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_open(self, port).await
+    }
+
+    async fn set_all_open(&mut self) -> Result<()> {
+        SafeClient::set_all_open(self).await
+    }
+
+    async fn set_port_close(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_close(self, port).await
+    }
+
+    async fn set_all_close(&mut self) -> Result<()> {
+        SafeClient::set_all_close(self).await
+    }
+
+    async fn set_port_toggle(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_toggle(self, port).await
+    }
+
+    async fn set_port_latch(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_latch(self, port).await
+    }
+
+    async fn set_port_momentary(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_momentary(self, port).await
+    }
+
+    async fn set_port_delay(&mut self, port: proto::Port, delay: u8) -> Result<()> {
+        SafeClient::set_port_delay(self, port, delay).await
+    }
+
+    async fn read_address(&mut self) -> Result<proto::Address> {
+        SafeClient::read_address(self).await
+    }
+
+    async fn set_address(&mut self, address: proto::Address) -> Result<()> {
+        SafeClient::set_address(self, address).await
+    }
+
+    async fn execute(&mut self, command: proto::Command) -> Result<()> {
+        SafeClient::execute(self, command).await
+    }
+
+    async fn apply_mask(&mut self, target: proto::PortMask) -> Result<()> {
+        SafeClient::apply_mask(self, target).await
+    }
+}
+
+#[cfg(all(test, feature = "simulator", feature = "tokio-tcp"))]
+mod tests {
+    use super::*;
+    use crate::{
+        simulator::testing::{closing_listener, holds_for_async, port, wait_until_async, Fixture},
+        tokio_common::{PortChange, WatchEventKind},
+    };
+    use assert_matches::assert_matches;
+    use futures_util::StreamExt;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn async_safe_client_pulse_survives_cancellation() {
+        let fixture = Fixture::new();
+        let client = fixture.async_client().await;
+        client
+            .pulse(port(4), Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(!fixture.is_open(4));
+
+        // The port is closed although the pulse was dropped while waiting.
+        let pulse = client.pulse(port(4), Duration::from_millis(500));
+        assert!(tokio::time::timeout(Duration::from_millis(100), pulse)
+            .await
+            .is_err());
+        assert!(fixture.is_open(4));
+        wait_until_async("the dropped pulse closed port 4", || !fixture.is_open(4)).await;
+    }
+
+    #[tokio::test]
+    async fn async_safe_client_timed_port_cancels() {
+        let fixture = Fixture::new();
+        let client = fixture.async_client().await;
+        assert!(client.set_port_for(port(5), Duration::MAX).await.is_err());
+        assert!(!fixture.is_open(5));
+
+        let run = client
+            .set_port_for(port(5), Duration::from_secs(6 * 3600))
+            .await
+            .unwrap();
+        assert_eq!(run.port(), port(5));
+        assert!(fixture.is_open(5));
+        assert!(run.extend(Duration::from_secs(60)));
+        assert!(!run.extend(Duration::MAX));
+        holds_for_async(
+            "the run keeps port 5 open",
+            Duration::from_millis(100),
+            || fixture.is_open(5),
+        )
+        .await;
+        assert!(!run.is_finished());
+        run.cancel().await.unwrap();
+        assert!(!fixture.is_open(5));
+    }
+
+    #[tokio::test]
+    async fn async_heartbeat_holds_relays_while_alive() {
+        let fixture = Fixture::new();
+        let client = fixture.async_client().await;
+        let mut heartbeat =
+            Heartbeat::new(client, port(7).into(), 1).with_interval(Duration::from_millis(300));
+        heartbeat.start().await.unwrap();
+        holds_for_async(
+            "the heartbeats hold port 7",
+            Duration::from_millis(1500),
+            || fixture.is_open(7),
+        )
+        .await;
+        wait_until_async("five heartbeats reached the device", || {
+            heartbeat.status().beats >= 5
+        })
+        .await;
+        heartbeat.stop().await.unwrap();
+        assert!(!fixture.is_open(7));
+    }
+
+    #[tokio::test]
+    async fn async_safe_client_reconnects_after_transport_failure() {
+        let fixture = Fixture::new();
+        let socket_addr = fixture.socket_addr;
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let connects = connects.clone();
+            move || {
+                let first = connects.fetch_add(1, Ordering::Relaxed) == 0;
+                async move {
+                    if first {
+                        let (listener, closing_addr) = closing_listener();
+                        let ctx = tokio_modbus::client::tcp::connect(closing_addr).await;
+                        drop(listener);
+                        ctx
+                    } else {
+                        tokio_modbus::client::tcp::connect(socket_addr).await
+                    }
+                }
+            }
+        };
+        let client = SafeClient::with_reconnect(connect)
+            .await
+            .unwrap()
+            .with_retry_policy(RetryPolicy {
+                max_retries: 1,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(10),
+            });
+        client.set_all_open().await.unwrap();
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+        assert_eq!(fixture.open_ports(), proto::PortMask::ALL);
+    }
+
+    #[tokio::test]
+    async fn async_safe_client_watch_against_simulator() {
+        let fixture = Fixture::new();
+        let client = fixture.async_client().await;
+        // A zero interval is raised to the minimum instead of panicking or spinning.
+        let mut events = std::pin::pin!(client.watch(Duration::ZERO));
+
+        // A change before the first reading is part of the baseline, so toggle until one is seen.
+        let toggles = async {
+            loop {
+                client.set_port_toggle(port(6)).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        let event = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                event = events.next() => event.unwrap(),
+                () = toggles => unreachable!(),
+            }
+        })
+        .await
+        .unwrap();
+        let state = fixture
+            .simulator
+            .port_states(proto::Address::default())
+            .unwrap()[6];
+        assert_matches!(
+            event.kind,
+            WatchEventKind::PortChanged(PortChange { port: changed, from, to })
+                if changed == port(6) && from != to && to == state
+        );
+    }
 }
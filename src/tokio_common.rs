@@ -4,9 +4,17 @@
 //! It defines the `Error` enum, which encapsulates all possible communication errors.
 
 use crate::protocol as proto;
+use std::time::Duration;
 
 /// Represents all possible errors that can occur during Modbus communication.
+///
+/// Errors returned by the clients of this crate are usually wrapped in
+/// [`Error::Operation`], which tells which operation, register and slave
+/// failed. Use the classification helpers (e.g., [`Error::is_timeout`]) or
+/// match on [`Error::root`] rather than on the error itself, since they look
+/// through this wrapper. Further variants may be added in the future.
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// An error originating from the protocol logic, such as invalid data.
     #[error(transparent)]
@@ -19,6 +27,145 @@ pub enum Error {
     /// A transport or communication error from the underlying `tokio-modbus` client.
     #[error(transparent)]
     Modbus(#[from] tokio_modbus::Error),
+
+    /// An error annotated with the operation during which it occurred.
+    #[error("{context} failed")]
+    Operation {
+        /// The operation, register and slave that failed.
+        context: ErrorContext,
+        /// The underlying error.
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Returns the underlying error, looking through any [`Error::Operation`] wrappers.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Operation { source, .. } => source.root(),
+            err => err,
+        }
+    }
+
+    /// Returns the operation context of the error, if known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::Operation { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Returns the I/O error kind if the error was caused by the transport
+    /// (e.g., a timeout, a broken pipe or a CRC error reported as invalid data).
+    pub fn io_error_kind(&self) -> Option<std::io::ErrorKind> {
+        match self.root() {
+            Error::Modbus(tokio_modbus::Error::Transport(err)) => Some(err.kind()),
+            _ => None,
+        }
+    }
+
+    /// Returns the exception code if the device rejected the request.
+    pub fn exception_code(&self) -> Option<tokio_modbus::ExceptionCode> {
+        match self.root() {
+            Error::ModbusException(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the device did not answer in time.
+    pub fn is_timeout(&self) -> bool {
+        self.io_error_kind() == Some(std::io::ErrorKind::TimedOut)
+    }
+
+    /// Returns `true` if the error is likely temporary, so repeating the
+    /// operation may succeed (timeouts, I/O errors, garbled or mismatching frames).
+    ///
+    /// Modbus exceptions and invalid data are deliberate answers of the device
+    /// and are not considered transient.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.root(), Error::Modbus(_))
+    }
+
+    /// Returns `true` if the device answered with a Modbus exception, i.e. it
+    /// is reachable but refused the request. Repeating the request won't help.
+    pub fn is_device_rejection(&self) -> bool {
+        self.exception_code().is_some()
+    }
+
+    /// Wraps the error with the given context, unless it already has one.
+    pub(crate) fn in_context(self, context: ErrorContext) -> Self {
+        match self {
+            err @ Error::Operation { .. } => err,
+            err => Error::Operation {
+                context,
+                source: Box::new(err),
+            },
+        }
+    }
+
+    /// Records the slave address in the error context, if there is one.
+    #[cfg(any(
+        feature = "tokio-rtu-sync",
+        feature = "tokio-tcp-sync",
+        all(
+            feature = "safe-client-async",
+            any(feature = "tokio-rtu", feature = "tokio-tcp")
+        )
+    ))]
+    pub(crate) fn with_slave(mut self, slave: tokio_modbus::Slave) -> Self {
+        if let Error::Operation { context, .. } = &mut self {
+            context.slave = Some(slave.0);
+        }
+        self
+    }
+}
+
+/// Describes the operation during which an [`Error`] occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// A human readable name of the operation, e.g. "read ports" or "open port 3".
+    pub operation: String,
+    /// The Modbus register the operation accessed.
+    pub register: u16,
+    /// The port the operation was addressed to, if any.
+    pub port: Option<proto::Port>,
+    /// The slave address the request was sent to, if known.
+    ///
+    /// The stateless functions can't tell which slave the context is set to,
+    /// so only the safe clients and the bus fill this in.
+    pub slave: Option<u8>,
+}
+
+impl ErrorContext {
+    /// Creates the context for reading the given register.
+    pub(crate) fn read(operation: &str, register: u16) -> Self {
+        Self {
+            operation: operation.to_string(),
+            register,
+            port: None,
+            slave: None,
+        }
+    }
+
+    /// Creates the context for writing the given command.
+    pub(crate) fn command(command: &proto::Command) -> Self {
+        Self {
+            operation: command.to_string(),
+            register: command.encode().0,
+            port: command.port(),
+            slave: None,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (register {:#06x}", self.operation, self.register)?;
+        if let Some(slave) = self.slave {
+            write!(f, ", slave {slave}")?;
+        }
+        write!(f, ")")
+    }
 }
 
 /// The result type for tokio operations.
@@ -32,6 +179,17 @@ pub const STOP_BITS: &tokio_serial::StopBits = &tokio_serial::StopBits::One;
 pub const DATA_BITS: &tokio_serial::DataBits = &tokio_serial::DataBits::Eight;
 /// The baud rate used for serial communication.
 pub const BAUD_RATE: u32 = 9600;
+/// The minimum silent interval between two Modbus RTU frames at [`BAUD_RATE`].
+///
+/// The Modbus RTU specification requires 3.5 character times of silence between
+/// frames, with one character taking 10 bits (start, 8 data and stop bit).
+pub const INTER_FRAME_DELAY: Duration = Duration::from_micros(35 * 1_000_000 / BAUD_RATE as u64);
+
+/// The shortest time between two polls of the port states.
+///
+/// Shorter polling intervals, including zero, are raised to this value, so
+/// polling never occupies the bus without a pause.
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 /// Creates and configures a `tokio_serial::SerialPortBuilder` for RTU communication.
 ///
@@ -54,3 +212,305 @@ pub fn serial_port_builder(device: &str) -> tokio_serial::SerialPortBuilder {
         .data_bits(*DATA_BITS)
         .flow_control(tokio_serial::FlowControl::None)
 }
+
+/// A single port that changed its state between two polls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortChange {
+    /// The port that changed.
+    pub port: proto::Port,
+    /// The state before the change.
+    pub from: proto::PortState,
+    /// The state after the change.
+    pub to: proto::PortState,
+}
+
+impl PortChange {
+    /// Returns the changes between two readings of all ports, in port order.
+    pub fn between(from: &proto::PortStates, to: &proto::PortStates) -> Vec<Self> {
+        let from_mask = proto::PortMask::from(*from);
+        let to_mask = proto::PortMask::from(*to);
+        let changed = (from_mask | to_mask) - (from_mask & to_mask);
+        changed
+            .iter()
+            .map(|port| Self {
+                port,
+                from: from[*port as usize],
+                to: to[*port as usize],
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for PortChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "port {}: {} -> {}", self.port, self.from, self.to)
+    }
+}
+
+/// What happened in a [`WatchEvent`].
+#[derive(Debug)]
+pub enum WatchEventKind {
+    /// A port changed its state.
+    PortChanged(PortChange),
+    /// Reading the ports failed after the last reading succeeded (or at the first reading).
+    CommunicationLost(Error),
+    /// Reading the ports succeeded again after a [`WatchEventKind::CommunicationLost`].
+    CommunicationRestored,
+}
+
+/// A timestamped event reported while watching the ports of a device.
+#[derive(Debug)]
+pub struct WatchEvent {
+    /// The time the event was detected.
+    pub at: std::time::SystemTime,
+    /// What happened.
+    pub kind: WatchEventKind,
+}
+
+#[cfg(any(
+    all(
+        feature = "safe-client-sync",
+        any(feature = "tokio-rtu-sync", feature = "tokio-tcp-sync")
+    ),
+    all(
+        feature = "safe-client-async",
+        any(feature = "tokio-rtu", feature = "tokio-tcp")
+    )
+))]
+pub use crate::timers::HeartbeatStatus;
+
+/// Tracks the last port states while polling and turns readings into [`WatchEvent`]s.
+#[cfg(any(feature = "safe-client-sync", feature = "safe-client-async"))]
+#[derive(Debug, Default)]
+pub(crate) struct ChangeDetector {
+    last: Option<proto::PortStates>,
+    lost: bool,
+}
+
+#[cfg(any(feature = "safe-client-sync", feature = "safe-client-async"))]
+impl ChangeDetector {
+    /// Processes the result of a reading and returns the resulting events.
+    ///
+    /// The first successful reading only sets the baseline. After a
+    /// communication loss, changes are reported against the last known states.
+    pub(crate) fn update(&mut self, reading: Result<proto::PortStates>) -> Vec<WatchEvent> {
+        let at = std::time::SystemTime::now();
+        let event = |kind| WatchEvent { at, kind };
+        match reading {
+            Ok(states) => {
+                let mut events = Vec::new();
+                if std::mem::take(&mut self.lost) {
+                    events.push(event(WatchEventKind::CommunicationRestored));
+                }
+                if let Some(last) = self.last.replace(states) {
+                    events.extend(
+                        PortChange::between(&last, &states)
+                            .into_iter()
+                            .map(|change| event(WatchEventKind::PortChanged(change))),
+                    );
+                }
+                events
+            }
+            Err(_) if self.lost => Vec::new(),
+            Err(err) => {
+                self.lost = true;
+                vec![event(WatchEventKind::CommunicationLost(err))]
+            }
+        }
+    }
+}
+
+/// Controls how often and how fast the safe clients retry failed operations.
+///
+/// Only transient failures (timeouts, I/O and CRC errors) are retried, Modbus
+/// exceptions never. Between two attempts the client waits for a backoff that
+/// starts at `initial_backoff` and doubles with each retry, up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of retries after the first failed attempt.
+    pub max_retries: u32,
+    /// The time to wait before the first retry.
+    pub initial_backoff: Duration,
+    /// The upper limit for the time to wait between two retries.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub const NONE: Self = Self {
+        max_retries: 0,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+
+    /// Returns the time to wait before the given retry (starting at 0).
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Retries up to 3 times, waiting 100 ms, 200 ms and 400 ms (at most 2 s).
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Returns the device delay for a pulse of the given duration.
+///
+/// The device only counts whole seconds from 1 to 255, every other duration
+/// (including zero) needs a software timer and yields `None`.
+#[cfg(any(
+    feature = "tokio-rtu-sync",
+    feature = "tokio-tcp-sync",
+    feature = "safe-client-async"
+))]
+pub(crate) fn device_delay(duration: Duration) -> Option<u8> {
+    if duration.subsec_nanos() != 0 {
+        return None;
+    }
+    u8::try_from(duration.as_secs())
+        .ok()
+        .filter(|seconds| *seconds > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_change_between() {
+        let from = proto::PortStates::from(proto::PortMask::from_bits(0b0000_0101));
+        let to = proto::PortStates::from(proto::PortMask::from_bits(0b1000_0100));
+        let changes = PortChange::between(&from, &to);
+        let rendered: Vec<_> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(rendered, ["port 0: open -> close", "port 7: close -> open"]);
+        assert!(PortChange::between(&to, &to).is_empty());
+    }
+
+    #[cfg(any(feature = "safe-client-sync", feature = "safe-client-async"))]
+    #[test]
+    fn change_detector_events() {
+        use assert_matches::assert_matches;
+
+        let states = |bits| Ok(proto::PortStates::from(proto::PortMask::from_bits(bits)));
+        let timeout = || {
+            Err(Error::from(tokio_modbus::Error::Transport(
+                std::io::ErrorKind::TimedOut.into(),
+            )))
+        };
+        let mut detector = ChangeDetector::default();
+
+        // A failing first reading is reported, the first success only sets the baseline.
+        assert_matches!(
+            &detector.update(timeout())[..],
+            [WatchEvent { kind: WatchEventKind::CommunicationLost(err), .. }] if err.is_timeout()
+        );
+        assert!(detector.update(timeout()).is_empty());
+        assert_matches!(
+            &detector.update(states(0x01))[..],
+            [WatchEvent {
+                kind: WatchEventKind::CommunicationRestored,
+                ..
+            }]
+        );
+        assert!(detector.update(states(0x01)).is_empty());
+
+        // Changes during a communication loss are reported after the recovery.
+        assert_eq!(detector.update(timeout()).len(), 1);
+        let events = detector.update(states(0x02));
+        assert_matches!(
+            &events[..],
+            [
+                WatchEvent {
+                    kind: WatchEventKind::CommunicationRestored,
+                    ..
+                },
+                WatchEvent {
+                    kind: WatchEventKind::PortChanged(first),
+                    ..
+                },
+                WatchEvent {
+                    kind: WatchEventKind::PortChanged(second),
+                    ..
+                },
+            ] if *first.port == 0 && *second.port == 1
+        );
+    }
+
+    #[cfg(any(feature = "safe-client-sync", feature = "safe-client-async"))]
+    #[test]
+    fn pulse_timing() {
+        assert_eq!(device_delay(Duration::from_secs(1)), Some(1));
+        assert_eq!(device_delay(Duration::from_secs(255)), Some(255));
+        assert_eq!(device_delay(Duration::from_secs(256)), None);
+        assert_eq!(device_delay(Duration::ZERO), None);
+        assert_eq!(device_delay(Duration::from_millis(2500)), None);
+        assert_eq!(device_delay(Duration::from_millis(350)), None);
+    }
+
+    #[cfg(all(
+        feature = "simulator",
+        feature = "safe-client-sync",
+        feature = "tokio-tcp-sync"
+    ))]
+    #[test]
+    fn errors_carry_operation_context() {
+        use crate::{
+            simulator::testing::{address, port, Fixture},
+            tokio_sync::R413D08,
+            tokio_sync_bus::Bus,
+        };
+        use tokio_modbus::{prelude::SlaveContext, ExceptionCode};
+
+        let fixture = Fixture::with_devices([address(2), address(5)]);
+        let mut ctx = fixture.sync_context();
+
+        // Both devices match the broadcast address, the simulator rejects the request.
+        ctx.set_slave(tokio_modbus::Slave(*proto::Address::BROADCAST));
+        let err = R413D08::read_address(&mut ctx).unwrap_err();
+        assert!(err.is_device_rejection());
+        assert!(!err.is_transient() && !err.is_timeout());
+        assert_eq!(
+            err.exception_code(),
+            Some(ExceptionCode::GatewayTargetDevice)
+        );
+        let context = err.context().unwrap();
+        assert_eq!(context.operation, "read address");
+        assert_eq!(context.register, proto::Address::ADDRESS);
+        assert_eq!(context.slave, None);
+
+        // Nobody answers at address 9.
+        ctx.set_timeout(Duration::from_millis(50));
+        let bus = Bus::new(ctx);
+        let err = bus
+            .device(address(9))
+            .set_port_delay(port(3), 10)
+            .unwrap_err();
+        assert!(err.is_timeout() && err.is_transient());
+        assert!(!err.is_device_rejection());
+        assert_eq!(
+            err.context(),
+            Some(&ErrorContext {
+                operation: "delay port 3 for 10s".to_string(),
+                register: 0x0004,
+                port: Some(port(3)),
+                slave: Some(9),
+            })
+        );
+        assert_eq!(
+            err.to_string(),
+            "delay port 3 for 10s (register 0x0004, slave 9) failed"
+        );
+        assert_eq!(
+            std::error::Error::source(&err).unwrap().to_string(),
+            "deadline has elapsed"
+        );
+    }
+}
@@ -32,9 +32,143 @@
 //! # }
 //! ```
 
-use crate::{protocol as proto, tokio_common::Result};
+use crate::{
+    protocol as proto,
+    tokio_common::{self, ErrorContext, Result},
+};
+use std::time::Duration;
 use tokio_modbus::prelude::{SyncReader, SyncWriter};
 
+/// A transport-agnostic, synchronous interface to an R413D08 relay module.
+///
+/// This trait abstracts over the concrete client, so application code can be
+/// written against it and work unchanged with the stateless [`R413D08`] functions,
+/// the [`crate::tokio_sync_safe_client::SafeClient`], a simulator or a test mock.
+///
+/// It is implemented for:
+/// * [`tokio_modbus::client::sync::Context`], delegating to the stateless [`R413D08`] functions.
+/// * [`crate::tokio_sync_safe_client::SafeClient`].
+///
+/// # Example
+///
+/// ```no_run
+/// use r413d08_lib::{protocol::Port, tokio_sync::RelayController};
+///
+/// fn open_first_two(relays: &mut impl RelayController) -> Result<(), Box<dyn std::error::Error>> {
+///     relays.set_port_open(Port::try_from(0)?)?;
+///     relays.set_port_open(Port::try_from(1)?)?;
+///     Ok(())
+/// }
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let socket_addr = "127.0.0.1:502".parse()?;
+/// let mut ctx = tokio_modbus::client::sync::tcp::connect(socket_addr)?;
+/// open_first_two(&mut ctx)?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait RelayController {
+    /// Reads the current status (Open/Close) of all [`proto::NUMBER_OF_PORTS`] ports.
+    fn read_ports(&mut self) -> Result<proto::PortStates>;
+
+    /// Sets the specified port to the **Open** state (activates relay).
+    fn set_port_open(&mut self, port: proto::Port) -> Result<()>;
+
+    /// Sets **all** ports to the **Open** state simultaneously.
+    fn set_all_open(&mut self) -> Result<()>;
+
+    /// Sets the specified port to the **Close** state (deactivates relay).
+    fn set_port_close(&mut self, port: proto::Port) -> Result<()>;
+
+    /// Sets **all** ports to the **Close** state simultaneously.
+    fn set_all_close(&mut self) -> Result<()>;
+
+    /// Toggles the current state of the specified port (Open -> Close, Close -> Open).
+    fn set_port_toggle(&mut self, port: proto::Port) -> Result<()>;
+
+    /// Latches the specified port (opens it and closes all others).
+    fn set_port_latch(&mut self, port: proto::Port) -> Result<()>;
+
+    /// Activates the specified port momentarily (Open for ~1 second, then Close).
+    fn set_port_momentary(&mut self, port: proto::Port) -> Result<()>;
+
+    /// Activates the specified port with a delayed close after `delay` seconds (0-255).
+    fn set_port_delay(&mut self, port: proto::Port, delay: u8) -> Result<()>;
+
+    /// Reads the configured Modbus device address from the device itself.
+    fn read_address(&mut self) -> Result<proto::Address>;
+
+    /// Sets a new Modbus device address.
+    fn set_address(&mut self, address: proto::Address) -> Result<()>;
+
+    /// Writes the given [`proto::Command`] to the device.
+    ///
+    /// The default implementation dispatches to the method matching the command.
+    fn execute(&mut self, command: proto::Command) -> Result<()> {
+        match command {
+            proto::Command::Open(port) => self.set_port_open(port),
+            proto::Command::Close(port) => self.set_port_close(port),
+            proto::Command::Toggle(port) => self.set_port_toggle(port),
+            proto::Command::Latch(port) => self.set_port_latch(port),
+            proto::Command::Momentary(port) => self.set_port_momentary(port),
+            proto::Command::Delay(port, delay) => self.set_port_delay(port, delay),
+            proto::Command::AllOpen => self.set_all_open(),
+            proto::Command::AllClose => self.set_all_close(),
+            proto::Command::SetAddress(address) => self.set_address(address),
+        }
+    }
+
+    /// Switches all ports to the given target mask with the fewest Modbus writes.
+    ///
+    /// The default implementation reads the current states and executes the
+    /// commands computed by [`proto::Command::plan_transition`].
+    fn apply_mask(&mut self, target: proto::PortMask) -> Result<()> {
+        let current = proto::PortMask::from(self.read_ports()?);
+        for command in proto::Command::plan_transition(current, target) {
+            self.execute(command)?;
+        }
+        Ok(())
+    }
+
+    /// Switches all ports to the given target states with the fewest Modbus writes.
+    fn apply_states(&mut self, target: proto::PortStates) -> Result<()> {
+        self.apply_mask(target.into())
+    }
+
+    /// Opens the specified port for the given duration, waiting for it with `wait`.
+    ///
+    /// A whole number of seconds from 1 to 255 uses the delay command of the
+    /// device, so the port closes even if the caller dies. Any other duration
+    /// is closed by this call. `wait` is called with the time the port stays
+    /// open and returns `false` to end the pulse early, which closes the port
+    /// right away. Returns the result of `wait`.
+    ///
+    /// The default implementation always closes the port after an early end
+    /// or a pulse timed in software.
+    fn pulse_with(
+        &mut self,
+        port: proto::Port,
+        duration: Duration,
+        wait: &mut dyn FnMut(Duration) -> bool,
+    ) -> Result<bool> {
+        let timed_by_device = match tokio_common::device_delay(duration) {
+            Some(delay) => {
+                self.set_port_delay(port, delay)?;
+                true
+            }
+            None => {
+                self.set_port_open(port)?;
+                false
+            }
+        };
+        let completed = wait(duration);
+        if !completed || !timed_by_device {
+            self.set_port_close(port)?;
+        }
+        Ok(completed)
+    }
+}
+
 /// A synchronous client for interacting with an R413D08 relay module over Modbus.
 ///
 /// This client wraps a [`tokio_modbus::client::sync::Context`] and provides
@@ -57,8 +191,11 @@ impl R413D08 {
     }
 
     /// Helper function to read holding registers and decode them into a specific type.
+    ///
+    /// Errors are annotated with the given operation name and the register address.
     fn read_and_decode<T, F>(
         ctx: &mut tokio_modbus::client::sync::Context,
+        operation: &str,
         address: u16,
         quantity: u16,
         decoder: F,
@@ -66,9 +203,30 @@ impl R413D08 {
     where
         F: FnOnce(&[u16]) -> Result<T>,
     {
-        decoder(&Self::map_tokio_result(
-            ctx.read_holding_registers(address, quantity),
-        )?)
+        Self::map_tokio_result(ctx.read_holding_registers(address, quantity))
+            .and_then(|words| decoder(&words))
+            .map_err(|err| err.in_context(ErrorContext::read(operation, address)))
+    }
+
+    /// Writes the given [`proto::Command`] to the device.
+    ///
+    /// All `set_*` functions are shorthands for this function with the
+    /// corresponding command.
+    ///
+    /// # Arguments
+    ///
+    /// * `command`: The [`proto::Command`] to execute.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
+    pub fn execute(
+        ctx: &mut tokio_modbus::client::sync::Context,
+        command: proto::Command,
+    ) -> Result<()> {
+        let (register, value) = command.encode();
+        Self::map_tokio_result(ctx.write_single_register(register, value))
+            .map_err(|err| err.in_context(ErrorContext::command(&command)))
     }
 
     /// Reads the current status (Open/Close) of all [`proto::NUMBER_OF_PORTS`] ports.
@@ -81,6 +239,7 @@ impl R413D08 {
     pub fn read_ports(ctx: &mut tokio_modbus::client::sync::Context) -> Result<proto::PortStates> {
         Self::read_and_decode(
             ctx,
+            "read ports",
             proto::PortStates::ADDRESS,
             proto::PortStates::QUANTITY,
             |words| Ok(proto::PortStates::decode_from_holding_registers(words)),
@@ -100,10 +259,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::sync::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(ctx.write_single_register(
-            port.address_for_write_register(),
-            proto::Port::REG_DATA_SET_PORT_OPEN,
-        ))
+        Self::execute(ctx, proto::Command::Open(port))
     }
 
     /// Sets **all** ports to the **Open** state simultaneously.
@@ -112,10 +268,7 @@ impl R413D08 {
     ///
     /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
     pub fn set_all_open(ctx: &mut tokio_modbus::client::sync::Context) -> Result<()> {
-        Self::map_tokio_result(ctx.write_single_register(
-            proto::PortsAll::ADDRESS,
-            proto::PortsAll::REG_DATA_SET_ALL_OPEN,
-        ))
+        Self::execute(ctx, proto::Command::AllOpen)
     }
 
     /// Sets the specified port to the **Close** state (deactivates relay).
@@ -131,10 +284,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::sync::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(ctx.write_single_register(
-            port.address_for_write_register(),
-            proto::Port::REG_DATA_SET_PORT_CLOSE,
-        ))
+        Self::execute(ctx, proto::Command::Close(port))
     }
 
     /// Sets **all** ports to the **Close** state simultaneously.
@@ -143,10 +293,7 @@ impl R413D08 {
     ///
     /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
     pub fn set_all_close(ctx: &mut tokio_modbus::client::sync::Context) -> Result<()> {
-        Self::map_tokio_result(ctx.write_single_register(
-            proto::PortsAll::ADDRESS,
-            proto::PortsAll::REG_DATA_SET_ALL_CLOSE,
-        ))
+        Self::execute(ctx, proto::Command::AllClose)
     }
 
     /// Toggles the current state of the specified port (Open -> Close, Close -> Open). Also called "Self-locking".
@@ -162,10 +309,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::sync::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(ctx.write_single_register(
-            port.address_for_write_register(),
-            proto::Port::REG_DATA_SET_PORT_TOGGLE,
-        ))
+        Self::execute(ctx, proto::Command::Toggle(port))
     }
 
     /// Latches the specified port (Inter-locking): Sets the given `port` to Open and all *other* ports to Close.
@@ -181,10 +325,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::sync::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(ctx.write_single_register(
-            port.address_for_write_register(),
-            proto::Port::REG_DATA_SET_PORT_LATCH,
-        ))
+        Self::execute(ctx, proto::Command::Latch(port))
     }
 
     /// Activates the specified port momentarily (Non-locking): Opens the port for ~1 second, then automatically Closes.
@@ -200,10 +341,7 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::sync::Context,
         port: proto::Port,
     ) -> Result<()> {
-        Self::map_tokio_result(ctx.write_single_register(
-            port.address_for_write_register(),
-            proto::Port::REG_DATA_SET_PORT_MOMENTARY,
-        ))
+        Self::execute(ctx, proto::Command::Momentary(port))
     }
 
     /// Initiates a delayed action on the specified port (typically Open -> Delay -> Close).
@@ -221,10 +359,50 @@ impl R413D08 {
         port: proto::Port,
         delay: u8,
     ) -> Result<()> {
-        Self::map_tokio_result(ctx.write_single_register(
-            port.address_for_write_register(),
-            proto::Port::encode_delay_for_write_register(delay),
-        ))
+        Self::execute(ctx, proto::Command::Delay(port, delay))
+    }
+
+    /// Switches all ports to the given target mask with the fewest Modbus writes.
+    ///
+    /// Reads the current port states and executes the command sequence computed by
+    /// [`proto::Command::plan_transition`], which uses the all-open, all-close and
+    /// latch commands where they save writes.
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: The ports that should be open; all others are closed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
+    /// The ports may then be left in an intermediate state.
+    pub fn apply_mask(
+        ctx: &mut tokio_modbus::client::sync::Context,
+        target: proto::PortMask,
+    ) -> Result<()> {
+        let current = proto::PortMask::from(Self::read_ports(ctx)?);
+        for command in proto::Command::plan_transition(current, target) {
+            Self::execute(ctx, command)?;
+        }
+        Ok(())
+    }
+
+    /// Switches all ports to the given target states with the fewest Modbus writes.
+    ///
+    /// See [`R413D08::apply_mask`] for details.
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: The [`proto::PortStates`] every port should have afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(tokio_modbus::Error)` if a Modbus communication error occurs.
+    pub fn apply_states(
+        ctx: &mut tokio_modbus::client::sync::Context,
+        target: proto::PortStates,
+    ) -> Result<()> {
+        Self::apply_mask(ctx, target.into())
     }
 
     /// Reads the configured Modbus device address from the device itself.
@@ -270,6 +448,7 @@ impl R413D08 {
     pub fn read_address(ctx: &mut tokio_modbus::client::sync::Context) -> Result<proto::Address> {
         Self::read_and_decode(
             ctx,
+            "read address",
             proto::Address::ADDRESS,
             proto::Address::QUANTITY,
             |words| Ok(proto::Address::decode_from_holding_registers(words)?),
@@ -313,8 +492,90 @@ impl R413D08 {
         ctx: &mut tokio_modbus::client::sync::Context,
         address: proto::Address,
     ) -> Result<()> {
-        Self::map_tokio_result(
-            ctx.write_single_register(proto::Address::ADDRESS, address.encode_for_write_register()),
-        )
+        Self::execute(ctx, proto::Command::SetAddress(address))
+    }
+}
+
+impl RelayController for tokio_modbus::client::sync::Context {
+    fn read_ports(&mut self) -> Result<proto::PortStates> {
+        R413D08::read_ports(self)
+    }
+
+    fn set_port_open(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_open(self, port)
+    }
+
+    fn set_all_open(&mut self) -> Result<()> {
+        R413D08::set_all_open(self)
+    }
+
+    fn set_port_close(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_close(self, port)
+    }
+
+    fn set_all_close(&mut self) -> Result<()> {
+        R413D08::set_all_close(self)
+    }
+
+    fn set_port_toggle(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_toggle(self, port)
+    }
+
+    fn set_port_latch(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_latch(self, port)
+    }
+
+    fn set_port_momentary(&mut self, port: proto::Port) -> Result<()> {
+        R413D08::set_port_momentary(self, port)
+    }
+
+    fn set_port_delay(&mut self, port: proto::Port, delay: u8) -> Result<()> {
+        R413D08::set_port_delay(self, port, delay)
+    }
+
+    fn read_address(&mut self) -> Result<proto::Address> {
+        R413D08::read_address(self)
+    }
+
+    fn set_address(&mut self, address: proto::Address) -> Result<()> {
+        R413D08::set_address(self, address)
+    }
+
+    fn execute(&mut self, command: proto::Command) -> Result<()> {
+        R413D08::execute(self, command)
+    }
+
+    fn apply_mask(&mut self, target: proto::PortMask) -> Result<()> {
+        R413D08::apply_mask(self, target)
+    }
+}
+
+#[cfg(all(
+    test,
+    feature = "simulator",
+    feature = "safe-client-sync",
+    feature = "tokio-tcp-sync"
+))]
+mod tests {
+    use super::*;
+    use crate::{
+        simulator::testing::{port, Fixture},
+        tokio_sync_safe_client::SafeClient,
+    };
+
+    #[test]
+    fn relay_controllers_against_simulator() {
+        fn latch_and_read(relays: &mut impl RelayController) -> proto::PortStates {
+            relays.set_all_open().unwrap();
+            relays.set_port_latch(port(2)).unwrap();
+            relays.read_ports().unwrap()
+        }
+
+        let fixture = Fixture::new();
+        let expected = proto::PortStates::from(proto::PortMask::from(port(2)));
+        let mut ctx = fixture.sync_context();
+        assert_eq!(latch_and_read(&mut ctx), expected);
+        let mut client = SafeClient::new(ctx);
+        assert_eq!(latch_and_read(&mut client), expected);
     }
 }
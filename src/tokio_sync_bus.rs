@@ -0,0 +1,326 @@
+//! Provides a thread-safe, synchronous manager for several R413D08 modules on one bus.
+//!
+//! This module defines the [`Bus`] struct, which owns a single `tokio-modbus`
+//! `sync::Context` (typically one RS485 serial port) and hands out a
+//! [`BusDevice`] handle per slave address. All handles share the context through
+//! a `std::sync::Mutex`, so the traffic of all devices is serialized. Each
+//! transaction switches the slave address under the lock and keeps the silent
+//! interval between frames required by the Modbus RTU specification.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use r413d08_lib::{
+//!     protocol::{Address, Port},
+//!     tokio_common::serial_port_builder,
+//!     tokio_sync_bus::Bus,
+//! };
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let ctx = tokio_modbus::client::sync::rtu::connect(&serial_port_builder("/dev/ttyUSB0"))?;
+//! let bus = Bus::new(ctx);
+//!
+//! let pumps = bus.device(Address::try_from(1)?);
+//! let valves = bus.device(Address::try_from(2)?);
+//! pumps.set_port_open(Port::try_from(0)?)?;
+//! println!("Valves: {}", valves.read_ports()?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    protocol as proto,
+    tokio_common::{Result, INTER_FRAME_DELAY},
+    tokio_sync::{RelayController, R413D08},
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_modbus::{client::sync::Context, prelude::SlaveContext, Slave};
+
+/// The shared state of a bus: the context and the time of the last transaction.
+struct BusState {
+    ctx: Context,
+    inter_frame_delay: Duration,
+    last_transaction: Option<Instant>,
+}
+
+impl BusState {
+    /// Runs a transaction with the given slave after waiting for the inter-frame delay.
+    fn transaction<T>(
+        &mut self,
+        slave: Slave,
+        operation: impl FnOnce(&mut Context) -> Result<T>,
+    ) -> Result<T> {
+        if let Some(last) = self.last_transaction {
+            let elapsed = last.elapsed();
+            if elapsed < self.inter_frame_delay {
+                std::thread::sleep(self.inter_frame_delay - elapsed);
+            }
+        }
+        self.ctx.set_slave(slave);
+        let result = operation(&mut self.ctx).map_err(|err| err.with_slave(slave));
+        self.last_transaction = Some(Instant::now());
+        result
+    }
+}
+
+/// A thread-safe, synchronous manager for several R413D08 modules sharing one bus.
+///
+/// The bus owns the Modbus context and can be cheaply cloned. Use [`Bus::device`]
+/// to obtain a handle for the device with a given slave address.
+#[derive(Clone)]
+pub struct Bus {
+    state: Arc<Mutex<BusState>>,
+}
+
+impl Bus {
+    /// Creates a new `Bus` using the RTU [`INTER_FRAME_DELAY`] between transactions.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx`: A synchronous Modbus client context, already connected.
+    pub fn new(ctx: Context) -> Self {
+        Self::with_inter_frame_delay(ctx, INTER_FRAME_DELAY)
+    }
+
+    /// Creates a new `Bus` with a custom silent interval between two transactions.
+    ///
+    /// A longer delay can help with slow devices or RS485 adapters that need
+    /// time to switch the line direction.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx`: A synchronous Modbus client context, already connected.
+    /// * `inter_frame_delay`: The minimum time between the end of one transaction
+    ///   and the start of the next one.
+    pub fn with_inter_frame_delay(ctx: Context, inter_frame_delay: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(BusState {
+                ctx,
+                inter_frame_delay,
+                last_transaction: None,
+            })),
+        }
+    }
+
+    /// Returns a handle for the device with the given slave address.
+    ///
+    /// Handles are cheap to create and clone, and can be used from any thread.
+    pub fn device(&self, address: proto::Address) -> BusDevice {
+        BusDevice {
+            state: self.state.clone(),
+            address: Arc::new(Mutex::new(address)),
+        }
+    }
+}
+
+/// A handle for one R413D08 module on a shared [`Bus`].
+///
+/// Every operation locks the bus, switches the context to this device's slave
+/// address and performs the transaction. Clones of a handle share the address,
+/// so [`BusDevice::set_address`] updates all of them.
+#[derive(Clone)]
+pub struct BusDevice {
+    state: Arc<Mutex<BusState>>,
+    address: Arc<Mutex<proto::Address>>,
+}
+
+impl BusDevice {
+    /// Returns the slave address this handle communicates with.
+    pub fn address(&self) -> proto::Address {
+        *self.address.lock().unwrap()
+    }
+
+    /// Runs an operation on the bus context addressed to this device.
+    fn transaction<T>(&self, operation: impl FnOnce(&mut Context) -> Result<T>) -> Result<T> {
+        let mut state = self.state.lock().unwrap();
+        state.transaction(Slave(*self.address()), operation)
+    }
+
+    /// Writes the given [`proto::Command`] to the device.
+    ///
+    /// A [`proto::Command::SetAddress`] command updates the handle's address
+    /// just like [`BusDevice::set_address`].
+    pub fn execute(&self, command: proto::Command) -> Result<()> {
+        if let proto::Command::SetAddress(address) = command {
+            return self.set_address(address);
+        }
+        self.transaction(|ctx| R413D08::execute(ctx, command))
+    }
+
+    /// Reads the current status (Open/Close) of all ports.
+    pub fn read_ports(&self) -> Result<proto::PortStates> {
+        self.transaction(R413D08::read_ports)
+    }
+
+    /// Sets the specified port to the **Open** state.
+    pub fn set_port_open(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Open(port))
+    }
+
+    /// Sets **all** ports to the **Open** state.
+    pub fn set_all_open(&self) -> Result<()> {
+        self.execute(proto::Command::AllOpen)
+    }
+
+    /// Sets the specified port to the **Close** state.
+    pub fn set_port_close(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Close(port))
+    }
+
+    /// Sets **all** ports to the **Close** state.
+    pub fn set_all_close(&self) -> Result<()> {
+        self.execute(proto::Command::AllClose)
+    }
+
+    /// Toggles the current state of the specified port.
+    pub fn set_port_toggle(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Toggle(port))
+    }
+
+    /// Latches the specified port (opens it and closes all others).
+    pub fn set_port_latch(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Latch(port))
+    }
+
+    /// Activates the specified port momentarily.
+    pub fn set_port_momentary(&self, port: proto::Port) -> Result<()> {
+        self.execute(proto::Command::Momentary(port))
+    }
+
+    /// Activates the specified port with a delayed close.
+    pub fn set_port_delay(&self, port: proto::Port, delay: u8) -> Result<()> {
+        self.execute(proto::Command::Delay(port, delay))
+    }
+
+    /// Switches all ports to the given target mask with the fewest Modbus writes.
+    ///
+    /// The bus stays locked for the whole sequence, so no other device's
+    /// traffic interleaves.
+    pub fn apply_mask(&self, target: proto::PortMask) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let slave = Slave(*self.address());
+        let current = proto::PortMask::from(state.transaction(slave, R413D08::read_ports)?);
+        for command in proto::Command::plan_transition(current, target) {
+            state.transaction(slave, |ctx| R413D08::execute(ctx, command))?;
+        }
+        Ok(())
+    }
+
+    /// Switches all ports to the given target states with the fewest Modbus writes.
+    pub fn apply_states(&self, target: proto::PortStates) -> Result<()> {
+        self.apply_mask(target.into())
+    }
+
+    /// Reads the configured Modbus device address from the device itself.
+    pub fn read_address(&self) -> Result<proto::Address> {
+        self.transaction(R413D08::read_address)
+    }
+
+    /// Sets a new Modbus device address.
+    ///
+    /// Upon success, the handle (and all its clones) communicate with the
+    /// device using the new address.
+    pub fn set_address(&self, address: proto::Address) -> Result<()> {
+        self.transaction(|ctx| R413D08::set_address(ctx, address))?;
+        *self.address.lock().unwrap() = address;
+        Ok(())
+    }
+}
+
+impl RelayController for BusDevice {
+    fn read_ports(&mut self) -> Result<proto::PortStates> {
+        BusDevice::read_ports(self)
+    }
+
+    fn set_port_open(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_open(self, port)
+    }
+
+    fn set_all_open(&mut self) -> Result<()> {
+        BusDevice::set_all_open(self)
+    }
+
+    fn set_port_close(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_close(self, port)
+    }
+
+    fn set_all_close(&mut self) -> Result<()> {
+        BusDevice::set_all_close(self)
+    }
+
+    fn set_port_toggle(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_toggle(self, port)
+    }
+
+    fn set_port_latch(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_latch(self, port)
+    }
+
+    fn set_port_momentary(&mut self, port: proto::Port) -> Result<()> {
+        BusDevice::set_port_momentary(self, port)
+    }
+
+    fn set_port_delay(&mut self, port: proto::Port, delay: u8) -> Result<()> {
+        BusDevice::set_port_delay(self, port, delay)
+    }
+
+    fn read_address(&mut self) -> Result<proto::Address> {
+        BusDevice::read_address(self)
+    }
+
+    fn set_address(&mut self, address: proto::Address) -> Result<()> {
+        BusDevice::set_address(self, address)
+    }
+
+    fn execute(&mut self, command: proto::Command) -> Result<()> {
+        BusDevice::execute(self, command)
+    }
+
+    fn apply_mask(&mut self, target: proto::PortMask) -> Result<()> {
+        BusDevice::apply_mask(self, target)
+    }
+}
+
+#[cfg(all(test, feature = "simulator", feature = "tokio-tcp-sync"))]
+mod tests {
+    use super::*;
+    use crate::simulator::testing::{address, port, Fixture};
+
+    #[test]
+    fn bus_against_simulator() {
+        let fixture = Fixture::with_devices([address(2), address(5)]);
+        let bus = Bus::new(fixture.sync_context());
+        let first = bus.device(address(2));
+        let second = bus.device(address(5));
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| first.set_port_latch(port(1)).unwrap());
+            scope.spawn(|| second.apply_mask(proto::PortMask::from_bits(0x81)).unwrap());
+        });
+        assert_eq!(
+            first.read_ports().unwrap(),
+            proto::PortMask::from_bits(0x02).into()
+        );
+        assert_eq!(
+            fixture.open_ports_of(address(5)),
+            proto::PortMask::from_bits(0x81)
+        );
+
+        let renamed = second.clone();
+        renamed.set_address(address(7)).unwrap();
+        assert_eq!(second.address(), address(7));
+        assert_eq!(second.read_address().unwrap(), address(7));
+        assert_eq!(fixture.simulator.addresses(), [address(2), address(7)]);
+    }
+
+    #[test]
+    fn bus_device_with_broadcast_address() {
+        let fixture = Fixture::new();
+        let broadcast = Bus::new(fixture.sync_context()).device(proto::Address::BROADCAST);
+        assert_eq!(broadcast.address(), proto::Address::BROADCAST);
+        assert_eq!(broadcast.read_address().unwrap(), proto::Address::default());
+    }
+}
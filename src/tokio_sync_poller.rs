@@ -0,0 +1,281 @@
+//! Provides a background thread that polls a synchronous [`SafeClient`] for port changes.
+//!
+//! The [`Poller`] reads the ports at a configurable interval and invokes the
+//! registered callbacks when a port changes or the link to the device is lost.
+//! It polls through a clone of the client, so it shares the client's mutex and
+//! commands issued from other threads interleave correctly with the polls.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use r413d08_lib::{tokio_sync_poller::Poller, tokio_sync_safe_client::SafeClient};
+//! use std::time::Duration;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let socket_addr = "127.0.0.1:502".parse()?;
+//! let client = SafeClient::new(tokio_modbus::client::sync::tcp::connect(socket_addr)?);
+//!
+//! let poller = Poller::builder(client.clone(), Duration::from_millis(500))
+//!     .on_change(|change, _at| println!("{change}"))
+//!     // Invoked once per outage, not for every failed poll.
+//!     .on_link_failure(|err| eprintln!("Link lost: {err}"))
+//!     .spawn()?;
+//!
+//! // The client can still be used to switch ports.
+//! client.set_all_open()?;
+//!
+//! poller.set_interval(Duration::from_secs(2));
+//! poller.stop();
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    tokio_common::{
+        ChangeDetector, Error, PortChange, WatchEvent, WatchEventKind, MIN_POLL_INTERVAL,
+    },
+    tokio_sync_safe_client::SafeClient,
+};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A callback invoked for every event detected by the [`Poller`].
+type Callback = Box<dyn FnMut(&WatchEvent) + Send>;
+
+/// The settings shared between the [`Poller`] handle and its thread.
+#[derive(Debug)]
+struct Control {
+    interval: Duration,
+    stop: bool,
+}
+
+/// The state shared between the [`Poller`] handle and its thread.
+#[derive(Debug)]
+struct Shared {
+    control: Mutex<Control>,
+    wakeup: Condvar,
+}
+
+impl Shared {
+    /// Waits until the next poll is due, returns `false` if the poller was stopped.
+    ///
+    /// Changes of the interval take effect immediately, relative to the last poll.
+    fn wait_for_next_poll(&self, last_poll: Instant) -> bool {
+        let mut control = self.control.lock().unwrap();
+        loop {
+            if control.stop {
+                return false;
+            }
+            let remaining =
+                (last_poll + control.interval).saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return true;
+            }
+            control = self.wakeup.wait_timeout(control, remaining).unwrap().0;
+        }
+    }
+}
+
+/// Configures the callbacks of a [`Poller`] before it is started.
+///
+/// Created by [`Poller::builder`].
+pub struct PollerBuilder {
+    client: SafeClient,
+    interval: Duration,
+    callbacks: Vec<Callback>,
+}
+
+impl PollerBuilder {
+    /// Registers a callback invoked for every event, see [`WatchEvent`].
+    pub fn on_event(mut self, callback: impl FnMut(&WatchEvent) + Send + 'static) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Registers a callback invoked for every port that changed, with the time
+    /// the change was detected.
+    pub fn on_change(
+        self,
+        mut callback: impl FnMut(&PortChange, SystemTime) + Send + 'static,
+    ) -> Self {
+        self.on_event(move |event| {
+            if let WatchEventKind::PortChanged(change) = &event.kind {
+                callback(change, event.at);
+            }
+        })
+    }
+
+    /// Registers a callback invoked when the link to the device is lost.
+    ///
+    /// It is invoked for the first failed reading, whether the link worked
+    /// before or not, and not again until a reading succeeded.
+    pub fn on_link_failure(mut self, mut callback: impl FnMut(&Error) + Send + 'static) -> Self {
+        self.callbacks.push(Box::new(move |event| {
+            if let WatchEventKind::CommunicationLost(err) = &event.kind {
+                callback(err);
+            }
+        }));
+        self
+    }
+
+    /// Starts polling on a new thread.
+    ///
+    /// The first reading only establishes the baseline, so no change is
+    /// reported for it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the thread cannot be spawned.
+    pub fn spawn(self) -> std::io::Result<Poller> {
+        let shared = Arc::new(Shared {
+            control: Mutex::new(Control {
+                interval: self.interval,
+                stop: false,
+            }),
+            wakeup: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        let Self {
+            client,
+            mut callbacks,
+            ..
+        } = self;
+        let thread = std::thread::Builder::new()
+            .name("r413d08-poller".to_string())
+            .spawn(move || {
+                let mut detector = ChangeDetector::default();
+                loop {
+                    let last_poll = Instant::now();
+                    for event in detector.update(client.read_ports()) {
+                        for callback in &mut callbacks {
+                            callback(&event);
+                        }
+                    }
+                    if !thread_shared.wait_for_next_poll(last_poll) {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Poller {
+            shared,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Polls the ports of a [`SafeClient`] on a background thread and reports changes.
+///
+/// Dropping the poller stops the thread, just like [`Poller::stop`].
+pub struct Poller {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Poller {
+    /// Creates a builder for a poller that reads the ports at the given interval.
+    ///
+    /// # Arguments
+    ///
+    /// * `client`: The client to poll, usually a clone of the client the
+    ///   application uses to send commands.
+    /// * `interval`: The time between the start of two polls, raised to
+    ///   [`MIN_POLL_INTERVAL`] if shorter.
+    pub fn builder(client: SafeClient, interval: Duration) -> PollerBuilder {
+        PollerBuilder {
+            client,
+            interval: interval.max(MIN_POLL_INTERVAL),
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// Returns the current polling interval.
+    pub fn interval(&self) -> Duration {
+        self.shared.control.lock().unwrap().interval
+    }
+
+    /// Changes the polling interval, effective immediately.
+    ///
+    /// Intervals shorter than [`MIN_POLL_INTERVAL`] are raised to it.
+    pub fn set_interval(&self, interval: Duration) {
+        self.shared.control.lock().unwrap().interval = interval.max(MIN_POLL_INTERVAL);
+        self.shared.wakeup.notify_all();
+    }
+
+    /// Stops polling and waits for the thread to finish.
+    ///
+    /// A poll in progress is completed first, so this may block for up to the
+    /// client's timeout.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    /// Signals the thread to stop and joins it.
+    fn shutdown(&mut self) {
+        self.shared.control.lock().unwrap().stop = true;
+        self.shared.wakeup.notify_all();
+        if let Some(thread) = self.thread.take() {
+            // A panicking callback already ended the thread, there is nothing left to clean up.
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(all(test, feature = "simulator", feature = "tokio-tcp-sync"))]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol as proto,
+        simulator::testing::{address, holds_for, port, Fixture},
+    };
+    use std::sync::mpsc;
+
+    #[test]
+    fn poller_against_simulator() {
+        let fixture = Fixture::new();
+        let mut ctx = fixture.sync_context();
+        ctx.set_timeout(Duration::from_millis(50));
+        let client = SafeClient::new(ctx).with_slave(proto::Address::default());
+
+        let (changes, changes_rx) = mpsc::channel();
+        let (failures, failures_rx) = mpsc::channel();
+        let poller = Poller::builder(client.clone(), Duration::ZERO)
+            .on_change(move |change, _| changes.send(*change).unwrap())
+            .on_link_failure(move |err| failures.send(err.is_timeout()).unwrap())
+            .spawn()
+            .unwrap();
+        assert_eq!(poller.interval(), MIN_POLL_INTERVAL);
+
+        // A change before the first reading is part of the baseline, so toggle until one is seen.
+        let change = (0..50)
+            .find_map(|_| {
+                client.set_port_toggle(port(3)).unwrap();
+                changes_rx.recv_timeout(Duration::from_millis(100)).ok()
+            })
+            .expect("a port change is reported");
+        assert_eq!(change.port, port(3));
+        assert_eq!(change.to, client.read_ports().unwrap()[3]);
+
+        // A device that doesn't answer is reported once.
+        poller.set_interval(Duration::from_millis(20));
+        assert_eq!(poller.interval(), Duration::from_millis(20));
+        SafeClient::from_shared(client.clone_shared())
+            .set_address(address(9))
+            .unwrap();
+        assert!(failures_rx.recv_timeout(Duration::from_secs(1)).unwrap());
+        holds_for(
+            "no further link failure",
+            Duration::from_millis(200),
+            || failures_rx.try_recv().is_err(),
+        );
+        poller.stop();
+    }
+}
@@ -6,10 +6,32 @@
 //! that all operations are serialized, making it safe to share across multiple
 //! threads (e.g., using an `Arc<SafeClient>`).
 
-use crate::{protocol as proto, tokio_common::Result, tokio_sync::R413D08};
-use std::sync::{Arc, Mutex};
+use crate::{
+    protocol as proto,
+    timers::{self, DelayWindow, PortGenerations, TimedRun, TimedRunStep},
+    tokio_common::{self, Error, HeartbeatStatus, Result, RetryPolicy},
+    tokio_sync::{RelayController, R413D08},
+    tokio_sync_scanner,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tokio_modbus::{client::sync::Context, prelude::SlaveContext, Slave};
 
+/// A function that establishes a new connection, see [`SafeClient::with_reconnect`].
+type Connect = dyn Fn() -> std::io::Result<Context> + Send + Sync;
+
+/// The state required to rebuild a broken connection.
+struct Reconnect {
+    connect: Box<Connect>,
+    /// Set after a transport failure, the context is rebuilt before the next use.
+    stale: AtomicBool,
+}
+
 /// A thread-safe, synchronous client for an R413D08 relay module.
 ///
 /// This client encapsulates a [`tokio_modbus::client::sync::Context`] within an
@@ -21,6 +43,13 @@ use tokio_modbus::{client::sync::Context, prelude::SlaveContext, Slave};
 /// the client's internal slave ID after successfully changing the device's
 /// Modbus address, preventing desynchronization errors.
 ///
+/// Created with [`SafeClient::with_reconnect`], the client transparently
+/// rebuilds its connection after a transport failure and retries transient
+/// errors according to its [`RetryPolicy`].
+///
+/// The client implements [`crate::tokio_sync::RelayController`], so it can be passed
+/// to code written against that trait.
+///
 /// # Example
 ///
 /// ```no_run
@@ -53,18 +82,91 @@ use tokio_modbus::{client::sync::Context, prelude::SlaveContext, Slave};
 #[derive(Clone)]
 pub struct SafeClient {
     ctx: Arc<Mutex<Context>>,
+    /// The slave ID of the device if known, applied before every operation.
+    slave: Arc<Mutex<Option<Slave>>>,
+    reconnect: Option<Arc<Reconnect>>,
+    retry_policy: RetryPolicy,
+    /// The commands written to each port, see [`SafeClient::pulse`].
+    generations: Arc<PortGenerations>,
 }
 
 impl SafeClient {
     /// Creates a new `SafeClient` instance.
     ///
+    /// The client does not retry failed operations, see [`SafeClient::with_retry_policy`].
+    ///
     /// # Arguments
     ///
     /// * `ctx`: A synchronous Modbus client context, already connected.
     pub fn new(ctx: Context) -> Self {
-        Self {
+        Self::from_shared(Arc::new(Mutex::new(ctx)))
+    }
+
+    /// Creates a new `SafeClient` that rebuilds its connection after transport failures.
+    ///
+    /// The `connect` function is called once to establish the initial connection
+    /// and again whenever a transport failure (e.g., a replugged USB-RS485
+    /// adapter or a dropped TCP connection) left the context unusable. It must
+    /// return a context configured for the device, including its slave ID.
+    ///
+    /// The client uses the default [`RetryPolicy`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the initial connection cannot be established.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use r413d08_lib::{
+    ///     protocol::Address, tokio_common::serial_port_builder,
+    ///     tokio_sync_safe_client::SafeClient,
+    /// };
+    /// use tokio_modbus::{client::sync::rtu, Slave};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let builder = serial_port_builder("/dev/ttyUSB0");
+    /// let client = SafeClient::with_reconnect(move || {
+    ///     rtu::connect_slave(&builder, Slave(*Address::default()))
+    /// })?;
+    /// println!("Port status: {}", client.read_ports()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_reconnect(
+        connect: impl Fn() -> std::io::Result<Context> + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let ctx = connect().map_err(tokio_modbus::Error::Transport)?;
+        Ok(Self {
             ctx: Arc::new(Mutex::new(ctx)),
-        }
+            slave: Arc::default(),
+            reconnect: Some(Arc::new(Reconnect {
+                connect: Box::new(connect),
+                stale: AtomicBool::new(false),
+            })),
+            retry_policy: RetryPolicy::default(),
+            generations: Arc::default(),
+        })
+    }
+
+    /// Sets the slave address of the device.
+    ///
+    /// The client then addresses every request to this device, even if the
+    /// connection was rebuilt or the shared context was used for another
+    /// device in the meantime. The address is also reported in errors.
+    pub fn with_slave(self, address: proto::Address) -> Self {
+        *self.slave.lock().unwrap() = Some(Slave(*address));
+        self
+    }
+
+    /// Sets the policy for retrying operations that failed with a transient error.
+    ///
+    /// Operations that are not idempotent ([`SafeClient::set_port_toggle`] and
+    /// [`SafeClient::set_address`]) are never retried, since the device may have
+    /// executed the command although the response got lost.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
     }
 
     /// Creates a new `SafeClient` from an existing `Arc<Mutex<Context>>`.
@@ -72,69 +174,341 @@ impl SafeClient {
     /// This allows multiple `SafeClient` instances to share the exact same
     /// underlying connection context.
     pub fn from_shared(ctx: Arc<Mutex<Context>>) -> Self {
-        Self { ctx }
+        Self {
+            ctx,
+            slave: Arc::default(),
+            reconnect: None,
+            retry_policy: RetryPolicy::NONE,
+            generations: Arc::default(),
+        }
     }
 
     /// Clones and returns the underlying `Arc<Mutex<Context>>`.
     ///
     /// This allows the shared context to be used by other parts of an
     /// application that may need direct access to the Modbus context.
+    /// A reconnect replaces the context inside the mutex, so the shared
+    /// context stays valid.
     pub fn clone_shared(&self) -> Arc<Mutex<Context>> {
         self.ctx.clone()
     }
 
+    /// Runs an operation on the locked context, reconnecting and retrying as configured.
+    fn with_context<T>(
+        &self,
+        idempotent: bool,
+        mut operation: impl FnMut(&mut Context) -> Result<T>,
+    ) -> Result<T> {
+        let mut guard = self.ctx.lock().unwrap();
+        let mut retry = 0;
+        loop {
+            let slave = *self.slave.lock().unwrap();
+            let result = self
+                .reconnect_if_stale(&mut guard)
+                .and_then(|()| {
+                    if let Some(slave) = slave {
+                        guard.set_slave(slave);
+                    }
+                    operation(&mut guard)
+                })
+                .map_err(|err| match slave {
+                    Some(slave) => err.with_slave(slave),
+                    None => err,
+                });
+            match result {
+                Err(err) if err.is_transient() => {
+                    if let Some(reconnect) = &self.reconnect {
+                        reconnect.stale.store(true, Ordering::Relaxed);
+                    }
+                    if !idempotent || retry >= self.retry_policy.max_retries {
+                        return Err(err);
+                    }
+                    std::thread::sleep(self.retry_policy.backoff(retry));
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Replaces the context with a new connection if the last one failed.
+    fn reconnect_if_stale(&self, ctx: &mut Context) -> Result<()> {
+        let Some(reconnect) = &self.reconnect else {
+            return Ok(());
+        };
+        if reconnect.stale.load(Ordering::Relaxed) {
+            *ctx = (reconnect.connect)().map_err(tokio_modbus::Error::Transport)?;
+            reconnect.stale.store(false, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Writes the given [`proto::Command`] to the device.
+    ///
+    /// A [`proto::Command::SetAddress`] command updates the client's slave ID
+    /// just like [`SafeClient::set_address`].
+    pub fn execute(&self, command: proto::Command) -> Result<()> {
+        if let proto::Command::SetAddress(address) = command {
+            return self.set_address(address);
+        }
+        let idempotent = !matches!(command, proto::Command::Toggle(_));
+        self.with_context(idempotent, |ctx| {
+            R413D08::execute(ctx, command)?;
+            self.generations.touch(timers::touched_ports(&command));
+            Ok(())
+        })
+    }
+
     /// Reads the current status (Open/Close) of all ports.
     pub fn read_ports(&self) -> Result<proto::PortStates> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::read_ports(&mut guard)
+        self.with_context(true, R413D08::read_ports)
     }
 
     /// Sets the specified port to the **Open** state.
     pub fn set_port_open(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_port_open(&mut guard, port)
+        self.execute(proto::Command::Open(port))
     }
 
     /// Sets **all** ports to the **Open** state.
     pub fn set_all_open(&self) -> Result<()> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_all_open(&mut guard)
+        self.execute(proto::Command::AllOpen)
     }
 
     /// Sets the specified port to the **Close** state.
     pub fn set_port_close(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_port_close(&mut guard, port)
+        self.execute(proto::Command::Close(port))
     }
 
     /// Sets **all** ports to the **Close** state.
     pub fn set_all_close(&self) -> Result<()> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_all_close(&mut guard)
+        self.execute(proto::Command::AllClose)
     }
 
     /// Toggles the current state of the specified port.
     pub fn set_port_toggle(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_port_toggle(&mut guard, port)
+        self.execute(proto::Command::Toggle(port))
     }
 
     /// Latches the specified port (opens it and closes all others).
     pub fn set_port_latch(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_port_latch(&mut guard, port)
+        self.execute(proto::Command::Latch(port))
     }
 
     /// Activates the specified port momentarily.
     pub fn set_port_momentary(&self, port: proto::Port) -> Result<()> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_port_momentary(&mut guard, port)
+        self.execute(proto::Command::Momentary(port))
     }
 
     /// Activates the specified port with a delayed close.
     pub fn set_port_delay(&self, port: proto::Port, delay: u8) -> Result<()> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_port_delay(&mut guard, port, delay)
+        self.execute(proto::Command::Delay(port, delay))
+    }
+
+    /// Opens the specified port for the given duration.
+    ///
+    /// A whole number of seconds from 1 to 255 uses the delay command of the
+    /// device ([`SafeClient::set_port_delay`]) and returns right away. Any other
+    /// duration, e.g. 350 ms or 2.5 s, is timed in software: the port is opened,
+    /// and this call blocks until it closes the port again.
+    ///
+    /// The client is not locked while waiting, so other threads can use it in
+    /// the meantime. If any of them sends a command affecting the port through
+    /// this client or one of its clones (including another pulse), the later
+    /// command wins and the port is not closed by this pulse.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use r413d08_lib::{protocol::Port, tokio_sync_safe_client::SafeClient};
+    /// use std::time::Duration;
+    ///
+    /// # fn example(client: SafeClient) -> Result<(), Box<dyn std::error::Error>> {
+    /// // Run the dosing pump on port 2 for 350 ms
+    /// client.pulse(Port::try_from(2)?, Duration::from_millis(350))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pulse(&self, port: proto::Port, duration: Duration) -> Result<()> {
+        if let Some(delay) = tokio_common::device_delay(duration) {
+            return self.set_port_delay(port, delay);
+        }
+        self.pulse_with(port, duration, |remaining| {
+            std::thread::sleep(remaining);
+            true
+        })?;
+        Ok(())
+    }
+
+    /// Opens the specified port for the given duration, waiting for it with `wait`.
+    ///
+    /// This is the blocking part of [`SafeClient::pulse`] with a custom wait,
+    /// e.g. one that can be interrupted. Whole seconds from 1 to 255 still use
+    /// the delay command of the device. `wait` is called with the time the port
+    /// stays open and returns `false` to end the pulse early. The port is then
+    /// closed right away, as is a pulse timed in software at its end, unless a
+    /// later command affected the port in the meantime. Returns the result of
+    /// `wait`.
+    pub fn pulse_with(
+        &self,
+        port: proto::Port,
+        duration: Duration,
+        wait: impl FnOnce(Duration) -> bool,
+    ) -> Result<bool> {
+        let delay = tokio_common::device_delay(duration);
+        let (generation, opened) = self.with_context(true, |ctx| {
+            match delay {
+                Some(delay) => R413D08::set_port_delay(ctx, port, delay)?,
+                None => R413D08::set_port_open(ctx, port)?,
+            }
+            Ok((self.generations.claim(port), Instant::now()))
+        })?;
+        let completed = wait((opened + duration).saturating_duration_since(Instant::now()));
+        if completed && delay.is_some() {
+            return Ok(true);
+        }
+        self.with_context(true, |ctx| {
+            if self.generations.is_current(port, generation) {
+                R413D08::set_port_close(ctx, port)?;
+                self.generations.touch(port.into());
+            }
+            Ok(())
+        })?;
+        Ok(completed)
+    }
+
+    /// Opens the specified port until the given duration has passed, even beyond 255 seconds.
+    ///
+    /// The delay command of the device counts at most 255 seconds. For a longer
+    /// duration, e.g. a 45 minute irrigation run, a background thread chains
+    /// delay commands and re-arms each one shortly before it runs out. The port
+    /// is always closed by the device itself, so if this process dies, the relay
+    /// still turns off within 255 seconds. The duration has a resolution of one
+    /// second, use [`SafeClient::pulse`] for shorter, more precise durations.
+    ///
+    /// The first delay command is sent before this call returns. The returned
+    /// [`TimedPort`] extends or cancels the run, dropping it leaves the run
+    /// going. A later command affecting the port through this client or one of
+    /// its clones ends the run when it is re-armed next, and the port keeps the
+    /// state set by that command.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the first delay command fails, or if the duration
+    /// reaches beyond the times the clock can represent.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use r413d08_lib::{protocol::Port, tokio_sync_safe_client::SafeClient};
+    /// use std::time::Duration;
+    ///
+    /// # fn example(client: SafeClient) -> Result<(), Box<dyn std::error::Error>> {
+    /// // Water zone 4 for 45 minutes, then another 10 minutes
+    /// let run = client.set_port_for(Port::try_from(4)?, Duration::from_secs(45 * 60))?;
+    /// run.extend(Duration::from_secs(10 * 60));
+    /// run.wait()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_port_for(&self, port: proto::Port, duration: Duration) -> Result<TimedPort> {
+        let deadline = timers::deadline_after(duration)?;
+        let window = self
+            .arm_window(port, deadline, None)?
+            .expect("a new run is never superseded");
+        let shared = Arc::new((Mutex::new(TimedRun::new(deadline)), Condvar::new()));
+        let worker = {
+            let client = self.clone();
+            let shared = shared.clone();
+            std::thread::spawn(move || client.keep_armed(port, window, &shared))
+        };
+        Ok(TimedPort {
+            port,
+            shared,
+            worker,
+        })
+    }
+
+    /// Arms the next delay window of a timed run, `None` if the run was superseded.
+    fn arm_window(
+        &self,
+        port: proto::Port,
+        deadline: Instant,
+        generation: Option<u64>,
+    ) -> Result<Option<DelayWindow>> {
+        self.with_context(true, |ctx| {
+            if generation.is_some_and(|generation| !self.generations.is_current(port, generation)) {
+                return Ok(None);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let delay = DelayWindow::delay(remaining);
+            R413D08::set_port_delay(ctx, port, delay)?;
+            let generation = self.generations.claim(port);
+            Ok(Some(DelayWindow::armed(
+                generation,
+                Instant::now(),
+                delay,
+                remaining,
+            )))
+        })
+    }
+
+    /// Keeps re-arming the delay windows of a timed run until it is over.
+    fn keep_armed(
+        &self,
+        port: proto::Port,
+        mut window: DelayWindow,
+        (lock, wake): &(Mutex<TimedRun>, Condvar),
+    ) -> Result<()> {
+        let mut run = lock.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            match run.next_step(&window, now) {
+                TimedRunStep::Close => {
+                    drop(run);
+                    return self.with_context(true, |ctx| {
+                        if self.generations.is_current(port, window.generation) {
+                            R413D08::set_port_close(ctx, port)?;
+                            self.generations.touch(port.into());
+                        }
+                        Ok(())
+                    });
+                }
+                TimedRunStep::Rearm(deadline) => {
+                    drop(run);
+                    match self.arm_window(port, deadline, Some(window.generation)) {
+                        Ok(Some(next)) => window = next,
+                        Ok(None) => return Ok(()),
+                        Err(err) if !window.retry(now) => return Err(err),
+                        Err(_) => {}
+                    }
+                    run = lock.lock().unwrap();
+                }
+                TimedRunStep::Finish => return Ok(()),
+                TimedRunStep::WaitUntil(at) => {
+                    run = wake.wait_timeout(run, at - now).unwrap().0;
+                }
+            }
+        }
+    }
+
+    /// Switches all ports to the given target mask with the fewest Modbus writes.
+    ///
+    /// The current states are read and the commands are written while holding
+    /// the lock, so no other operation can interleave. A retry starts over by
+    /// reading the current states again.
+    pub fn apply_mask(&self, target: proto::PortMask) -> Result<()> {
+        self.with_context(true, |ctx| {
+            R413D08::apply_mask(ctx, target)?;
+            self.generations.touch(proto::PortMask::ALL);
+            Ok(())
+        })
+    }
+
+    /// Switches all ports to the given target states with the fewest Modbus writes.
+    ///
+    /// See [`SafeClient::apply_mask`] for details.
+    pub fn apply_states(&self, target: proto::PortStates) -> Result<()> {
+        self.apply_mask(target.into())
     }
 
     /// Reads the configured Modbus device address.
@@ -142,8 +516,7 @@ impl SafeClient {
     /// It's recommended to use the broadcast address for this operation,
     /// ensuring only one device is on the bus.
     pub fn read_address(&self) -> Result<proto::Address> {
-        let mut guard = self.ctx.lock().unwrap();
-        R413D08::read_address(&mut guard)
+        self.with_context(true, R413D08::read_address)
     }
 
     /// Sets a new Modbus device address.
@@ -153,9 +526,593 @@ impl SafeClient {
     /// internal slave ID to match. This keeps the client synchronized with the
     /// device state, preventing subsequent communication errors.
     pub fn set_address(&self, address: proto::Address) -> Result<()> {
+        self.with_context(false, |ctx| {
+            R413D08::set_address(ctx, address)?;
+            ctx.set_slave(Slave(*address));
+            Ok(())
+        })?;
+        *self.slave.lock().unwrap() = Some(Slave(*address));
+        Ok(())
+    }
+
+    /// Probes the given slave addresses for devices on the bus of this client.
+    ///
+    /// See [`tokio_sync_scanner::scan_with_progress`] for the arguments. The
+    /// probes readdress the shared context, so the client restores its slave
+    /// afterwards (see [`SafeClient::with_slave`]). A client without a slave
+    /// address is left at [`proto::Address::BROADCAST`], the default slave of
+    /// TCP connections.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the transport fails. The scan is not retried.
+    pub fn scan_with_progress(
+        &self,
+        addresses: impl IntoIterator<Item = proto::Address>,
+        timeout: Duration,
+        on_probe: impl FnMut(proto::Address, Option<&tokio_sync_scanner::ProbeOutcome>),
+    ) -> Result<tokio_sync_scanner::ScanReport> {
         let mut guard = self.ctx.lock().unwrap();
-        R413D08::set_address(&mut guard, address)?;
-        guard.set_slave(Slave(*address));
+        let result = self.reconnect_if_stale(&mut guard).and_then(|()| {
+            tokio_sync_scanner::scan_with_progress(&mut guard, addresses, timeout, on_probe)
+        });
+        let slave = self.slave.lock().unwrap().unwrap_or_else(Slave::tcp_device);
+        guard.set_slave(slave);
+        if let (Err(err), Some(reconnect)) = (&result, &self.reconnect) {
+            if err.is_transient() {
+                reconnect.stale.store(true, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+/// A port opened with [`SafeClient::set_port_for`].
+///
+/// Dropping the handle does not stop the run, the port still closes at its deadline.
+pub struct TimedPort {
+    port: proto::Port,
+    shared: Arc<(Mutex<TimedRun>, Condvar)>,
+    worker: std::thread::JoinHandle<Result<()>>,
+}
+
+impl TimedPort {
+    /// Returns the port of the run.
+    pub fn port(&self) -> proto::Port {
+        self.port
+    }
+
+    /// Returns the time the port closes.
+    pub fn deadline(&self) -> Instant {
+        self.shared.0.lock().unwrap().deadline
+    }
+
+    /// Returns the time left until the port closes.
+    pub fn remaining(&self) -> Duration {
+        self.deadline().saturating_duration_since(Instant::now())
+    }
+
+    /// Returns `true` if the run is over.
+    pub fn is_finished(&self) -> bool {
+        self.worker.is_finished()
+    }
+
+    /// Moves the deadline of the run later by the given duration.
+    ///
+    /// The delay of the device is re-armed right away. Returns `false` if the
+    /// run is already over or the new deadline is beyond the times the clock
+    /// can represent.
+    pub fn extend(&self, by: Duration) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        let (run, wake) = &*self.shared;
+        let extended = run.lock().unwrap().extend(by);
+        if extended {
+            wake.notify_one();
+        }
+        extended
+    }
+
+    /// Closes the port right away and ends the run.
+    ///
+    /// The port is left alone if a later command already superseded the run.
+    pub fn cancel(self) -> Result<()> {
+        let (run, wake) = &*self.shared;
+        run.lock().unwrap().cancelled = true;
+        wake.notify_one();
+        self.wait()
+    }
+
+    /// Blocks until the run is over.
+    ///
+    /// Fails if the delay could not be re-armed before it ran out, in which
+    /// case the device closed the port before the deadline.
+    pub fn wait(self) -> Result<()> {
+        self.worker
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+/// A function called for every missed heartbeat, see [`Heartbeat::on_missed`].
+type OnMissed = dyn Fn(&Error, &HeartbeatStatus) + Send + Sync;
+
+/// Keeps selected relays open only while the controller is alive.
+///
+/// A background thread keeps re-issuing the delay command of the device
+/// ([`proto::Port::REG_DATA_SET_PORT_DELAY`]) for each port well before its
+/// timeout runs out. If the process, the host or the bus link dies, the
+/// heartbeats stop and the device drops the relays by itself once the timeout
+/// has passed. Dropping a running `Heartbeat` has the same effect, use
+/// [`Heartbeat::stop`] to close the relays right away.
+///
+/// # Example
+///
+/// ```no_run
+/// use r413d08_lib::{
+///     protocol::{Port, PortMask},
+///     tokio_sync_safe_client::{Heartbeat, SafeClient},
+/// };
+///
+/// # fn example(client: SafeClient) -> Result<(), Box<dyn std::error::Error>> {
+/// let mut ports = PortMask::NONE;
+/// ports.insert(Port::try_from(0)?);
+/// // Keep the conveyor running while we are alive, stop within 3 s otherwise
+/// let mut heartbeat = Heartbeat::new(client, ports, 3)
+///     .on_missed(|err, status| eprintln!("Missed heartbeat #{}: {err}", status.consecutive_missed));
+/// heartbeat.start()?;
+/// // ... run the conveyor ...
+/// heartbeat.stop()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Heartbeat {
+    client: SafeClient,
+    ports: proto::PortMask,
+    timeout: u8,
+    interval: Duration,
+    on_missed: Option<Arc<OnMissed>>,
+    status: Arc<Mutex<HeartbeatStatus>>,
+    /// The channel stopping the running worker thread, disconnected when dropped.
+    worker: Option<(mpsc::Sender<()>, std::thread::JoinHandle<()>)>,
+}
+
+impl Heartbeat {
+    /// Creates a stopped heartbeat for the given ports and device timeout in seconds.
+    ///
+    /// A timeout of 0 is raised to 1 second. By default, three heartbeats are
+    /// sent per timeout.
+    pub fn new(client: SafeClient, ports: proto::PortMask, timeout: u8) -> Self {
+        Self {
+            client,
+            ports,
+            timeout: timeout.max(1),
+            interval: timers::heartbeat_interval(timeout),
+            on_missed: None,
+            status: Arc::default(),
+            worker: None,
+        }
+    }
+
+    /// Sets the time between two heartbeats.
+    ///
+    /// The interval is limited to at least 10 ms and at most half the timeout,
+    /// so a single missed heartbeat does not drop the relays.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = timers::clamp_heartbeat_interval(interval, self.timeout);
+        self
+    }
+
+    /// Calls the given function from the worker thread for every missed heartbeat.
+    pub fn on_missed(
+        mut self,
+        on_missed: impl Fn(&Error, &HeartbeatStatus) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_missed = Some(Arc::new(on_missed));
+        self
+    }
+
+    /// Sends the first heartbeat, opening the ports, and starts the worker thread.
+    ///
+    /// Fails without starting if the first heartbeat fails. Does nothing if
+    /// the heartbeat is already running.
+    pub fn start(&mut self) -> Result<()> {
+        if self.is_running() {
+            return Ok(());
+        }
+        let result = self.beat();
+        self.status.lock().unwrap().record(result.is_ok());
+        result?;
+        let (stop, stopped) = mpsc::channel();
+        let heartbeat = Self {
+            client: self.client.clone(),
+            status: self.status.clone(),
+            on_missed: self.on_missed.clone(),
+            worker: None,
+            ..*self
+        };
+        let worker = std::thread::spawn(move || heartbeat.keep_beating(&stopped));
+        self.worker = Some((stop, worker));
         Ok(())
     }
+
+    /// Stops the worker thread and closes the ports.
+    ///
+    /// Does nothing if the heartbeat is not running.
+    pub fn stop(&mut self) -> Result<()> {
+        let Some((stop, worker)) = self.worker.take() else {
+            return Ok(());
+        };
+        drop(stop);
+        worker
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        self.ports
+            .iter()
+            .try_for_each(|port| self.client.set_port_close(port))
+    }
+
+    /// Returns `true` if the worker thread is running.
+    pub fn is_running(&self) -> bool {
+        self.worker.is_some()
+    }
+
+    /// Returns the counters of the heartbeats sent so far.
+    pub fn status(&self) -> HeartbeatStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// Returns `true` if a heartbeat reached the device within the timeout, i.e. the relays are held.
+    pub fn is_alive(&self) -> bool {
+        self.status()
+            .is_alive(Duration::from_secs(self.timeout.into()))
+    }
+
+    /// Re-issues the delay command for all ports.
+    fn beat(&self) -> Result<()> {
+        self.ports
+            .iter()
+            .try_for_each(|port| self.client.set_port_delay(port, self.timeout))
+    }
+
+    /// Sends the heartbeats until the stop channel is disconnected.
+    fn keep_beating(&self, stopped: &mpsc::Receiver<()>) {
+        let mut next = Instant::now() + self.interval;
+        loop {
+            let timeout = next.saturating_duration_since(Instant::now());
+            if stopped.recv_timeout(timeout) != Err(mpsc::RecvTimeoutError::Timeout) {
+                return;
+            }
+            next = timers::next_beat(next, self.interval, Instant::now());
+            let result = self.beat();
+            let status = {
+                let mut status = self.status.lock().unwrap();
+                status.record(result.is_ok());
+                *status
+            };
+            if let (Err(err), Some(on_missed)) = (&result, &self.on_missed) {
+                on_missed(err, &status);
+            }
+        }
+    }
+}
+
+impl RelayController for SafeClient {
+    fn read_ports(&mut self) -> Result<proto::PortStates> {
+        SafeClient::read_ports(self)
+    }
+
+    fn set_port_open(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_open(self, port)
+    }
+
+    fn set_all_open(&mut self) -> Result<()> {
+        SafeClient::set_all_open(self)
+    }
+
+    fn set_port_close(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_close(self, port)
+    }
+
+    fn set_all_close(&mut self) -> Result<()> {
+        SafeClient::set_all_close(self)
+    }
+
+    fn set_port_toggle(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_toggle(self, port)
+    }
+
+    fn set_port_latch(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_latch(self, port)
+    }
+
+    fn set_port_momentary(&mut self, port: proto::Port) -> Result<()> {
+        SafeClient::set_port_momentary(self, port)
+    }
+
+    fn set_port_delay(&mut self, port: proto::Port, delay: u8) -> Result<()> {
+        SafeClient::set_port_delay(self, port, delay)
+    }
+
+    fn read_address(&mut self) -> Result<proto::Address> {
+        SafeClient::read_address(self)
+    }
+
+    fn set_address(&mut self, address: proto::Address) -> Result<()> {
+        SafeClient::set_address(self, address)
+    }
+
+    fn execute(&mut self, command: proto::Command) -> Result<()> {
+        SafeClient::execute(self, command)
+    }
+
+    fn apply_mask(&mut self, target: proto::PortMask) -> Result<()> {
+        SafeClient::apply_mask(self, target)
+    }
+
+    fn pulse_with(
+        &mut self,
+        port: proto::Port,
+        duration: Duration,
+        wait: &mut dyn FnMut(Duration) -> bool,
+    ) -> Result<bool> {
+        SafeClient::pulse_with(self, port, duration, wait)
+    }
+}
+
+#[cfg(all(test, feature = "simulator", feature = "tokio-tcp-sync"))]
+mod tests {
+    use super::*;
+    use crate::simulator::testing::{
+        address, closing_listener, holds_for, port, wait_until, Fixture,
+    };
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn safe_client_pulses() {
+        let fixture = Fixture::new();
+        let client = fixture.sync_client();
+
+        // Whole seconds use the device timer and return right away.
+        let started = Instant::now();
+        client.pulse(port(1), Duration::from_secs(2)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(fixture.is_open(1));
+
+        // A second pulse started during the first one extends it.
+        let pulse = |duration| {
+            let client = client.clone();
+            std::thread::spawn(move || client.pulse(port(2), duration))
+        };
+        let first = pulse(Duration::from_millis(300));
+        wait_until("the first pulse opened port 2", || fixture.is_open(2));
+        let second = pulse(Duration::from_millis(600));
+        first.join().unwrap().unwrap();
+        assert!(fixture.is_open(2));
+        second.join().unwrap().unwrap();
+        assert!(!fixture.is_open(2));
+    }
+
+    #[test]
+    fn safe_client_timed_ports() {
+        let fixture = Fixture::new();
+        let client = fixture.sync_client();
+
+        // Extending the run re-arms the device delay.
+        let started = Instant::now();
+        let run = client
+            .set_port_for(port(1), Duration::from_secs(2))
+            .unwrap();
+        assert!(fixture.is_open(1));
+        assert!(run.extend(Duration::from_secs(1)));
+        holds_for(
+            "the extended run keeps port 1 open",
+            Duration::from_millis(2500),
+            || fixture.is_open(1),
+        );
+        run.wait().unwrap();
+        assert!(!fixture.is_open(1));
+        assert!(started.elapsed() >= Duration::from_millis(2900));
+
+        // Cancelling closes the port right away.
+        let run = client
+            .set_port_for(port(2), Duration::from_secs(3600))
+            .unwrap();
+        assert!(fixture.is_open(2));
+        assert!(run.remaining() > Duration::from_secs(3590));
+        assert!(!run.extend(Duration::MAX));
+        assert!(run.remaining() < Duration::from_secs(3600));
+        run.cancel().unwrap();
+        assert!(!fixture.is_open(2));
+
+        // A later command supersedes the run, which then leaves the port alone.
+        let run = client
+            .set_port_for(port(3), Duration::from_secs(3600))
+            .unwrap();
+        client.set_port_close(port(3)).unwrap();
+        assert!(run.extend(Duration::ZERO));
+        run.wait().unwrap();
+        assert!(!fixture.is_open(3));
+
+        // A run beyond the clock is rejected before the port is touched.
+        assert!(client.set_port_for(port(4), Duration::MAX).is_err());
+        assert!(!fixture.is_open(4));
+    }
+
+    #[test]
+    fn heartbeat_holds_relays_while_alive() {
+        let fixture = Fixture::new();
+        let client = fixture.sync_client();
+        let ports = proto::PortMask::from_bits(0b0000_0110);
+
+        // Intervals that would flood the bus or miss the timeout are limited.
+        let heartbeat = Heartbeat::new(client.clone(), ports, 1);
+        assert_eq!(
+            heartbeat.with_interval(Duration::from_secs(5)).interval,
+            Duration::from_millis(500)
+        );
+
+        // The heartbeats keep the ports open beyond the timeout, stopping closes them.
+        let mut heartbeat =
+            Heartbeat::new(client.clone(), ports, 1).with_interval(Duration::from_millis(300));
+        heartbeat.start().unwrap();
+        assert!(heartbeat.is_running());
+        holds_for(
+            "the heartbeats hold ports 1 and 2",
+            Duration::from_millis(1500),
+            || fixture.open_ports() == ports,
+        );
+        assert!(heartbeat.is_alive());
+        wait_until("five heartbeats reached the device", || {
+            heartbeat.status().beats >= 5
+        });
+        assert_eq!(heartbeat.status().missed, 0);
+        heartbeat.stop().unwrap();
+        assert!(!heartbeat.is_running());
+        assert_eq!(fixture.open_ports(), proto::PortMask::NONE);
+
+        // Without heartbeats, the device drops the relays after the timeout.
+        let mut heartbeat = Heartbeat::new(client, ports, 1);
+        heartbeat.start().unwrap();
+        drop(heartbeat);
+        assert_eq!(fixture.open_ports(), ports);
+        wait_until("the device dropped the relays", || {
+            fixture.open_ports() == proto::PortMask::NONE
+        });
+    }
+
+    #[test]
+    fn heartbeat_reports_missed_beats() {
+        // Two devices answering the broadcast address garble every heartbeat.
+        let fixture = Fixture::with_devices([address(1), address(2)]);
+        let client = SafeClient::new(fixture.sync_context()).with_slave(proto::Address::BROADCAST);
+        let mut heartbeat = Heartbeat::new(client, port(0).into(), 1);
+        assert!(heartbeat.start().is_err());
+        assert!(!heartbeat.is_running());
+        assert_eq!(heartbeat.status().missed, 1);
+
+        // Once running, missed heartbeats are reported until they succeed again.
+        let ctx = tokio_modbus::client::sync::tcp::connect_with_timeout(
+            fixture.socket_addr,
+            Some(Duration::from_millis(200)),
+        )
+        .unwrap();
+        let client = SafeClient::new(ctx).with_slave(address(1));
+        let other = SafeClient::new(fixture.sync_context()).with_slave(address(1));
+        let missed = Arc::new(Mutex::new(Vec::new()));
+        let mut heartbeat = Heartbeat::new(client, port(0).into(), 1)
+            .with_interval(Duration::from_millis(100))
+            .on_missed({
+                let missed = missed.clone();
+                move |_, status| missed.lock().unwrap().push(status.consecutive_missed)
+            });
+        heartbeat.start().unwrap();
+        other.set_address(address(3)).unwrap();
+        wait_until("two heartbeats were missed", || {
+            missed.lock().unwrap().len() >= 2
+        });
+        other.set_address(address(1)).unwrap();
+        let beats = heartbeat.status().beats;
+        wait_until("the heartbeats reach the device again", || {
+            heartbeat.status().beats > beats
+        });
+        heartbeat.stop().unwrap();
+        assert_eq!(missed.lock().unwrap()[..2], [1, 2]);
+        assert_eq!(heartbeat.status().consecutive_missed, 0);
+    }
+
+    #[test]
+    fn safe_client_against_simulator() {
+        let fixture = Fixture::new();
+        let client = SafeClient::new(fixture.sync_context());
+        client.set_port_open(port(5)).unwrap();
+        assert_eq!(client.read_ports().unwrap()[5], proto::PortState::Open);
+        assert_eq!(
+            fixture.simulator.port_states(proto::Address::default()),
+            Some(client.read_ports().unwrap())
+        );
+        assert_eq!(client.read_address().unwrap(), proto::Address::default());
+    }
+
+    #[test]
+    fn safe_client_apply_mask_against_simulator() {
+        let fixture = Fixture::new();
+        let client = SafeClient::new(fixture.sync_context());
+        for bits in [0xFF, 0x10, 0xA5, 0x5A, 0x00, 0x81] {
+            let target = proto::PortMask::from_bits(bits);
+            client.apply_mask(target).unwrap();
+            assert_eq!(fixture.open_ports(), target);
+        }
+    }
+
+    #[test]
+    fn safe_client_scans_and_restores_its_slave() {
+        let fixture = Fixture::with_devices([proto::Address::default(), address(5)]);
+        let client = fixture.sync_client();
+        client.set_port_open(port(2)).unwrap();
+        let mut probed = 0;
+        let report = client
+            .scan_with_progress((1..=6).map(address), Duration::from_millis(50), |_, _| {
+                probed += 1
+            })
+            .unwrap();
+        assert_eq!(probed, 6);
+        assert_eq!(
+            report.devices().collect::<Vec<_>>(),
+            [proto::Address::default(), address(5)]
+        );
+
+        // Direct users of the shared context reach the device of the client again.
+        let shared = client.clone_shared();
+        let states = R413D08::read_ports(&mut shared.lock().unwrap()).unwrap();
+        assert_eq!(states[2], proto::PortState::Open);
+    }
+
+    #[test]
+    fn safe_client_reconnects_after_transport_failure() {
+        assert!(
+            !Error::ModbusException(tokio_modbus::ExceptionCode::IllegalFunction).is_transient()
+        );
+
+        let fixture = Fixture::new();
+        let socket_addr = fixture.socket_addr;
+        let policy = RetryPolicy {
+            max_retries: 1,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        };
+        // The initial connection fails on first use, all later ones reach the simulator.
+        let connect = |connects: Arc<AtomicUsize>| {
+            move || {
+                if connects.fetch_add(1, Ordering::Relaxed) == 0 {
+                    let (listener, closing_addr) = closing_listener();
+                    let ctx = tokio_modbus::client::sync::tcp::connect(closing_addr);
+                    drop(listener);
+                    ctx
+                } else {
+                    tokio_modbus::client::sync::tcp::connect(socket_addr)
+                }
+            }
+        };
+
+        // A read is retried on a new connection.
+        let connects = Arc::new(AtomicUsize::new(0));
+        let client = SafeClient::with_reconnect(connect(connects.clone()))
+            .unwrap()
+            .with_retry_policy(policy);
+        client.read_ports().unwrap();
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+
+        // A toggle is not retried, but the next operation reconnects.
+        let connects = Arc::new(AtomicUsize::new(0));
+        let client = SafeClient::with_reconnect(connect(connects.clone()))
+            .unwrap()
+            .with_retry_policy(policy);
+        assert!(client.set_port_toggle(port(0)).is_err());
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+        client.set_port_toggle(port(0)).unwrap();
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+        assert!(fixture.is_open(0));
+    }
 }
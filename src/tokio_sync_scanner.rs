@@ -0,0 +1,206 @@
+//! Provides a synchronous scanner to discover R413D08 devices on a Modbus bus.
+//!
+//! Commissioning a bus with several devices requires knowing which slave
+//! addresses are in use. Reading the address with the broadcast address only
+//! works with a single device on the bus, so this module probes every slave
+//! address individually instead: it reads the port states
+//! ([`proto::PortStates::ADDRESS`]) with a short timeout and records who answers.
+//!
+//! If several devices share one address, they answer at the same time and the
+//! response is garbled (e.g., an invalid CRC). Such responses are reported as
+//! [`ProbeOutcome::Conflict`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use r413d08_lib::{protocol::Address, tokio_common::serial_port_builder, tokio_sync_scanner};
+//! use std::time::Duration;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let builder = serial_port_builder("/dev/ttyUSB0");
+//! let mut ctx = tokio_modbus::client::sync::rtu::connect(&builder)?;
+//!
+//! let addresses = (Address::MIN..=Address::MAX).filter_map(|a| Address::try_from(a).ok());
+//! let report = tokio_sync_scanner::scan(&mut ctx, addresses, Duration::from_millis(50))?;
+//! for address in report.devices() {
+//!     println!("Found device at address {address}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    protocol as proto,
+    tokio_common::{Error, ErrorContext, Result},
+};
+use std::{io, time::Duration};
+use tokio_modbus::{
+    client::sync::Context,
+    prelude::{SlaveContext, SyncReader},
+    Slave,
+};
+
+/// The outcome of probing a single slave address that produced any reaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeOutcome {
+    /// A device answered with a valid response.
+    Device,
+    /// A device answered with a Modbus exception, so it is present but may not be an R413D08.
+    Exception(tokio_modbus::ExceptionCode),
+    /// A garbled or mismatching response was received, most likely because
+    /// several devices share this address. Contains the details of the error.
+    Conflict(String),
+}
+
+/// The result of a bus scan, listing every probed address that produced a reaction.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanReport {
+    /// The responding addresses and their outcome, in the order they were probed.
+    pub responses: Vec<(proto::Address, ProbeOutcome)>,
+}
+
+impl ScanReport {
+    /// Returns the addresses at which a single device answered (including exceptions).
+    pub fn devices(&self) -> impl Iterator<Item = proto::Address> + '_ {
+        self.responses
+            .iter()
+            .filter(|(_, outcome)| !matches!(outcome, ProbeOutcome::Conflict(_)))
+            .map(|(address, _)| *address)
+    }
+
+    /// Returns the addresses with a likely address conflict and the error details.
+    pub fn conflicts(&self) -> impl Iterator<Item = (proto::Address, &str)> + '_ {
+        self.responses
+            .iter()
+            .filter_map(|(address, outcome)| match outcome {
+                ProbeOutcome::Conflict(details) => Some((*address, details.as_str())),
+                _ => None,
+            })
+    }
+
+    /// Returns `true` if a likely address conflict was detected.
+    pub fn has_conflicts(&self) -> bool {
+        self.conflicts().next().is_some()
+    }
+}
+
+/// Probes a single slave address by reading the port states with the given timeout.
+///
+/// The timeout of the context is restored afterwards, the slave is left at the probed address.
+///
+/// # Returns
+///
+/// * `Ok(Some(outcome))`: Something answered at this address.
+/// * `Ok(None)`: Nothing answered within the timeout.
+///
+/// # Errors
+///
+/// Returns an error if the transport itself fails (e.g., the serial port is gone),
+/// since scanning further addresses would be pointless.
+pub fn probe(
+    ctx: &mut Context,
+    address: proto::Address,
+    timeout: Duration,
+) -> Result<Option<ProbeOutcome>> {
+    let previous_timeout = ctx.timeout();
+    ctx.set_slave(Slave(*address));
+    ctx.set_timeout(timeout);
+    let result =
+        ctx.read_holding_registers(proto::PortStates::ADDRESS, proto::PortStates::QUANTITY);
+    ctx.set_timeout(previous_timeout);
+
+    match result {
+        Ok(Ok(_)) => Ok(Some(ProbeOutcome::Device)),
+        Ok(Err(exception)) => Ok(Some(ProbeOutcome::Exception(exception))),
+        Err(tokio_modbus::Error::Transport(err)) => match err.kind() {
+            io::ErrorKind::TimedOut => Ok(None),
+            io::ErrorKind::InvalidData => Ok(Some(ProbeOutcome::Conflict(err.to_string()))),
+            _ => Err(Error::from(tokio_modbus::Error::Transport(err))
+                .in_context(ErrorContext::read("probe", proto::PortStates::ADDRESS))
+                .with_slave(Slave(*address))),
+        },
+        Err(err @ tokio_modbus::Error::Protocol(_)) => {
+            Ok(Some(ProbeOutcome::Conflict(err.to_string())))
+        }
+    }
+}
+
+/// Probes all given slave addresses one after another and collects the responses.
+///
+/// The slave of the context is left at the last probed address, so callers
+/// sharing the context with other operations must set their slave again.
+///
+/// # Arguments
+///
+/// * `addresses`: The slave addresses to probe, e.g. all from [`proto::Address::MIN`] to [`proto::Address::MAX`].
+/// * `timeout`: The time to wait for an answer per address. At 9600 baud a
+///   response takes about 20 ms, so 50-100 ms is usually sufficient.
+///
+/// # Errors
+///
+/// Returns an error if the transport fails, see [`probe`].
+pub fn scan(
+    ctx: &mut Context,
+    addresses: impl IntoIterator<Item = proto::Address>,
+    timeout: Duration,
+) -> Result<ScanReport> {
+    scan_with_progress(ctx, addresses, timeout, |_, _| {})
+}
+
+/// Like [`scan`], but calls `on_probe` after every probed address with its
+/// outcome (`None` if nothing answered), e.g. to report devices as they are found.
+///
+/// # Errors
+///
+/// Returns an error if the transport fails, see [`probe`].
+pub fn scan_with_progress(
+    ctx: &mut Context,
+    addresses: impl IntoIterator<Item = proto::Address>,
+    timeout: Duration,
+    mut on_probe: impl FnMut(proto::Address, Option<&ProbeOutcome>),
+) -> Result<ScanReport> {
+    let mut report = ScanReport::default();
+    for address in addresses {
+        let outcome = probe(ctx, address, timeout)?;
+        on_probe(address, outcome.as_ref());
+        if let Some(outcome) = outcome {
+            report.responses.push((address, outcome));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(all(test, feature = "simulator", feature = "tokio-tcp-sync"))]
+mod tests {
+    use super::*;
+    use crate::simulator::testing::{address, Fixture};
+
+    #[test]
+    fn scanner_against_simulator() {
+        let fixture = Fixture::with_devices([address(2), address(5)]);
+        let mut ctx = fixture.sync_context();
+        let report = scan(&mut ctx, (1..=6).map(address), Duration::from_millis(50)).unwrap();
+        assert_eq!(
+            report.responses,
+            [
+                (address(2), ProbeOutcome::Device),
+                (address(5), ProbeOutcome::Device)
+            ]
+        );
+        assert!(!report.has_conflicts());
+
+        let mut probed = Vec::new();
+        let progress = scan_with_progress(
+            &mut ctx,
+            (4..=5).map(address),
+            Duration::from_millis(50),
+            |address, outcome| probed.push((address, outcome.cloned())),
+        )
+        .unwrap();
+        assert_eq!(progress.responses, [(address(5), ProbeOutcome::Device)]);
+        assert_eq!(
+            probed,
+            [(address(4), None), (address(5), Some(ProbeOutcome::Device))]
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Implements the `watch` command, which live-monitors the relay states.
+//!
+//! On a terminal with text output, a table of all relays is redrawn after every
+//! poll. Otherwise, or with `--changes-only`, one record per transition is
+//! printed, which suits logging.
+
+use crate::{
+    interrupt,
+    output::{Output, Report},
+};
+use anyhow::Result;
+use console::{style, Term};
+use r413d08_lib::{
+    protocol as proto,
+    tokio_common::{self, PortChange},
+    tokio_sync_safe_client::SafeClient,
+};
+use std::{
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How long a relay stays highlighted in the table after it changed.
+const HIGHLIGHT_DURATION: Duration = Duration::from_secs(2);
+
+/// Returns the error message including the cause of a failed operation.
+fn error_message(err: &tokio_common::Error) -> String {
+    match err.context() {
+        Some(_) => format!("{err}: {}", err.root()),
+        None => err.to_string(),
+    }
+}
+
+/// The state of the monitor between two polls.
+struct Monitor {
+    interval: Duration,
+    started: Instant,
+    states: Option<proto::PortStates>,
+    /// The time each relay last changed, `None` if it did not change since watching started.
+    last_change: [Option<Instant>; proto::NUMBER_OF_PORTS],
+    polls: u64,
+    errors: u64,
+    last_error: Option<String>,
+}
+
+impl Monitor {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            started: Instant::now(),
+            states: None,
+            last_change: [None; proto::NUMBER_OF_PORTS],
+            polls: 0,
+            errors: 0,
+            last_error: None,
+        }
+    }
+
+    /// Records the result of a poll and returns the relays that changed.
+    fn update(
+        &mut self,
+        result: &std::result::Result<proto::PortStates, tokio_common::Error>,
+    ) -> Vec<PortChange> {
+        self.polls += 1;
+        match result {
+            Ok(states) => {
+                let changes = match &self.states {
+                    Some(previous) => PortChange::between(previous, states),
+                    None => Vec::new(),
+                };
+                let now = Instant::now();
+                for change in &changes {
+                    self.last_change[*change.port as usize] = Some(now);
+                }
+                self.states = Some(*states);
+                changes
+            }
+            Err(err) => {
+                self.errors += 1;
+                self.last_error = Some(error_message(err));
+                Vec::new()
+            }
+        }
+    }
+
+    /// Renders the table of all relays, one line per entry.
+    fn render(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!(
+                "Watching every {} for {}s - press Ctrl-C to stop",
+                humantime::format_duration(self.interval),
+                self.started.elapsed().as_secs()
+            ),
+            format!("{:>5}  {:<5}  Last change", "Relay", "State"),
+        ];
+        for (idx, last_change) in self.last_change.iter().enumerate() {
+            let state = match &self.states {
+                Some(states) => match states[idx] {
+                    proto::PortState::Open => "ON",
+                    proto::PortState::Close => "OFF",
+                },
+                None => "?",
+            };
+            let since = match last_change {
+                Some(at) => format!(
+                    "{} ago",
+                    humantime::format_duration(Duration::from_secs(at.elapsed().as_secs()))
+                ),
+                None => "-".to_string(),
+            };
+            let line = format!("{idx:>5}  {state:<5}  {since}");
+            if last_change.is_some_and(|at| at.elapsed() < HIGHLIGHT_DURATION) {
+                lines.push(style(line).reverse().to_string());
+            } else {
+                lines.push(line);
+            }
+        }
+        let errors = format!("Polls: {}  Errors: {}", self.polls, self.errors);
+        lines.push(match &self.last_error {
+            Some(last_error) => style(format!("{errors}  Last error: {last_error}"))
+                .red()
+                .to_string(),
+            None => errors,
+        });
+        lines
+    }
+}
+
+/// Polls the ports at the given interval until Ctrl-C is pressed.
+///
+/// The table is only drawn for text output on a terminal, otherwise (and with
+/// `changes_only`) every transition and communication error is reported as a record.
+pub fn watch(
+    client: &SafeClient,
+    output: &Output,
+    interval: Duration,
+    changes_only: bool,
+) -> Result<()> {
+    let term = Term::stdout();
+    let table = !changes_only && output.is_text() && term.is_term();
+    let mut monitor = Monitor::new(interval);
+    let mut drawn_lines = 0;
+    if !table {
+        output.change_header();
+    }
+    let (stop, stopped) = mpsc::channel();
+    interrupt::handle(
+        move || {
+            let _ = stop.send(());
+        },
+        || loop {
+            let poll_started = Instant::now();
+            let result = client.read_ports();
+            let changes = monitor.update(&result);
+            if table {
+                let lines = monitor.render();
+                term.clear_last_lines(drawn_lines)?;
+                term.write_line(&lines.join("\n"))?;
+                drawn_lines = lines.len();
+            } else {
+                let at = SystemTime::now();
+                match &result {
+                    Ok(states) => {
+                        for change in changes {
+                            output.report(&Report::Change {
+                                at,
+                                change,
+                                states: *states,
+                            });
+                        }
+                    }
+                    Err(err) => output.report(&Report::CommunicationError {
+                        at,
+                        message: error_message(err),
+                    }),
+                }
+            }
+            if stopped
+                .recv_timeout(interval.saturating_sub(poll_started.elapsed()))
+                .is_ok()
+            {
+                return Ok(());
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(bits: u8) -> proto::PortStates {
+        proto::PortMask::from_bits(bits).into()
+    }
+
+    fn render(monitor: &Monitor) -> Vec<String> {
+        monitor
+            .render()
+            .iter()
+            .map(|line| console::strip_ansi_codes(line).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn monitor_tracks_changes_and_errors() {
+        let mut monitor = Monitor::new(Duration::from_secs(1));
+        assert!(monitor.update(&Ok(states(0b0000_0001))).is_empty());
+        assert_eq!(monitor.last_change, [None; proto::NUMBER_OF_PORTS]);
+
+        let changes = monitor.update(&Ok(states(0b0000_0110)));
+        let changed: Vec<_> = changes.iter().map(|change| *change.port).collect();
+        assert_eq!(changed, [0, 1, 2]);
+        assert_eq!(changes[0].to, proto::PortState::Close);
+        assert!(monitor.last_change[..3].iter().all(Option::is_some));
+        assert!(monitor.last_change[3..].iter().all(Option::is_none));
+
+        let err = tokio_common::Error::from(tokio_modbus::Error::Transport(
+            std::io::ErrorKind::TimedOut.into(),
+        ));
+        assert!(monitor.update(&Err(err)).is_empty());
+        assert_eq!(monitor.states, Some(states(0b0000_0110)));
+        assert_eq!((monitor.polls, monitor.errors), (3, 1));
+        assert!(monitor.last_error.is_some());
+    }
+
+    #[test]
+    fn monitor_renders_the_table() {
+        let mut monitor = Monitor::new(Duration::from_secs(1));
+        let lines = render(&monitor);
+        assert_eq!(lines.len(), 2 + proto::NUMBER_OF_PORTS + 1);
+        assert_eq!(lines[2], "    0  ?      -");
+        assert_eq!(lines[10], "Polls: 0  Errors: 0");
+
+        monitor.update(&Ok(states(0b0000_0000)));
+        monitor.update(&Ok(states(0b0000_0010)));
+        let lines = render(&monitor);
+        assert_eq!(lines[2], "    0  OFF    -");
+        assert_eq!(lines[3], "    1  ON     0s ago");
+
+        monitor.last_error = Some("Timed out".to_string());
+        monitor.errors = 1;
+        assert_eq!(
+            render(&monitor)[10],
+            "Polls: 2  Errors: 1  Last error: Timed out"
+        );
+    }
+}